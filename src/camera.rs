@@ -1,5 +1,5 @@
-use crate::constants::{SIDE_VIEW, TWO_PI};
-use crate::vector::{Float, Vector3};
+use binary_accretion::constants::{SIDE_VIEW, TWO_PI};
+use binary_accretion::{Float, Vector3};
 
 pub struct Camera {
     pos: Vector3,
@@ -24,7 +24,7 @@ impl Camera {
         }
     }
 
-    pub fn view(&self, buffer: &mut Vec<u32>, width: usize, height: usize, positions: &[Vector3]) {
+    pub fn view(&self, buffer: &mut [u32], width: usize, height: usize, positions: &[Vector3]) {
         assert_eq!(buffer.len(), width * height);
         for pixel in buffer.iter_mut() {
             *pixel = 0x000000;
@@ -34,7 +34,7 @@ impl Camera {
             let pos = position - self.pos;
             let x = 0.5 + pos.dot(self.horizontal) / self.horizontal_length;
             let y = 0.5 + pos.dot(self.vertical) / self.vertical_length;
-            if 0.0 <= x && x < 1.0 && 0.0 <= y && y < 1.0 {
+            if (0.0..1.0).contains(&x) && (0.0..1.0).contains(&y) {
                 let x = (width as Float * x) as usize;
                 let y = (height as Float * y) as usize;
                 buffer[y * width + x] = 0xFFFFFF;
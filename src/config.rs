@@ -0,0 +1,553 @@
+use crate::constants::{AU, SOLAR_MASS, YEAR};
+use crate::eos::IdealGas;
+use crate::snapshot;
+use crate::vector::Float;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum DensityCurve {
+    Uniform,
+    InverseLinear,
+    InverseQuadratic,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum NeighborSearch {
+    // O(n k log n) k-d tree search
+    Tree,
+    // O(n^2) reference implementation, for cross-checking
+    Quadratic,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum GravitySolver {
+    // O(n^2) direct summation
+    Direct,
+    // O(n log n) octree, with accuracy controlled by `opening_angle`
+    BarnesHut,
+    // O(n) fast multipole method, with accuracy controlled by `opening_angle` and
+    // `multipole_order`
+    Fmm,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Softening {
+    None,
+    // Point masses replaced by Plummer spheres
+    Plummer,
+    // Point masses replaced by the M4 cubic spline kernel, consistent with SPH
+    Spline,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum KernelType {
+    // Truncated at `smoothing_dist_factor` smoothing lengths
+    Gaussian,
+    // The M4 spline, with support 2h
+    CubicSpline,
+    // The M6 spline, with support 3h
+    QuinticSpline,
+    // Wendland kernels with support 2h, which do not suffer from the pairing instability at large
+    // neighbor counts
+    WendlandC2,
+    WendlandC4,
+    WendlandC6,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum HeatCapacity {
+    // With the constant `adiabatic_index`
+    Constant,
+    // Of molecular hydrogen, whose adiabatic index falls from 5/3 in cold gas, where its
+    // rotational levels are frozen out, to 7/5 and then 9/7 as they and its vibrational levels are
+    // excited. `molar_mass` should then be that of H2
+    MolecularHydrogen,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum EquationOfStateType {
+    // An ideal gas with the evolved thermal energy and the configured heat capacity
+    IdealGas,
+    // At `initial_temperature`
+    Isothermal,
+    // P = `polytropic_constant` rho^`polytropic_exponent`
+    Polytropic,
+    // Isothermal at `initial_temperature` below `critical_density` and gamma = 7/5 above it
+    Barotropic,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Cooling {
+    None,
+    // Optically thin line cooling of the interstellar medium, Koyama & Inutsuka (2002)
+    Interstellar,
+    // Optically thin thermal emission of dust grains at the gas temperature
+    Dust,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum OpacityType {
+    // `constant_opacity`
+    Constant,
+    // Ice and metal grains, Bell & Lin (1994)
+    Dust,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Integrator {
+    // First order, for comparison
+    Euler,
+    // Second order and symplectic kick-drift-kick, with one force evaluation per step
+    Leapfrog,
+    // Fourth order but not symplectic, with four force evaluations per step
+    RungeKutta4,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ExportFormat {
+    // GADGET-2 binary snapshots, with SnapFormat 1 or 2
+    Gadget1,
+    Gadget2,
+    // The HDF5 layout of GADGET and AREPO
+    Hdf5,
+}
+
+// All quantities are in SI units
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimulationConfig {
+    // Computational
+    // The number of gas particles, which decreases as sinks form and accrete
+    pub count: usize,
+    // The timestep, or the largest timestep with `adaptive_timestep`
+    pub delta_t: Float,
+    // Choose every timestep as the largest that satisfies the criteria below, each a fraction
+    // of a local time scale
+    pub adaptive_timestep: bool,
+    // Of the time for sound and compression to cross the smoothing length
+    pub courant_factor: Float,
+    // Of sqrt(h / |a|)
+    pub acceleration_factor: Float,
+    // Of the time to lose or gain all thermal energy at the current rate
+    pub energy_change_factor: Float,
+    pub integrator: Integrator,
+    // Give every particle its own leapfrog timestep of `delta_t / 2^n`, for n up to
+    // `timestep_bins`, chosen by the adaptive criteria. Only the particles at the end of their
+    // step and their neighbors are evaluated, and cooling and radiation are applied to each
+    // particle over its own step
+    pub block_timesteps: bool,
+    pub timestep_bins: usize,
+    pub neighbor_search: NeighborSearch,
+    // Let every pair of particles within the kernel support of either interact, so that grad-h SPH
+    // conserves momentum and energy. Otherwise particles only see those within their own support
+    pub symmetric_neighbors: bool,
+    // Run without a window until `max_steps` steps or `end_time` of simulated time, whichever
+    // comes first, with 0 for no limit
+    pub headless: bool,
+    pub max_steps: u64,
+    pub end_time: Float,
+    // Write statistics every `statistics_interval` steps to `statistics_file`, or to standard
+    // output if empty
+    pub statistics_interval: usize,
+    pub statistics_file: String,
+    // Also append the sink and binary diagnostics of every statistics report to `sink_file`, as a
+    // time series, or not if empty
+    pub sink_file: String,
+    // Save a snapshot to `checkpoint_file` every `checkpoint_interval` steps, or never if 0
+    pub checkpoint_interval: usize,
+    pub checkpoint_file: String,
+    // Export a snapshot named `<export_prefix>_<number>` for external analysis every
+    // `export_interval` of simulated time, or never if 0
+    pub export_interval: Float,
+    pub export_format: ExportFormat,
+    pub export_prefix: String,
+    // General initial conditions
+    // The seed of the random initial conditions, or 0 to choose one at random
+    pub seed: u64,
+    // Read the initial particles from this CSV or GADGET file instead of generating a sphere,
+    // with `count` and `mass` then taken from the file. One unit of length, velocity and mass in
+    // the file is `import_length_unit`, `import_velocity_unit` and `import_mass_unit` in SI
+    pub initial_conditions: String,
+    pub import_length_unit: Float,
+    pub import_velocity_unit: Float,
+    pub import_mass_unit: Float,
+    pub radius: Float,
+    pub rotational_period: Float,
+    pub density_curve: DensityCurve,
+    // Gravity
+    pub enable_gravity: bool,
+    pub gravity_solver: GravitySolver,
+    // Smaller is more accurate. Since the error of `Fmm` also decreases with `multipole_order`,
+    // it is usually run with a larger opening angle, around 0.7 to 0.9. `Fmm` requires it to be
+    // below 1, or overlapping cells would interact through their expansions, and `BarnesHut` at
+    // most 2 / sqrt(3), or a node could be approximated as seen from inside itself
+    pub opening_angle: Float,
+    // 0 for monopoles, 2 for quadrupoles, 3 for octupoles and so on
+    pub multipole_order: usize,
+    pub softening: Softening,
+    pub softening_length: Float,
+    // Use the smoothing length of each particle as its softening length instead of
+    // `softening_length`
+    pub adaptive_softening: bool,
+    // Print the error of `gravity_solver` relative to direct summation along with the statistics
+    pub report_gravity_error: bool,
+    // The total mass of the generated sphere, shared equally by its particles
+    pub mass: Float,
+    // Sinks
+    // Replace gas denser than `sink_density` that is bound and collapsing within `sink_radius` by
+    // a sink particle, which then accretes the bound gas that falls within `sink_radius`
+    pub enable_sinks: bool,
+    pub sink_density: Float,
+    pub sink_radius: Float,
+    // SPH
+    pub enable_gas_dynamics: bool,
+    pub initial_temperature: Float,
+    // The mean molecular weight, as the mean molar mass of the gas particles. That of pure H2 is
+    // 0.002016 kg/mol, and of molecular gas with solar helium abundance about 0.00237 kg/mol
+    pub molar_mass: Float,
+    pub heat_capacity: HeatCapacity,
+    pub adiabatic_index: Float,
+    // Only the `IdealGas` equation of state is affected by the evolved thermal energy
+    pub equation_of_state: EquationOfStateType,
+    pub polytropic_constant: Float,
+    pub polytropic_exponent: Float,
+    pub critical_density: Float,
+    // Radiative cooling above `minimum_temperature`, and a constant heating rate per unit mass,
+    // such as from cosmic rays. With `exact_cooling` the cooling is integrated exactly over every
+    // step (Townsend 2009) instead of being added to the rate of change of the thermal energy,
+    // so that it does not limit the timestep
+    pub cooling: Cooling,
+    pub heating_rate: Float,
+    pub exact_cooling: bool,
+    pub minimum_temperature: Float,
+    // Flux-limited diffusion radiative transfer (Whitehouse & Bate 2004), where every gas particle
+    // carries radiation energy that diffuses between neighbors and is exchanged with its thermal
+    // energy, solved implicitly every step. Requires the `IdealGas` equation of state. Gas
+    // farther than `radiation_boundary` from the center of mass is held at `initial_temperature`
+    // in equilibrium with the radiation, so that radiation can escape the cloud (Whitehouse & Bate
+    // 2006)
+    pub radiation: bool,
+    pub radiation_boundary: Float,
+    pub opacity: OpacityType,
+    pub constant_opacity: Float,
+    // The number of nearest neighbors whose distance sets the smoothing lengths to start from
+    pub neighbors: usize,
+    pub kernel: KernelType,
+    // The support of the `Gaussian` kernel, in smoothing lengths
+    pub smoothing_dist_factor: Float,
+    // Smoothing lengths h and densities rho are solved together so that h = smoothing_length_factor
+    // (m / rho)^(1/3) (grad-h SPH), with about 4 pi / 3 (support * smoothing_length_factor)^3
+    // particles within the kernel support
+    pub smoothing_length_factor: Float,
+    // XSPH: particles move with their own velocity plus this fraction of the kernel weighted
+    // relative velocity of their neighbors. Not energy conserving
+    pub velocity_averaging: Float,
+    // Artificial viscosity
+    pub enable_viscosity: bool,
+    pub viscosity_alpha: Float,
+    pub viscosity_beta: Float,
+    pub balsara_switch: bool,
+    // Morris-Monaghan viscosity, where each particle evolves its own alpha between
+    // `viscosity_alpha_min` and `viscosity_alpha`, decaying over `1 / viscosity_decay` sound
+    // crossing times of its smoothing length
+    pub time_dependent_viscosity: bool,
+    pub viscosity_alpha_min: Float,
+    pub viscosity_decay: Float,
+    // Magnetic fields
+    // Ideal smoothed particle magnetohydrodynamics (Price 2012), where every gas particle carries
+    // a magnetic field, starting as a uniform `initial_magnetic_field` along the rotation axis
+    pub magnetic_fields: bool,
+    pub initial_magnetic_field: Float,
+    // Hyperbolic and parabolic divergence cleaning (Tricco & Price 2012), which carries errors in
+    // the divergence of the field away at the fast magnetosonic speed and damps them over
+    // `1 / cleaning_damping` crossings of the smoothing length
+    pub divergence_cleaning: bool,
+    pub cleaning_damping: Float,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        SimulationConfig {
+            count: 2000,
+            delta_t: 500.0 * YEAR,
+            adaptive_timestep: false,
+            courant_factor: 0.3,
+            acceleration_factor: 0.3,
+            energy_change_factor: 0.1,
+            integrator: Integrator::Leapfrog,
+            block_timesteps: false,
+            timestep_bins: 10,
+            neighbor_search: NeighborSearch::Tree,
+            symmetric_neighbors: false,
+            headless: false,
+            max_steps: 0,
+            end_time: 0.0,
+            statistics_interval: 100,
+            statistics_file: String::new(),
+            sink_file: String::new(),
+            checkpoint_interval: 0,
+            checkpoint_file: "checkpoint.snap".to_owned(),
+            export_interval: 0.0,
+            export_format: ExportFormat::Hdf5,
+            export_prefix: "snapshot".to_owned(),
+            seed: 0,
+            initial_conditions: String::new(),
+            import_length_unit: 1.0,
+            import_velocity_unit: 1.0,
+            import_mass_unit: 1.0,
+            radius: 10_000.0 * AU,
+            rotational_period: 1e6 * YEAR,
+            density_curve: DensityCurve::InverseQuadratic,
+            enable_gravity: true,
+            gravity_solver: GravitySolver::Direct,
+            opening_angle: 0.5,
+            multipole_order: 3,
+            softening: Softening::None,
+            softening_length: 100.0 * AU,
+            adaptive_softening: false,
+            report_gravity_error: false,
+            mass: 1.0 * SOLAR_MASS,
+            enable_sinks: false,
+            sink_density: 1e-10,
+            sink_radius: 50.0 * AU,
+            enable_gas_dynamics: true,
+            initial_temperature: 5.0,
+            molar_mass: 0.002016,
+            heat_capacity: HeatCapacity::Constant,
+            adiabatic_index: 5.0 / 3.0,
+            equation_of_state: EquationOfStateType::IdealGas,
+            polytropic_constant: 1e14,
+            polytropic_exponent: 5.0 / 3.0,
+            critical_density: 1e-11,
+            cooling: Cooling::None,
+            heating_rate: 0.0,
+            exact_cooling: false,
+            minimum_temperature: 2.7,
+            radiation: false,
+            radiation_boundary: 8_000.0 * AU,
+            opacity: OpacityType::Dust,
+            constant_opacity: 0.1,
+            neighbors: 30,
+            kernel: KernelType::Gaussian,
+            smoothing_dist_factor: 2.0,
+            smoothing_length_factor: 1.0,
+            velocity_averaging: 1.0,
+            enable_viscosity: false,
+            viscosity_alpha: 1.0,
+            viscosity_beta: 2.0,
+            balsara_switch: true,
+            time_dependent_viscosity: false,
+            viscosity_alpha_min: 0.1,
+            viscosity_decay: 0.2,
+            magnetic_fields: false,
+            initial_magnetic_field: 1e-9,
+            divergence_cleaning: true,
+            cleaning_damping: 1.0,
+        }
+    }
+}
+
+impl SimulationConfig {
+    // Reads a configuration from a `.toml` or `.ron` file. Missing fields take their default values
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+        let config: SimulationConfig = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(&text).map_err(|e| e.to_string()),
+            Some("ron") => ron::de::from_str(&text).map_err(|e| e.to_string()),
+            _ => Err("expected a .toml or .ron file".to_owned()),
+        }
+        .map_err(|e| format!("Invalid configuration {}: {}", path.display(), e))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Builds a configuration from command line arguments. `--config <file>` loads a file, and
+    // any field can then be overridden by `--<field> <value>`, with dashes in place of
+    // underscores, e.g. `--delta-t 1e10 --density-curve Uniform`, and a flag without a value is
+    // true, e.g. `--headless`. `--restart <snapshot>` instead
+    // starts from the configuration of a snapshot, and returns its path for continuing the run
+    pub fn from_args<I: IntoIterator<Item = String>>(
+        args: I,
+    ) -> Result<(Self, Option<PathBuf>), String> {
+        let mut config_file = None;
+        let mut restart = None;
+        let mut overrides = Vec::new();
+        let mut args = args.into_iter().peekable();
+        while let Some(flag) = args.next() {
+            let key = match flag.strip_prefix("--") {
+                Some(key) => key.replace('-', "_"),
+                None => return Err(format!("Unexpected argument {}", flag)),
+            };
+            let value = match args.peek() {
+                Some(value) if !value.starts_with("--") => args.next().unwrap(),
+                _ => "true".to_owned(),
+            };
+            if key == "config" {
+                config_file = Some(value);
+            } else if key == "restart" {
+                restart = Some(PathBuf::from(value));
+            } else {
+                overrides.push((key, value));
+            }
+        }
+
+        let config = match (config_file, &restart) {
+            (Some(_), Some(_)) => return Err("Cannot use both --config and --restart".to_owned()),
+            (Some(path), None) => SimulationConfig::load(Path::new(&path))?,
+            (None, Some(path)) => snapshot::read_config(path)?,
+            (None, None) => SimulationConfig::default(),
+        };
+        if overrides.is_empty() {
+            return Ok((config, restart));
+        }
+        let mut table = toml::Value::try_from(&config).map_err(|e| e.to_string())?;
+        for (key, value) in overrides {
+            table
+                .as_table_mut()
+                .unwrap()
+                .insert(key, parse_value(&value));
+        }
+        let config: SimulationConfig = table
+            .try_into()
+            .map_err(|e| format!("Invalid command line configuration: {}", e))?;
+        if restart.is_some() {
+            config.validate_restart()?;
+        } else {
+            config.validate()?;
+        }
+        Ok((config, restart))
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.neighbors >= self.count {
+            return Err(format!(
+                "neighbors ({}) must be less than count ({})",
+                self.neighbors, self.count
+            ));
+        }
+        self.validate_restart()
+    }
+
+    // The checks of `validate` that also hold for the configuration of a snapshot, where `count`
+    // is the gas left after accretion. Only the initial smoothing lengths need more than
+    // `neighbors` particles
+    pub fn validate_restart(&self) -> Result<(), String> {
+        if self.opening_angle <= 0.0 {
+            return Err("opening_angle must be positive".to_owned());
+        }
+        if self.gravity_solver == GravitySolver::BarnesHut
+            && self.opening_angle > 2.0 / (3.0 as Float).sqrt()
+        {
+            return Err(
+                "opening_angle must be at most 2 / sqrt(3) with the BarnesHut gravity solver"
+                    .to_owned(),
+            );
+        }
+        if self.gravity_solver == GravitySolver::Fmm && self.opening_angle >= 1.0 {
+            return Err("opening_angle must be less than 1 with the Fmm gravity solver".to_owned());
+        }
+        if self.viscosity_alpha <= 0.0 || self.viscosity_alpha_min > self.viscosity_alpha {
+            return Err(
+                "viscosity_alpha must be positive and at least viscosity_alpha_min".to_owned(),
+            );
+        }
+        if self.multipole_order > 8 {
+            return Err("multipole_order must be at most 8".to_owned());
+        }
+        if !(self.delta_t > 0.0 && self.radius > 0.0 && self.mass > 0.0) {
+            return Err("delta_t, radius and mass must be positive".to_owned());
+        }
+        if !(self.courant_factor > 0.0
+            && self.acceleration_factor > 0.0
+            && self.energy_change_factor > 0.0)
+        {
+            return Err(
+                "courant_factor, acceleration_factor and energy_change_factor must be positive"
+                    .to_owned(),
+            );
+        }
+        if self.block_timesteps && self.integrator != Integrator::Leapfrog {
+            return Err("block_timesteps requires the Leapfrog integrator".to_owned());
+        }
+        if self.headless && self.max_steps == 0 && self.end_time <= 0.0 {
+            return Err("headless requires max_steps or end_time".to_owned());
+        }
+        if self.timestep_bins > 40 {
+            return Err("timestep_bins must be at most 40".to_owned());
+        }
+        if !(self.import_length_unit > 0.0
+            && self.import_velocity_unit > 0.0
+            && self.import_mass_unit > 0.0)
+        {
+            return Err(
+                "import_length_unit, import_velocity_unit and import_mass_unit must be positive"
+                    .to_owned(),
+            );
+        }
+        if self.enable_sinks && !(self.sink_density > 0.0 && self.sink_radius > 0.0) {
+            return Err("sink_density and sink_radius must be positive".to_owned());
+        }
+        if !(self.molar_mass > 0.0 && self.adiabatic_index > 1.0) {
+            return Err(
+                "molar_mass must be positive and adiabatic_index greater than 1".to_owned(),
+            );
+        }
+        if !(self.polytropic_constant > 0.0
+            && self.polytropic_exponent > 0.0
+            && self.critical_density > 0.0)
+        {
+            return Err(
+                "polytropic_constant, polytropic_exponent and critical_density must be positive"
+                    .to_owned(),
+            );
+        }
+        if !(self.heating_rate >= 0.0 && self.minimum_temperature >= 0.0) {
+            return Err("heating_rate and minimum_temperature must not be negative".to_owned());
+        }
+        if self.radiation && self.equation_of_state != EquationOfStateType::IdealGas {
+            return Err("radiation requires the IdealGas equation of state".to_owned());
+        }
+        if !(self.constant_opacity > 0.0 && self.radiation_boundary > 0.0) {
+            return Err("constant_opacity and radiation_boundary must be positive".to_owned());
+        }
+        if !(self.initial_magnetic_field.is_finite() && self.cleaning_damping >= 0.0) {
+            return Err(
+                "initial_magnetic_field must be finite and cleaning_damping not negative"
+                    .to_owned(),
+            );
+        }
+        if self.smoothing_dist_factor <= 0.0 {
+            return Err("smoothing_dist_factor must be positive".to_owned());
+        }
+        if self.smoothing_length_factor <= 0.0 {
+            return Err("smoothing_length_factor must be positive".to_owned());
+        }
+        Ok(())
+    }
+
+    // Derived quantities, for the particles of the generated sphere
+    pub fn particle_mass(&self) -> Float {
+        self.mass / self.count as Float
+    }
+    pub fn initial_thermal_energy(&self) -> Float {
+        self.particle_mass() * IdealGas::new(self).energy(self.initial_temperature)
+    }
+    // Whether the pressure depends on the evolved thermal energy. The other equations of state
+    // depend on density alone, so the thermal energy is held fixed and does not limit the timestep
+    pub fn evolves_thermal_energy(&self) -> bool {
+        self.equation_of_state == EquationOfStateType::IdealGas
+    }
+}
+
+// Command line values are parsed as TOML values, falling back to plain strings so that enum
+// variants do not need quoting
+fn parse_value(value: &str) -> toml::Value {
+    format!("value = {}", value)
+        .parse::<toml::Value>()
+        .ok()
+        .and_then(|parsed| parsed.get("value").cloned())
+        .unwrap_or_else(|| toml::Value::String(value.to_owned()))
+}
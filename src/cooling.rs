@@ -0,0 +1,363 @@
+use crate::config::{Cooling, SimulationConfig};
+use crate::constants::{HYDROGEN_MASS, STEFAN_BOLTZMANN};
+use crate::opacity;
+use crate::particle;
+use crate::vector::Float;
+use std::sync::OnceLock;
+
+// The optically thin radiative cooling rate per unit mass, as a piecewise power law in temperature
+// tabulated on a logarithmic grid, times the number density of hydrogen nuclei over their mass
+// density for collisionally excited line cooling, which scales with density
+pub struct CoolingTable {
+    temperatures: Vec<Float>,
+    rates: Vec<Float>,
+    collisional: bool,
+    // The integral of 1 / rate from every temperature to the last, for the exact integration
+    integrals: Vec<Float>,
+}
+
+// The mass per hydrogen nucleus of gas with solar helium abundance
+const MASS_PER_HYDROGEN: Float = 1.4 * HYDROGEN_MASS;
+const TABLE_STEPS_PER_DECADE: usize = 100;
+
+static INTERSTELLAR: OnceLock<CoolingTable> = OnceLock::new();
+static DUST: OnceLock<CoolingTable> = OnceLock::new();
+
+// The configured cooling table, if any
+pub fn table(config: &SimulationConfig) -> Option<&'static CoolingTable> {
+    match config.cooling {
+        Cooling::None => None,
+        // The fit of Koyama & Inutsuka (2002) to the line cooling of the interstellar medium, in
+        // erg cm^3 s^-1 converted to W m^3
+        Cooling::Interstellar => Some(INTERSTELLAR.get_or_init(|| {
+            CoolingTable::new(true, |t| {
+                2e-26
+                    * (1e7 * (-1.184e5 / (t + 1000.0)).exp()
+                        + 1.4e-2 * t.sqrt() * (-92.0 / t).exp())
+                    * 1e-13
+            })
+        })),
+        // The thermal emission of dust in equilibrium with the gas, 4 sigma kappa T^4
+        Cooling::Dust => Some(DUST.get_or_init(|| {
+            CoolingTable::new(false, |t| {
+                4.0 * STEFAN_BOLTZMANN * opacity::dust(t) * t.powi(4)
+            })
+        })),
+    }
+}
+
+impl CoolingTable {
+    // Tabulates `rate` from 1 K to 1e9 K
+    fn new(collisional: bool, rate: impl Fn(Float) -> Float) -> Self {
+        let temperatures: Vec<Float> = (0..=9 * TABLE_STEPS_PER_DECADE)
+            .map(|k| (10.0 as Float).powf(k as Float / TABLE_STEPS_PER_DECADE as Float))
+            .collect();
+        let rates: Vec<Float> = temperatures.iter().map(|&t| rate(t)).collect();
+        let mut table = CoolingTable {
+            integrals: vec![0.0; temperatures.len()],
+            temperatures,
+            rates,
+            collisional,
+        };
+        for k in (0..table.temperatures.len() - 1).rev() {
+            table.integrals[k] =
+                table.integrals[k + 1] + table.segment_integral(k, table.temperatures[k]);
+        }
+        table
+    }
+
+    // The cooling rate per unit mass at a density and temperature, which vanishes below the table
+    pub fn rate(&self, density: Float, temperature: Float) -> Float {
+        if temperature < self.temperatures[0] {
+            return 0.0;
+        }
+        let k = self.segment(temperature);
+        self.coefficient(density)
+            * self.rates[k]
+            * (temperature / self.temperatures[k]).powf(self.slope(k))
+    }
+
+    // The temperature after cooling for `dt` at a constant density, with the thermal energy per
+    // unit mass `heat_capacity` times the temperature (Townsend 2009). This is exact for the
+    // tabulated power laws, and cannot overshoot below the table
+    pub fn integrate(
+        &self,
+        density: Float,
+        temperature: Float,
+        heat_capacity: Float,
+        dt: Float,
+    ) -> Float {
+        if temperature <= self.temperatures[0] {
+            return temperature;
+        }
+        let k = self.segment(temperature);
+        let integral = self.integrals[k + 1]
+            + self.segment_integral(k, temperature)
+            + self.coefficient(density) * dt / heat_capacity;
+        if integral >= self.integrals[0] {
+            return self.temperatures[0];
+        }
+        // The segment the new temperature falls in, at or below the current one
+        let mut k = k;
+        while self.integrals[k] < integral {
+            k -= 1;
+        }
+        // Invert the integral over the segment from the new temperature to its end
+        let (t, slope) = (self.temperatures[k], self.slope(k));
+        let remaining = (integral - self.integrals[k + 1]) * self.rates[k] / t;
+        let end = self.temperatures[k + 1] / t;
+        if (slope - 1.0).abs() < 1e-9 {
+            t * end * (-remaining).exp()
+        } else {
+            t * (end.powf(1.0 - slope) - (1.0 - slope) * remaining).powf((1.0 - slope).recip())
+        }
+    }
+
+    // The temperature nearest to `temperature`, in the direction of the net rate of change, at
+    // which the cooling rate per unit mass balances `heating`, or None if there is none within
+    // the table. Cooling stronger than the heating all the way down stops at the start of the
+    // table, below which it vanishes
+    pub fn equilibrium(&self, density: Float, temperature: Float, heating: Float) -> Option<Float> {
+        let net = |t: Float| heating - self.rate(density, t);
+        let k = self.segment(temperature);
+        if net(temperature) < 0.0 {
+            (0..=k)
+                .rev()
+                .find(|&k| net(self.temperatures[k]) >= 0.0)
+                .map(|k| self.balance(density, heating, k))
+                .or(Some(self.temperatures[0]))
+        } else {
+            (k..self.temperatures.len() - 1)
+                .find(|&k| net(self.temperatures[k + 1]) <= 0.0)
+                .map(|k| self.balance(density, heating, k))
+        }
+    }
+
+    // The temperature within segment `k` at which the cooling rate per unit mass is `heating`
+    fn balance(&self, density: Float, heating: Float, k: usize) -> Float {
+        let (t, slope) = (self.temperatures[k], self.slope(k));
+        if slope.abs() < 1e-12 {
+            return t;
+        }
+        (t * (heating / (self.coefficient(density) * self.rates[k])).powf(slope.recip()))
+            .max(t)
+            .min(self.temperatures[k + 1])
+    }
+
+    // The number of hydrogen nuclei per unit volume over the density, for line cooling
+    fn coefficient(&self, density: Float) -> Float {
+        if self.collisional {
+            density / (MASS_PER_HYDROGEN * MASS_PER_HYDROGEN)
+        } else {
+            1.0
+        }
+    }
+
+    // The segment containing `temperature`, extending the last one
+    fn segment(&self, temperature: Float) -> usize {
+        let position = temperature.log10() * TABLE_STEPS_PER_DECADE as Float;
+        (position.max(0.0) as usize).min(self.temperatures.len() - 2)
+    }
+
+    fn slope(&self, k: usize) -> Float {
+        (self.rates[k + 1] / self.rates[k]).ln()
+            / (self.temperatures[k + 1] / self.temperatures[k]).ln()
+    }
+
+    // The integral of 1 / rate from `temperature` to the end of segment `k`
+    fn segment_integral(&self, k: usize, temperature: Float) -> Float {
+        let (t, slope) = (self.temperatures[k], self.slope(k));
+        let (start, end) = (temperature / t, self.temperatures[k + 1] / t);
+        if (slope - 1.0).abs() < 1e-9 {
+            t / self.rates[k] * (end / start).ln()
+        } else {
+            t / self.rates[k] * (end.powf(1.0 - slope) - start.powf(1.0 - slope)) / (1.0 - slope)
+        }
+    }
+}
+
+// The net rate of change of the thermal energy of a gas particle due to radiative cooling and
+// `heating_rate`, with no cooling below `minimum_temperature`
+pub fn rate(config: &SimulationConfig, mass: Float, energy: Float, density: Float) -> Float {
+    let temperature = particle::temperature(config, mass, energy, density);
+    let cooling = match table(config) {
+        Some(table) if temperature > config.minimum_temperature => table.rate(density, temperature),
+        _ => 0.0,
+    };
+    mass * (config.heating_rate - cooling)
+}
+
+// The thermal energy of a gas particle after `dt` of exact cooling, down to at most
+// `minimum_temperature`, followed by heating. The result does not go past the temperature at which
+// the heating and cooling balance
+pub fn integrate(
+    config: &SimulationConfig,
+    mass: Float,
+    energy: Float,
+    density: Float,
+    dt: Float,
+) -> Float {
+    let temperature = particle::temperature(config, mass, energy, density);
+    let table = match table(config) {
+        Some(table) if energy > 0.0 => table,
+        _ => return energy + mass * config.heating_rate * dt,
+    };
+    // Assuming a constant heat capacity over the step
+    let heat_capacity = energy / mass / temperature;
+    let cooled = if temperature > config.minimum_temperature {
+        let cooled = table
+            .integrate(density, temperature, heat_capacity, dt)
+            .max(config.minimum_temperature);
+        mass * heat_capacity * cooled
+    } else {
+        energy
+    };
+    let heated = cooled + mass * config.heating_rate * dt;
+    if config.heating_rate == 0.0 {
+        return heated;
+    }
+    // The net rate does not change sign before the equilibrium, so a split result that moves
+    // against it has reached the equilibrium within the step
+    let equilibrium = table
+        .equilibrium(density, temperature, config.heating_rate)
+        .map(|t| mass * heat_capacity * t.max(config.minimum_temperature));
+    match equilibrium {
+        Some(equilibrium) if rate(config, mass, energy, density) < 0.0 => {
+            if heated > energy {
+                equilibrium
+            } else {
+                heated.max(equilibrium)
+            }
+        }
+        Some(equilibrium) => {
+            if heated < energy {
+                equilibrium
+            } else {
+                heated.min(equilibrium)
+            }
+        }
+        None => heated.max(energy),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eos::IdealGas;
+
+    const DENSITY: Float = 1e-18;
+
+    fn config(heating_rate: Float) -> SimulationConfig {
+        SimulationConfig {
+            cooling: Cooling::Interstellar,
+            heating_rate,
+            minimum_temperature: 10.0,
+            ..SimulationConfig::default()
+        }
+    }
+
+    // The temperature after cooling for `dt` at the rate of the tabulated power laws, by fine
+    // fourth order Runge-Kutta steps
+    fn explicit(
+        table: &CoolingTable,
+        temperature: Float,
+        heat_capacity: Float,
+        dt: Float,
+    ) -> Float {
+        let steps = 100_000;
+        let h = dt / steps as Float;
+        let slope = |t: Float| -table.rate(DENSITY, t) / heat_capacity;
+        let mut t = temperature;
+        for _ in 0..steps {
+            let k1 = slope(t);
+            let k2 = slope(t + h / 2.0 * k1);
+            let k3 = slope(t + h / 2.0 * k2);
+            let k4 = slope(t + h * k3);
+            t += h / 6.0 * (k1 + 2.0 * k2 + 2.0 * k3 + k4);
+        }
+        t
+    }
+
+    #[test]
+    fn exact_cooling_matches_explicit_integration() {
+        let config = config(0.0);
+        let table = table(&config).unwrap();
+        let heat_capacity = IdealGas::new(&config).energy(1.0);
+        let start = 5000.0;
+        // Within the segment from 4898 K to 5012 K, and then across ten
+        for &(dt, segments) in &[(1e9, 0), (2e10, 10)] {
+            let exact = table.integrate(DENSITY, start, heat_capacity, dt);
+            let reference = explicit(table, start, heat_capacity, dt);
+            assert_eq!(table.segment(start) - table.segment(exact), segments);
+            assert!(
+                ((exact - reference) / reference).abs() < 1e-8,
+                "{} exact, {} explicit",
+                exact,
+                reference
+            );
+        }
+    }
+
+    #[test]
+    fn long_steps_stop_at_the_floor_or_equilibrium() {
+        let mass = 1e25;
+        let energy = |config: &SimulationConfig, t: Float| mass * IdealGas::new(config).energy(t);
+        let temperature = |config: &SimulationConfig, energy: Float| {
+            particle::temperature(config, mass, energy, DENSITY)
+        };
+        let steps = [1e8, 1e10, 1e12, 1e14, 1e20];
+
+        // Without heating, cooling ends at the minimum temperature
+        let config = config(0.0);
+        let cooled: Vec<Float> = steps
+            .iter()
+            .map(|&dt| {
+                temperature(
+                    &config,
+                    integrate(&config, mass, energy(&config, 5000.0), DENSITY, dt),
+                )
+            })
+            .collect();
+        assert!(cooled.windows(2).all(|pair| pair[1] <= pair[0]));
+        assert!(cooled.iter().all(|&t| t >= config.minimum_temperature));
+        assert!((cooled[steps.len() - 1] - config.minimum_temperature).abs() < 1e-9);
+
+        // With heating, the temperature approaches the equilibrium from either side
+        let config = super::tests::config(1e-4);
+        let table = table(&config).unwrap();
+        let equilibrium = table
+            .equilibrium(DENSITY, 5000.0, config.heating_rate)
+            .unwrap();
+        assert_eq!(
+            table.equilibrium(DENSITY, 20.0, config.heating_rate),
+            Some(equilibrium)
+        );
+        let balance = table.rate(DENSITY, equilibrium) / config.heating_rate;
+        assert!((balance - 1.0).abs() < 1e-9, "{}", balance);
+        for &start in &[5000.0, 20.0] {
+            let evolved: Vec<Float> = steps
+                .iter()
+                .map(|&dt| {
+                    temperature(
+                        &config,
+                        integrate(&config, mass, energy(&config, start), DENSITY, dt),
+                    )
+                })
+                .collect();
+            for &t in &evolved {
+                assert!(
+                    (t - equilibrium) * (start - equilibrium) >= 0.0,
+                    "{} from {}",
+                    t,
+                    start
+                );
+            }
+            let last = evolved[steps.len() - 1];
+            assert!(
+                ((last - equilibrium) / equilibrium).abs() < 1e-9,
+                "{}",
+                last
+            );
+        }
+    }
+}
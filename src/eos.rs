@@ -0,0 +1,303 @@
+use crate::config::{EquationOfStateType, HeatCapacity, SimulationConfig};
+use crate::constants::GAS_CONSTANT;
+use crate::vector::Float;
+use std::sync::OnceLock;
+
+// The pressure of the gas as a function of its density and thermal energy per unit mass. Only
+// `IdealGas` depends on the evolved thermal energy, the others depend on density alone
+pub trait EquationOfState {
+    fn pressure(&self, density: Float, energy: Float) -> Float;
+    // The adiabatic sound speed, sqrt(dP/drho) at constant entropy
+    fn sound_speed(&self, density: Float, energy: Float) -> Float;
+    fn temperature(&self, density: Float, energy: Float) -> Float;
+}
+
+// Calls `f` with the configured equation of state
+pub fn with_equation_of_state<R>(
+    config: &SimulationConfig,
+    f: impl FnOnce(&dyn EquationOfState) -> R,
+) -> R {
+    // The isothermal sound speed squared at `initial_temperature`
+    let isothermal = GAS_CONSTANT * config.initial_temperature / config.molar_mass;
+    match config.equation_of_state {
+        EquationOfStateType::IdealGas => f(&IdealGas::new(config)),
+        EquationOfStateType::Isothermal => f(&Isothermal {
+            sound_speed_squared: isothermal,
+            temperature: config.initial_temperature,
+        }),
+        EquationOfStateType::Polytropic => f(&Polytropic {
+            constant: config.polytropic_constant,
+            exponent: config.polytropic_exponent,
+            molar_mass: config.molar_mass,
+        }),
+        EquationOfStateType::Barotropic => f(&Barotropic {
+            sound_speed_squared: isothermal,
+            temperature: config.initial_temperature,
+            critical_density: config.critical_density,
+        }),
+    }
+}
+
+// An ideal gas, P = rho R T / mu, which is adiabatic apart from shocks. The thermal energy is
+// related to the temperature by the configured heat capacity
+pub struct IdealGas {
+    molar_mass: Float,
+    heat_capacity: HeatCapacity,
+    adiabatic_index: Float,
+}
+
+impl IdealGas {
+    pub fn new(config: &SimulationConfig) -> Self {
+        IdealGas {
+            molar_mass: config.molar_mass,
+            heat_capacity: config.heat_capacity,
+            adiabatic_index: config.adiabatic_index,
+        }
+    }
+
+    // The thermal energy per unit mass at a temperature
+    pub fn energy(&self, temperature: Float) -> Float {
+        let specific_gas_constant = GAS_CONSTANT / self.molar_mass;
+        match self.heat_capacity {
+            HeatCapacity::Constant => {
+                specific_gas_constant * temperature / (self.adiabatic_index - 1.0)
+            }
+            HeatCapacity::MolecularHydrogen => {
+                specific_gas_constant * hydrogen_energy(temperature).0
+            }
+        }
+    }
+
+    // The (temperature, adiabatic index) at a thermal energy per unit mass
+    fn state(&self, energy: Float) -> (Float, Float) {
+        let specific_gas_constant = GAS_CONSTANT / self.molar_mass;
+        match self.heat_capacity {
+            HeatCapacity::Constant => (
+                energy * (self.adiabatic_index - 1.0) / specific_gas_constant,
+                self.adiabatic_index,
+            ),
+            HeatCapacity::MolecularHydrogen => {
+                let (temperature, heat_capacity) =
+                    hydrogen_temperature(energy / specific_gas_constant);
+                (temperature, 1.0 + 1.0 / heat_capacity)
+            }
+        }
+    }
+}
+
+impl EquationOfState for IdealGas {
+    fn pressure(&self, density: Float, energy: Float) -> Float {
+        let (temperature, _) = self.state(energy);
+        density * GAS_CONSTANT / self.molar_mass * temperature
+    }
+    fn sound_speed(&self, density: Float, energy: Float) -> Float {
+        let (_, adiabatic_index) = self.state(energy);
+        (adiabatic_index * self.pressure(density, energy) / density).sqrt()
+    }
+    fn temperature(&self, _density: Float, energy: Float) -> Float {
+        self.state(energy).0
+    }
+}
+
+// P = c_s^2 rho at a fixed temperature, for gas that radiates away any compressional heating
+pub struct Isothermal {
+    sound_speed_squared: Float,
+    temperature: Float,
+}
+
+impl EquationOfState for Isothermal {
+    fn pressure(&self, density: Float, _energy: Float) -> Float {
+        self.sound_speed_squared * density
+    }
+    fn sound_speed(&self, _density: Float, _energy: Float) -> Float {
+        self.sound_speed_squared.sqrt()
+    }
+    fn temperature(&self, _density: Float, _energy: Float) -> Float {
+        self.temperature
+    }
+}
+
+// P = K rho^gamma
+pub struct Polytropic {
+    constant: Float,
+    exponent: Float,
+    molar_mass: Float,
+}
+
+impl EquationOfState for Polytropic {
+    fn pressure(&self, density: Float, _energy: Float) -> Float {
+        self.constant * density.powf(self.exponent)
+    }
+    fn sound_speed(&self, density: Float, energy: Float) -> Float {
+        (self.exponent * self.pressure(density, energy) / density).sqrt()
+    }
+    fn temperature(&self, density: Float, energy: Float) -> Float {
+        self.pressure(density, energy) * self.molar_mass / density / GAS_CONSTANT
+    }
+}
+
+// Isothermal below `critical_density`, where the collapsing gas becomes optically thick and
+// heats up, stiffening to gamma = 7/5 above it: P = c_s^2 rho (1 + (rho / rho_crit)^(2/5))
+pub struct Barotropic {
+    sound_speed_squared: Float,
+    temperature: Float,
+    critical_density: Float,
+}
+
+impl Barotropic {
+    fn stiffening(&self, density: Float) -> Float {
+        (density / self.critical_density).powf(0.4)
+    }
+}
+
+impl EquationOfState for Barotropic {
+    fn pressure(&self, density: Float, _energy: Float) -> Float {
+        self.sound_speed_squared * density * (1.0 + self.stiffening(density))
+    }
+    fn sound_speed(&self, density: Float, _energy: Float) -> Float {
+        (self.sound_speed_squared * (1.0 + 1.4 * self.stiffening(density))).sqrt()
+    }
+    fn temperature(&self, density: Float, _energy: Float) -> Float {
+        self.temperature * (1.0 + self.stiffening(density))
+    }
+}
+
+// Molecular hydrogen with translational, rotational and vibrational degrees of freedom, with its
+// ortho and para states in equilibrium and without dissociation. Tabulated on a logarithmic grid
+// of temperatures from 1 K to 1e5 K as (T, u / (R / mu)), in which u is monotonic
+const HYDROGEN_MIN_TEMPERATURE: Float = 1.0;
+const HYDROGEN_STEPS_PER_DECADE: usize = 200;
+static HYDROGEN_TABLE: OnceLock<Vec<(Float, Float)>> = OnceLock::new();
+
+fn hydrogen_table() -> &'static [(Float, Float)] {
+    HYDROGEN_TABLE.get_or_init(|| {
+        // Of the rotational and vibrational levels
+        let (theta_rotation, theta_vibration) = (85.4, 6100.0);
+        (0..=5 * HYDROGEN_STEPS_PER_DECADE)
+            .map(|k| {
+                let temperature = HYDROGEN_MIN_TEMPERATURE
+                    * (10.0 as Float).powf(k as Float / HYDROGEN_STEPS_PER_DECADE as Float);
+                // The mean rotational energy over kT from the partition function, with odd
+                // (ortho) levels three times as degenerate as even (para) levels
+                let (mut partition, mut energy) = (0.0, 0.0);
+                for j in 0..300 {
+                    let level = (j * (j + 1)) as Float * theta_rotation / temperature;
+                    let weight = if j % 2 == 0 { 1.0 } else { 3.0 } * (2 * j + 1) as Float;
+                    partition += weight * (-level).exp();
+                    energy += weight * level * (-level).exp();
+                }
+                let x = theta_vibration / temperature;
+                let vibration = x / x.exp_m1();
+                (
+                    temperature,
+                    temperature * (1.5 + energy / partition + vibration),
+                )
+            })
+            .collect()
+    })
+}
+
+// The (u / (R / mu), c_v / (R / mu)) of molecular hydrogen at a temperature
+fn hydrogen_energy(temperature: Float) -> (Float, Float) {
+    let table = hydrogen_table();
+    let position =
+        (temperature / HYDROGEN_MIN_TEMPERATURE).log10() * HYDROGEN_STEPS_PER_DECADE as Float;
+    let k = (position.max(0.0) as usize).min(table.len() - 2);
+    interpolate(table[k], table[k + 1], temperature)
+}
+
+// The (T, c_v / (R / mu)) of molecular hydrogen at a thermal energy per unit mass in units of
+// R / mu
+fn hydrogen_temperature(energy: Float) -> (Float, Float) {
+    let table = hydrogen_table();
+    let k = table
+        .partition_point(|&(_, u)| u < energy)
+        .clamp(1, table.len() - 1);
+    let swap = |(t, u): (Float, Float)| (u, t);
+    let (temperature, slope) = interpolate(swap(table[k - 1]), swap(table[k]), energy);
+    (temperature, 1.0 / slope)
+}
+
+// The linear interpolation, or extrapolation, between the points `a` and `b` at `x`, and its slope
+fn interpolate(a: (Float, Float), b: (Float, Float), x: Float) -> (Float, Float) {
+    let slope = (b.1 - a.1) / (b.0 - a.0);
+    (a.1 + slope * (x - a.0), slope)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(equation_of_state: EquationOfStateType) -> SimulationConfig {
+        SimulationConfig {
+            equation_of_state,
+            ..SimulationConfig::default()
+        }
+    }
+
+    // d ln P / d ln rho, by central differences
+    fn effective_index(config: &SimulationConfig, density: Float, energy: Float) -> Float {
+        let step = 1e-4;
+        let pressure =
+            |density: Float| with_equation_of_state(config, |eos| eos.pressure(density, energy));
+        let (high, low) = (density * (1.0 + step), density * (1.0 - step));
+        (pressure(high) / pressure(low)).ln() / (high / low).ln()
+    }
+
+    #[test]
+    fn barotropic_is_isothermal_below_the_critical_density_and_stiffens_above() {
+        let config = config(EquationOfStateType::Barotropic);
+        let isothermal = GAS_CONSTANT * config.initial_temperature / config.molar_mass;
+        let density = 1e-9 * config.critical_density;
+        let pressure = with_equation_of_state(&config, |eos| eos.pressure(density, 0.0));
+        assert!((pressure / (isothermal * density) - 1.0).abs() < 1e-3);
+        assert!((effective_index(&config, density, 0.0) - 1.0).abs() < 1e-3);
+
+        let density = 1e10 * config.critical_density;
+        let index = effective_index(&config, density, 0.0);
+        assert!((index - 1.4).abs() < 1e-3, "{}", index);
+    }
+
+    #[test]
+    fn temperature_pressure_and_sound_speed_are_consistent() {
+        let types = [
+            EquationOfStateType::IdealGas,
+            EquationOfStateType::Isothermal,
+            EquationOfStateType::Polytropic,
+            EquationOfStateType::Barotropic,
+        ];
+        for &equation_of_state in &types {
+            let config = config(equation_of_state);
+            let energy = config.initial_thermal_energy() / config.particle_mass();
+            for &density in &[1e-16, 1e-13, 1e-11, 1e-8] {
+                let (pressure, sound_speed, temperature) = with_equation_of_state(&config, |eos| {
+                    (
+                        eos.pressure(density, energy),
+                        eos.sound_speed(density, energy),
+                        eos.temperature(density, energy),
+                    )
+                });
+                // The ideal gas law holds for the temperature of every equation of state
+                let ideal = density * GAS_CONSTANT * temperature / config.molar_mass;
+                assert!(
+                    (pressure / ideal - 1.0).abs() < 1e-12,
+                    "{:?} at {}",
+                    equation_of_state,
+                    density
+                );
+                // The adiabatic index is that of the ideal gas, and the effective one otherwise
+                let index = match equation_of_state {
+                    EquationOfStateType::IdealGas => config.adiabatic_index,
+                    _ => effective_index(&config, density, energy),
+                };
+                let expected = (index * pressure / density).sqrt();
+                assert!(
+                    (sound_speed / expected - 1.0).abs() < 1e-6,
+                    "{:?} at {}",
+                    equation_of_state,
+                    density
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,405 @@
+use crate::config::ExportFormat;
+use crate::cooling;
+use crate::kernel;
+use crate::simulation::Simulation;
+use crate::vector::Float;
+use hdf5_pure::{AttrValue, FileBuilder};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+// Writes the gas particles of `simulation` as snapshot `number` in the configured format, in SI
+// units, for reading by external tools such as yt. Returns the path written
+pub fn export(
+    simulation: &Simulation,
+    densities: &[Float],
+    number: usize,
+) -> Result<PathBuf, String> {
+    let config = simulation.config();
+    let path = match config.export_format {
+        ExportFormat::Gadget1 | ExportFormat::Gadget2 => {
+            PathBuf::from(format!("{}_{:03}", config.export_prefix, number))
+        }
+        ExportFormat::Hdf5 => PathBuf::from(format!("{}_{:03}.hdf5", config.export_prefix, number)),
+    };
+    let fields = Fields::new(simulation, densities);
+    match config.export_format {
+        ExportFormat::Gadget1 => write_gadget(&path, &fields, false),
+        ExportFormat::Gadget2 => write_gadget(&path, &fields, true),
+        ExportFormat::Hdf5 => write_hdf5(&path, &fields),
+    }
+    .map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+    Ok(path)
+}
+
+// Gas is written as particle type 0 and sinks as type 5
+struct Fields {
+    count: usize,
+    sinks: usize,
+    time: Float,
+    // Of the gas particles followed by the sinks
+    positions: Vec<Float>,
+    velocities: Vec<Float>,
+    masses: Vec<Float>,
+    // Of the gas particles only, with energies per unit mass
+    internal_energies: Vec<Float>,
+    densities: Vec<Float>,
+    // The radius of the kernel support, following GADGET
+    smoothing_lengths: Vec<Float>,
+    // The net radiative cooling rate per unit mass, after heating
+    cooling_rates: Vec<Float>,
+    // Per unit mass, with `radiation` only
+    radiation_energies: Option<Vec<Float>>,
+    // With `magnetic_fields` only
+    magnetic_fields: Option<Vec<Float>>,
+}
+
+impl Fields {
+    fn new(simulation: &Simulation, densities: &[Float]) -> Self {
+        let config = simulation.config();
+        let support = kernel::with_kernel(config, |kernel| kernel.support());
+        let count = config.count;
+        Fields {
+            count,
+            sinks: simulation.sinks().len(),
+            time: simulation.time(),
+            positions: simulation
+                .positions()
+                .iter()
+                .flat_map(|p| p.iter().copied())
+                .collect(),
+            velocities: simulation
+                .velocities()
+                .iter()
+                .flat_map(|v| v.iter().copied())
+                .collect(),
+            masses: simulation.masses().to_vec(),
+            internal_energies: simulation.thermal_energies()[..count]
+                .iter()
+                .zip(simulation.masses().iter())
+                .map(|(&energy, &mass)| energy / mass)
+                .collect(),
+            densities: densities[..count].to_vec(),
+            smoothing_lengths: simulation.smoothing_lengths()[..count]
+                .iter()
+                .map(|&smooth| smooth * support)
+                .collect(),
+            cooling_rates: (0..count)
+                .map(|i| {
+                    let (mass, energy) = (simulation.masses()[i], simulation.thermal_energies()[i]);
+                    -cooling::rate(config, mass, energy, densities[i]) / mass
+                })
+                .collect(),
+            radiation_energies: if config.radiation {
+                Some(
+                    simulation.radiation_energies()[..count]
+                        .iter()
+                        .zip(simulation.masses().iter())
+                        .map(|(&energy, &mass)| energy / mass)
+                        .collect(),
+                )
+            } else {
+                None
+            },
+            magnetic_fields: if config.magnetic_fields {
+                Some(
+                    simulation.magnetic_fields()[..count]
+                        .iter()
+                        .flat_map(|b| b.iter().copied())
+                        .collect(),
+                )
+            } else {
+                None
+            },
+        }
+    }
+}
+
+// GADGET-2 snapshots in single precision, as read by default by most tools. Blocks are Fortran
+// records, which in format 2 are each preceded by a record with the block name
+fn write_gadget(path: &Path, fields: &Fields, format2: bool) -> std::io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    let mut block = |name: &[u8; 4], data: Vec<u8>| -> std::io::Result<()> {
+        if format2 {
+            out.write_all(&8u32.to_le_bytes())?;
+            out.write_all(name)?;
+            out.write_all(&(data.len() as u32 + 8).to_le_bytes())?;
+            out.write_all(&8u32.to_le_bytes())?;
+        }
+        out.write_all(&(data.len() as u32).to_le_bytes())?;
+        out.write_all(&data)?;
+        out.write_all(&(data.len() as u32).to_le_bytes())
+    };
+    let singles = |values: &[Float]| -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|&x| (x as f32).to_le_bytes().to_vec())
+            .collect()
+    };
+
+    let count = [fields.count as u32, 0, 0, 0, 0, fields.sinks as u32];
+    let mut header = Vec::with_capacity(256);
+    header.extend(count.iter().flat_map(|n| n.to_le_bytes().to_vec()));
+    // An empty mass table, since masses are written per particle
+    header.extend(&[0; 48]);
+    // Time and redshift
+    header.extend(&fields.time.to_le_bytes());
+    header.extend(&0f64.to_le_bytes());
+    // Star formation and feedback flags
+    header.extend(&[0; 8]);
+    // Total counts
+    header.extend(count.iter().flat_map(|n| n.to_le_bytes().to_vec()));
+    // Cooling flag, then the number of files
+    header.extend(&0i32.to_le_bytes());
+    header.extend(&1i32.to_le_bytes());
+    // Box size, Omega0, OmegaLambda and the Hubble parameter
+    header.extend(&[0; 32]);
+    header.resize(256, 0);
+
+    block(b"HEAD", header)?;
+    block(b"POS ", singles(&fields.positions))?;
+    block(b"VEL ", singles(&fields.velocities))?;
+    let ids = (0..(fields.count + fields.sinks) as u32).flat_map(|id| id.to_le_bytes().to_vec());
+    block(b"ID  ", ids.collect())?;
+    block(b"MASS", singles(&fields.masses))?;
+    block(b"U   ", singles(&fields.internal_energies))?;
+    block(b"RHO ", singles(&fields.densities))?;
+    block(b"HSML", singles(&fields.smoothing_lengths))?;
+    out.flush()
+}
+
+// The group and attribute layout of GADGET and AREPO HDF5 snapshots, in double precision
+fn write_hdf5(path: &Path, fields: &Fields) -> std::io::Result<()> {
+    let (count, sinks) = (fields.count, fields.sinks);
+    let mut builder = FileBuilder::new();
+
+    let mut header = builder.create_group("Header");
+    let counts = vec![count as u32, 0, 0, 0, 0, sinks as u32];
+    header.set_attr("NumPart_ThisFile", AttrValue::U32Array(counts.clone()));
+    header.set_attr("NumPart_Total", AttrValue::U32Array(counts));
+    header.set_attr("NumPart_Total_HighWord", AttrValue::U32Array(vec![0; 6]));
+    header.set_attr("MassTable", AttrValue::F64Array(vec![0.0; 6]));
+    header.set_attr("Time", AttrValue::F64(fields.time));
+    header.set_attr("Redshift", AttrValue::F64(0.0));
+    header.set_attr("BoxSize", AttrValue::F64(0.0));
+    header.set_attr("NumFilesPerSnapshot", AttrValue::I32(1));
+    header.set_attr("Omega0", AttrValue::F64(0.0));
+    header.set_attr("OmegaLambda", AttrValue::F64(0.0));
+    header.set_attr("HubbleParam", AttrValue::F64(1.0));
+    header.set_attr("Flag_DoublePrecision", AttrValue::I32(1));
+    for flag in &[
+        "Flag_Sfr",
+        "Flag_Cooling",
+        "Flag_StellarAge",
+        "Flag_Metals",
+        "Flag_Feedback",
+    ] {
+        header.set_attr(flag, AttrValue::I32(0));
+    }
+    builder.add_group(header.finish());
+
+    let ids: Vec<u64> = (0..(count + sinks) as u64).collect();
+    let mut gas = builder.create_group("PartType0");
+    gas.create_dataset("Coordinates")
+        .with_f64_data(&fields.positions[..3 * count])
+        .with_shape(&[count as u64, 3]);
+    gas.create_dataset("Velocities")
+        .with_f64_data(&fields.velocities[..3 * count])
+        .with_shape(&[count as u64, 3]);
+    gas.create_dataset("ParticleIDs")
+        .with_u64_data(&ids[..count]);
+    gas.create_dataset("Masses")
+        .with_f64_data(&fields.masses[..count]);
+    gas.create_dataset("InternalEnergy")
+        .with_f64_data(&fields.internal_energies);
+    gas.create_dataset("Density")
+        .with_f64_data(&fields.densities);
+    gas.create_dataset("SmoothingLength")
+        .with_f64_data(&fields.smoothing_lengths);
+    gas.create_dataset("CoolingRate")
+        .with_f64_data(&fields.cooling_rates);
+    if let Some(radiation_energies) = &fields.radiation_energies {
+        gas.create_dataset("RadiationEnergy")
+            .with_f64_data(radiation_energies);
+    }
+    if let Some(magnetic_fields) = &fields.magnetic_fields {
+        gas.create_dataset("MagneticField")
+            .with_f64_data(magnetic_fields)
+            .with_shape(&[count as u64, 3]);
+    }
+    builder.add_group(gas.finish());
+
+    if sinks > 0 {
+        let mut sink = builder.create_group("PartType5");
+        sink.create_dataset("Coordinates")
+            .with_f64_data(&fields.positions[3 * count..])
+            .with_shape(&[sinks as u64, 3]);
+        sink.create_dataset("Velocities")
+            .with_f64_data(&fields.velocities[3 * count..])
+            .with_shape(&[sinks as u64, 3]);
+        sink.create_dataset("ParticleIDs")
+            .with_u64_data(&ids[count..]);
+        sink.create_dataset("Masses")
+            .with_f64_data(&fields.masses[count..]);
+        builder.add_group(sink.finish());
+    }
+
+    builder
+        .write(path)
+        .map_err(|e| std::io::Error::other(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SimulationConfig;
+    use crate::constants::{AU, SOLAR_MASS};
+    use crate::import;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::convert::TryInto;
+    use std::fs;
+
+    const COUNT: usize = 20;
+    const SINKS: usize = 2;
+
+    fn fields() -> Fields {
+        let mut rng = StdRng::seed_from_u64(12);
+        let mut values = |n: usize, scale: Float| -> Vec<Float> {
+            (0..n).map(|_| scale * rng.gen_range(0.5, 2.0)).collect()
+        };
+        let total = COUNT + SINKS;
+        Fields {
+            count: COUNT,
+            sinks: SINKS,
+            time: 3.7e11,
+            positions: values(3 * total, 100.0 * AU),
+            velocities: values(3 * total, 1e3),
+            masses: values(total, 1e-3 * SOLAR_MASS),
+            internal_energies: values(COUNT, 1e5),
+            densities: values(COUNT, 1e-15),
+            smoothing_lengths: values(COUNT, 10.0 * AU),
+            cooling_rates: values(COUNT, 1e-3),
+            radiation_energies: None,
+            magnetic_fields: None,
+        }
+    }
+
+    fn assert_single_precision(read: &[Float], written: &[Float]) {
+        assert_eq!(read.len(), written.len());
+        for (&a, &b) in read.iter().zip(written) {
+            assert_eq!(a, b as f32 as Float);
+        }
+    }
+
+    // The contents of the Fortran records of a GADGET snapshot, in order
+    fn records(mut bytes: &[u8]) -> Vec<&[u8]> {
+        let length = |bytes: &[u8]| u32::from_le_bytes(bytes[..4].try_into().unwrap()) as usize;
+        let mut records = Vec::new();
+        while !bytes.is_empty() {
+            let len = length(bytes);
+            assert_eq!(length(&bytes[4 + len..]), len);
+            records.push(&bytes[4..4 + len]);
+            bytes = &bytes[8 + len..];
+        }
+        records
+    }
+
+    #[test]
+    fn gadget_snapshots_read_back() {
+        let written = fields();
+        let total = COUNT + SINKS;
+        let blocks: [(&[u8; 4], usize); 8] = [
+            (b"HEAD", 256),
+            (b"POS ", 12 * total),
+            (b"VEL ", 12 * total),
+            (b"ID  ", 4 * total),
+            (b"MASS", 4 * total),
+            (b"U   ", 4 * COUNT),
+            (b"RHO ", 4 * COUNT),
+            (b"HSML", 4 * COUNT),
+        ];
+        for &format2 in &[false, true] {
+            let path =
+                std::env::temp_dir().join(format!("export_{}_{}", format2, std::process::id()));
+            write_gadget(&path, &written, format2).unwrap();
+            let bytes = fs::read(&path).unwrap();
+            let read = import::import(&SimulationConfig::default(), &path);
+            fs::remove_file(&path).unwrap();
+
+            let records = records(&bytes);
+            let data: Vec<&[u8]> = if format2 {
+                assert_eq!(records.len(), 2 * blocks.len());
+                for (name, (block, &(expected, size))) in records
+                    .iter()
+                    .step_by(2)
+                    .zip(records.iter().skip(1).step_by(2).zip(&blocks))
+                {
+                    assert_eq!(&name[..4], expected);
+                    let next = u32::from_le_bytes(name[4..].try_into().unwrap()) as usize;
+                    assert_eq!(next, block.len() + 8);
+                    assert_eq!(block.len(), size);
+                }
+                records.iter().skip(1).step_by(2).copied().collect()
+            } else {
+                records
+            };
+            let sizes: Vec<usize> = data.iter().map(|block| block.len()).collect();
+            let expected: Vec<usize> = blocks.iter().map(|&(_, size)| size).collect();
+            assert_eq!(sizes, expected);
+
+            let header = data[0];
+            let word = |k: usize| u32::from_le_bytes(header[4 * k..4 * k + 4].try_into().unwrap());
+            let counts = [COUNT as u32, 0, 0, 0, 0, SINKS as u32];
+            assert_eq!((0..6).map(word).collect::<Vec<_>>(), counts);
+            // The total counts, then the number of files
+            assert_eq!((24..30).map(word).collect::<Vec<_>>(), counts);
+            assert_eq!(word(31), 1);
+            let time = Float::from_le_bytes(header[72..80].try_into().unwrap());
+            assert_eq!(time, written.time);
+
+            let read = read.unwrap();
+            let flatten = |vectors: &[crate::vector::Vector3]| -> Vec<Float> {
+                vectors.iter().flat_map(|v| v.iter().copied()).collect()
+            };
+            assert_single_precision(&flatten(&read.positions), &written.positions);
+            assert_single_precision(&flatten(&read.velocities), &written.velocities);
+            assert_single_precision(&read.masses, &written.masses);
+            assert_single_precision(&read.internal_energies, &written.internal_energies);
+            assert_eq!(read.time, written.time);
+        }
+    }
+
+    #[test]
+    fn hdf5_snapshots_read_back() {
+        let written = fields();
+        let path = std::env::temp_dir().join(format!("export_{}.hdf5", std::process::id()));
+        write_hdf5(&path, &written).unwrap();
+        let file = hdf5_pure::File::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let header = file.group("Header").unwrap().attrs().unwrap();
+        let counts = vec![COUNT as u32, 0, 0, 0, 0, SINKS as u32];
+        assert_eq!(
+            header["NumPart_ThisFile"],
+            AttrValue::U32Array(counts.clone())
+        );
+        assert_eq!(header["NumPart_Total"], AttrValue::U32Array(counts));
+        assert_eq!(header["Time"], AttrValue::F64(written.time));
+
+        let read = |name: &str| file.dataset(name).unwrap().read_f64().unwrap();
+        let shape = |name: &str| file.dataset(name).unwrap().shape().unwrap();
+        let gas = 3 * COUNT;
+        assert_eq!(shape("PartType0/Coordinates"), vec![COUNT as u64, 3]);
+        assert_eq!(shape("PartType5/Coordinates"), vec![SINKS as u64, 3]);
+        assert_eq!(read("PartType0/Coordinates"), written.positions[..gas]);
+        assert_eq!(read("PartType5/Coordinates"), written.positions[gas..]);
+        assert_eq!(read("PartType0/Velocities"), written.velocities[..gas]);
+        assert_eq!(read("PartType5/Velocities"), written.velocities[gas..]);
+        assert_eq!(read("PartType0/Masses"), written.masses[..COUNT]);
+        assert_eq!(read("PartType5/Masses"), written.masses[COUNT..]);
+        assert_eq!(read("PartType0/InternalEnergy"), written.internal_energies);
+        assert_eq!(read("PartType0/Density"), written.densities);
+        assert_eq!(read("PartType0/SmoothingLength"), written.smoothing_lengths);
+    }
+}
@@ -0,0 +1,477 @@
+use crate::config::{SimulationConfig, Softening};
+use crate::constants::GRAVITATIONAL_CONSTANT;
+use crate::octree::Octree;
+use crate::particle;
+use crate::vector::{Float, Vector3};
+use rayon::prelude::*;
+
+// Cartesian fast multipole method. Multipoles of order `p` are expanded about the center of mass
+// of every node, and local expansions of order `p + 1` about the geometric center, so that both
+// the potential and the acceleration are accurate to order `p`.
+//
+// With `T_k(r) = D^k (1/|r|) / k!` for a multi-index `k`, the potential of the particles `j`
+// of a node with center of mass `c`, as seen from `x`, is
+//     phi(x) = -G sum_k M_k T_k(x - c),  M_k = sum_j m_j (c - x_j)^k
+
+// Returns the (acceleration, specific potential energy) of every particle
+pub fn fmm(
+    config: &SimulationConfig,
+    positions: &[Vector3],
+    masses: &[Float],
+    softening: &[Float],
+) -> Vec<(Vector3, Float)> {
+    let fmm = Fmm::new(config, positions, masses, softening);
+    let root = Octree::ROOT;
+    let mut result = vec![(Vector3::zero(), 0.0); positions.len()];
+    for (i, accel, potential) in fmm.descend(root, vec![0.0; fmm.local_len], vec![root]) {
+        result[i] = (accel, potential);
+    }
+    result
+}
+
+// All multi-indices of degree at most `order`, sorted by degree
+struct MultiIndices {
+    order: usize,
+    list: Vec<[usize; 3]>,
+    lookup: Vec<usize>,
+    // The indices of `k - e_i` and `k - 2e_i` for every `k` and axis `i`, or `usize::MAX`
+    lower: Vec<[(usize, usize); 3]>,
+}
+
+impl MultiIndices {
+    fn new(order: usize) -> Self {
+        let mut list = Vec::new();
+        for degree in 0..=order {
+            for a in (0..=degree).rev() {
+                for b in (0..=degree - a).rev() {
+                    list.push([a, b, degree - a - b]);
+                }
+            }
+        }
+        let mut lookup = vec![usize::MAX; (order + 1).pow(3)];
+        for (index, k) in list.iter().enumerate() {
+            lookup[(k[0] * (order + 1) + k[1]) * (order + 1) + k[2]] = index;
+        }
+        let mut indices = MultiIndices {
+            order,
+            list,
+            lookup,
+            lower: Vec::new(),
+        };
+        indices.lower = indices
+            .list
+            .iter()
+            .map(|&k| {
+                let mut lower = [(usize::MAX, usize::MAX); 3];
+                for (axis, lower) in lower.iter_mut().enumerate() {
+                    let mut k = k;
+                    if k[axis] >= 1 {
+                        k[axis] -= 1;
+                        lower.0 = indices.index(k);
+                    }
+                    if k[axis] >= 1 {
+                        k[axis] -= 1;
+                        lower.1 = indices.index(k);
+                    }
+                }
+                lower
+            })
+            .collect();
+        indices
+    }
+
+    // The number of multi-indices of degree at most `order`
+    fn count(order: usize) -> usize {
+        (order + 1) * (order + 2) * (order + 3) / 6
+    }
+
+    fn index(&self, k: [usize; 3]) -> usize {
+        self.lookup[(k[0] * (self.order + 1) + k[1]) * (self.order + 1) + k[2]]
+    }
+
+    // `v^k` for the first `len` multi-indices `k`
+    fn monomials(&self, v: Vector3, len: usize) -> Vec<Float> {
+        let mut powers = vec![[1.0; 3]; self.order + 1];
+        for n in 1..=self.order {
+            for (axis, &x) in v.items().iter().enumerate() {
+                powers[n][axis] = powers[n - 1][axis] * x;
+            }
+        }
+        self.list[..len]
+            .iter()
+            .map(|k| powers[k[0]][0] * powers[k[1]][1] * powers[k[2]][2])
+            .collect()
+    }
+
+    // `T_k(r)` for the first `len` multi-indices `k`, from the recurrence
+    //     |k| r^2 T_k + (2|k| - 1) sum_i r_i T_{k - e_i} + (|k| - 1) sum_i T_{k - 2e_i} = 0
+    fn derivatives(&self, r: Vector3, len: usize) -> Vec<Float> {
+        let r2 = r.norm_squared();
+        let mut derivatives = vec![0.0; len];
+        derivatives[0] = r2.sqrt().recip();
+        for index in 1..len {
+            let k = self.list[index];
+            let degree = (k[0] + k[1] + k[2]) as Float;
+            let mut first = 0.0;
+            let mut second = 0.0;
+            for (&(once, twice), &x) in self.lower[index].iter().zip(r.items()) {
+                if once != usize::MAX {
+                    first += x * derivatives[once];
+                }
+                if twice != usize::MAX {
+                    second += derivatives[twice];
+                }
+            }
+            derivatives[index] =
+                -((2.0 * degree - 1.0) * first + (degree - 1.0) * second) / (degree * r2);
+        }
+        derivatives
+    }
+}
+
+// The binomial coefficient of multi-indices, `prod_i (k_i choose q_i)`
+fn binomial(k: [usize; 3], q: [usize; 3]) -> Float {
+    (0..3)
+        .map(|axis| {
+            (0..q[axis])
+                .map(|n| (k[axis] - n) as Float / (n + 1) as Float)
+                .product::<Float>()
+        })
+        .product()
+}
+
+// `k - q` if `q <= k` componentwise
+fn difference(k: [usize; 3], q: [usize; 3]) -> Option<[usize; 3]> {
+    if (0..3).all(|axis| q[axis] <= k[axis]) {
+        Some([k[0] - q[0], k[1] - q[1], k[2] - q[2]])
+    } else {
+        None
+    }
+}
+
+struct Fmm<'a> {
+    config: &'a SimulationConfig,
+    positions: &'a [Vector3],
+    masses: &'a [Float],
+    softening: &'a [Float],
+    tree: Octree,
+    indices: MultiIndices,
+    multipole_len: usize,
+    local_len: usize,
+    // (target, source, result, coefficient) index quadruples of each translation
+    multipole_shift: Vec<(usize, usize, usize, Float)>,
+    multipole_to_local: Vec<(usize, usize, usize, Float)>,
+    local_shift: Vec<(usize, usize, usize, Float)>,
+    // Per node
+    multipoles: Vec<Vec<Float>>,
+    centers_of_mass: Vec<Vector3>,
+    radii: Vec<Float>,
+    softenings: Vec<Float>,
+}
+
+impl<'a> Fmm<'a> {
+    // Larger than for Barnes-Hut, since multipole to local translations are expensive
+    const LEAF_SIZE: usize = 32;
+
+    fn new(
+        config: &'a SimulationConfig,
+        positions: &'a [Vector3],
+        masses: &'a [Float],
+        softening: &'a [Float],
+    ) -> Self {
+        let p = config.multipole_order;
+        let indices = MultiIndices::new(2 * p + 1);
+        let multipole_len = MultiIndices::count(p);
+        let local_len = MultiIndices::count(p + 1);
+
+        // M_k += C(k, q) (c - c')^(k - q) M'_q
+        let mut multipole_shift = Vec::new();
+        for (k_index, &k) in indices.list[..multipole_len].iter().enumerate() {
+            for (q_index, &q) in indices.list[..multipole_len].iter().enumerate() {
+                if let Some(diff) = difference(k, q) {
+                    let coefficient = binomial(k, q);
+                    multipole_shift.push((k_index, q_index, indices.index(diff), coefficient));
+                }
+            }
+        }
+        // L_l += C(k + l, k) T_{k + l} M_k
+        let mut multipole_to_local = Vec::new();
+        for (l_index, &l) in indices.list[..local_len].iter().enumerate() {
+            for (k_index, &k) in indices.list[..multipole_len].iter().enumerate() {
+                let sum = [k[0] + l[0], k[1] + l[1], k[2] + l[2]];
+                let coefficient = binomial(sum, k);
+                multipole_to_local.push((l_index, k_index, indices.index(sum), coefficient));
+            }
+        }
+        // L'_q += C(l, q) s^(l - q) L_l
+        let mut local_shift = Vec::new();
+        for (q_index, &q) in indices.list[..local_len].iter().enumerate() {
+            for (l_index, &l) in indices.list[..local_len].iter().enumerate() {
+                if let Some(diff) = difference(l, q) {
+                    let coefficient = binomial(l, q);
+                    local_shift.push((q_index, l_index, indices.index(diff), coefficient));
+                }
+            }
+        }
+
+        let mut fmm = Fmm {
+            config,
+            positions,
+            masses,
+            softening,
+            tree: Octree::new(positions, Self::LEAF_SIZE),
+            indices,
+            multipole_len,
+            local_len,
+            multipole_shift,
+            multipole_to_local,
+            local_shift,
+            multipoles: Vec::new(),
+            centers_of_mass: Vec::new(),
+            radii: Vec::new(),
+            softenings: Vec::new(),
+        };
+        fmm.ascend();
+        fmm
+    }
+
+    // Computes the multipoles and largest softening lengths of all nodes, children before parents
+    fn ascend(&mut self) {
+        let node_count = self.tree.nodes.len();
+        self.multipoles = vec![Vec::new(); node_count];
+        self.centers_of_mass = vec![Vector3::zero(); node_count];
+        self.radii = vec![0.0; node_count];
+        self.softenings = vec![0.0; node_count];
+        for node in (0..node_count).rev() {
+            let mut multipole = vec![0.0; self.multipole_len];
+            if self.tree.is_leaf(node) {
+                let points = self.tree.points(node);
+                let mass: Float = points.iter().map(|&i| self.masses[i]).sum();
+                let center = points
+                    .iter()
+                    .map(|&i| self.positions[i] * self.masses[i])
+                    .sum::<Vector3>()
+                    / mass;
+                for &i in points {
+                    let monomials = self
+                        .indices
+                        .monomials(center - self.positions[i], self.multipole_len);
+                    for (moment, monomial) in multipole.iter_mut().zip(monomials) {
+                        *moment += self.masses[i] * monomial;
+                    }
+                    self.radii[node] = self.radii[node].max((self.positions[i] - center).norm());
+                    self.softenings[node] = self.softenings[node].max(self.softening[i]);
+                }
+                self.centers_of_mass[node] = center;
+            } else {
+                let children = &self.tree.nodes[node].children;
+                let total: Float = children.iter().map(|&c| self.multipoles[c][0]).sum();
+                let center = children
+                    .iter()
+                    .map(|&c| self.centers_of_mass[c] * self.multipoles[c][0])
+                    .sum::<Vector3>()
+                    / total;
+                for &child in children {
+                    let offset = center - self.centers_of_mass[child];
+                    let monomials = self.indices.monomials(offset, self.multipole_len);
+                    for &(k, q, diff, coefficient) in &self.multipole_shift {
+                        multipole[k] += coefficient * monomials[diff] * self.multipoles[child][q];
+                    }
+                    self.radii[node] = self.radii[node].max(offset.norm() + self.radii[child]);
+                    self.softenings[node] = self.softenings[node].max(self.softenings[child]);
+                }
+                self.centers_of_mass[node] = center;
+            }
+            self.multipoles[node] = multipole;
+        }
+    }
+
+    // The expansions are not softened, so the particles of the two nodes must also be far enough
+    // apart for softened gravity to be newtonian, which it is beyond twice the softening length
+    // for the spline, while Plummer spheres only approach it, to within 3% at 8 softening lengths
+    fn well_separated(&self, target: usize, source: usize) -> bool {
+        let target_radius = 3.0_f64.sqrt() * self.tree.nodes[target].half_width;
+        let dist = (self.tree.nodes[target].center - self.centers_of_mass[source]).norm();
+        let reach = match self.config.softening {
+            Softening::None => 0.0,
+            Softening::Plummer => 8.0,
+            Softening::Spline => 2.0,
+        } * self.softenings[target].max(self.softenings[source]);
+        target_radius + self.radii[source] < self.config.opening_angle * dist
+            && target_radius + self.radii[source] + reach <= dist
+    }
+
+    // Accumulates the local expansion of `node` from the expansion of its parent and all
+    // well-separated sources among `candidates`, passing the sources that are too close on to
+    // the children. Returns the (index, acceleration, potential) of the particles in `node`
+    fn descend(
+        &self,
+        node: usize,
+        mut local: Vec<Float>,
+        mut candidates: Vec<usize>,
+    ) -> Vec<(usize, Vector3, Float)> {
+        let is_leaf = self.tree.is_leaf(node);
+        let half_width = self.tree.nodes[node].half_width;
+        let mut near = Vec::new();
+        while let Some(source) = candidates.pop() {
+            if self.well_separated(node, source) {
+                let offset = self.tree.nodes[node].center - self.centers_of_mass[source];
+                let derivatives = self.indices.derivatives(offset, self.indices.list.len());
+                let multipole = &self.multipoles[source];
+                for &(l, k, sum, coefficient) in &self.multipole_to_local {
+                    local[l] += coefficient * derivatives[sum] * multipole[k];
+                }
+            } else if self.tree.is_leaf(source)
+                || (!is_leaf && self.tree.nodes[source].half_width <= half_width)
+            {
+                near.push(source);
+            } else {
+                candidates.extend(self.tree.nodes[source].children.iter().copied());
+            }
+        }
+
+        if is_leaf {
+            self.evaluate(node, &local, &near)
+        } else {
+            self.tree.nodes[node]
+                .children
+                .par_iter()
+                .flat_map(|&child| {
+                    let offset = self.tree.nodes[child].center - self.tree.nodes[node].center;
+                    let monomials = self.indices.monomials(offset, self.local_len);
+                    let mut shifted = vec![0.0; self.local_len];
+                    for &(q, l, diff, coefficient) in &self.local_shift {
+                        shifted[q] += coefficient * monomials[diff] * local[l];
+                    }
+                    self.descend(child, shifted, near.clone())
+                })
+                .collect()
+        }
+    }
+
+    // Evaluates the local expansion at the particles of a leaf, and adds the direct interactions
+    // with the particles of the nearby leaves
+    fn evaluate(
+        &self,
+        node: usize,
+        local: &[Float],
+        near: &[usize],
+    ) -> Vec<(usize, Vector3, Float)> {
+        self.tree
+            .points(node)
+            .iter()
+            .map(|&i| {
+                let pos = self.positions[i];
+                let offset = pos - self.tree.nodes[node].center;
+                let monomials = self.indices.monomials(offset, self.local_len);
+                let mut potential = 0.0;
+                let mut gradient = [0.0; 3];
+                for (index, &l) in self.indices.list[..self.local_len].iter().enumerate() {
+                    potential += local[index] * monomials[index];
+                    for axis in 0..3 {
+                        let (once, _) = self.indices.lower[index][axis];
+                        if once != usize::MAX {
+                            gradient[axis] += local[index] * l[axis] as Float * monomials[once];
+                        }
+                    }
+                }
+                let mut accel =
+                    GRAVITATIONAL_CONSTANT * gradient.iter().copied().collect::<Vector3>();
+                let mut potential = -GRAVITATIONAL_CONSTANT * potential;
+                for &source in near {
+                    for &j in self.tree.points(source) {
+                        if j != i {
+                            let (self_soft, other_soft) = (self.softening[i], self.softening[j]);
+                            let (other_pos, other_mass) = (self.positions[j], self.masses[j]);
+                            accel += particle::gravitational_acceleration_from(
+                                self.config,
+                                pos,
+                                self_soft,
+                                other_pos,
+                                other_mass,
+                                other_soft,
+                            );
+                            potential += particle::gravitational_potential_from(
+                                self.config,
+                                pos,
+                                self_soft,
+                                other_pos,
+                                other_mass,
+                                other_soft,
+                            );
+                        }
+                    }
+                }
+                (i, accel, potential)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::GravitySolver;
+    use crate::gravity;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn particles(count: usize) -> (Vec<Vector3>, Vec<Float>) {
+        let mut rng = StdRng::seed_from_u64(1);
+        let positions = (0..count)
+            .map(|_| (0..3).map(|_| rng.gen_range(-1e15, 1e15)).collect())
+            .collect();
+        let masses = (0..count).map(|_| rng.gen_range(1e29, 2e29)).collect();
+        (positions, masses)
+    }
+
+    // The rms relative error of the accelerations against direct summation
+    fn rms_error(config: &SimulationConfig, count: usize, softening: Float) -> Float {
+        let (positions, masses) = particles(count);
+        let softening = vec![softening; count];
+        let exact = gravity::direct_accelerations(config, &positions, &masses, &softening);
+        let approximate = fmm(config, &positions, &masses, &softening);
+        let sum: Float = exact
+            .iter()
+            .zip(&approximate)
+            .map(|(&exact, &(approximate, _))| {
+                ((approximate - exact).norm() / exact.norm()).powi(2)
+            })
+            .sum();
+        (sum / count as Float).sqrt()
+    }
+
+    #[test]
+    fn error_decreases_with_order() {
+        let errors: Vec<Float> = (0..=4)
+            .map(|order| {
+                let config = SimulationConfig {
+                    gravity_solver: GravitySolver::Fmm,
+                    opening_angle: 0.7,
+                    multipole_order: order,
+                    ..SimulationConfig::default()
+                };
+                rms_error(&config, 1000, 0.0)
+            })
+            .collect();
+        for order in 1..errors.len() {
+            assert!(errors[order] < errors[order - 1], "{:?}", errors);
+        }
+        assert!(errors[3] < 2e-3, "{} at order 3", errors[3]);
+    }
+
+    #[test]
+    fn softened_error_matches_unsoftened() {
+        for softening in [Softening::Plummer, Softening::Spline] {
+            let config = SimulationConfig {
+                gravity_solver: GravitySolver::Fmm,
+                opening_angle: 0.7,
+                multipole_order: 3,
+                softening,
+                ..SimulationConfig::default()
+            };
+            let error = rms_error(&config, 1000, 1e14);
+            assert!(error < 2e-3, "{} with {:?} softening", error, softening);
+        }
+    }
+}
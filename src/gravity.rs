@@ -0,0 +1,353 @@
+use crate::config::{GravitySolver, SimulationConfig};
+use crate::constants::GRAVITATIONAL_CONSTANT;
+use crate::fmm;
+use crate::octree::Octree;
+use crate::particle;
+use crate::vector::{Float, Vector3};
+use rayon::prelude::*;
+
+// The softening length of every particle, given their smoothing lengths
+pub fn softening_lengths(config: &SimulationConfig, smoothing_lengths: &[Float]) -> Vec<Float> {
+    if config.adaptive_softening {
+        smoothing_lengths.to_vec()
+    } else {
+        vec![config.softening_length; smoothing_lengths.len()]
+    }
+}
+
+// The gravitational acceleration of every particle, using the configured solver
+pub fn accelerations(
+    config: &SimulationConfig,
+    positions: &[Vector3],
+    masses: &[Float],
+    softening: &[Float],
+) -> Vec<Vector3> {
+    match config.gravity_solver {
+        GravitySolver::Direct => direct_accelerations(config, positions, masses, softening),
+        GravitySolver::BarnesHut => barnes_hut(
+            config,
+            positions,
+            masses,
+            softening,
+            &vec![true; positions.len()],
+        )
+        .into_iter()
+        .map(|(accel, _)| accel)
+        .collect(),
+        GravitySolver::Fmm => fmm::fmm(config, positions, masses, softening)
+            .into_iter()
+            .map(|(accel, _)| accel)
+            .collect(),
+    }
+}
+
+// The gravitational acceleration of the `active` particles, and zero for the others. Only the
+// fast multipole method evaluates every particle regardless
+pub fn active_accelerations(
+    config: &SimulationConfig,
+    positions: &[Vector3],
+    masses: &[Float],
+    softening: &[Float],
+    active: &[bool],
+) -> Vec<Vector3> {
+    match config.gravity_solver {
+        GravitySolver::Direct => (0..positions.len())
+            .into_par_iter()
+            .map(|i| {
+                if active[i] {
+                    particle::gravitational_acceleration(
+                        config,
+                        positions[i],
+                        softening[i],
+                        positions,
+                        masses,
+                        softening,
+                    )
+                } else {
+                    Vector3::zero()
+                }
+            })
+            .collect(),
+        GravitySolver::BarnesHut => barnes_hut(config, positions, masses, softening, active)
+            .into_iter()
+            .map(|(accel, _)| accel)
+            .collect(),
+        GravitySolver::Fmm => fmm::fmm(config, positions, masses, softening)
+            .into_iter()
+            .zip(active.iter())
+            .map(|((accel, _), &active)| if active { accel } else { Vector3::zero() })
+            .collect(),
+    }
+}
+
+// The gravitational potential energy per unit mass of every particle, using the configured solver
+pub fn potentials(
+    config: &SimulationConfig,
+    positions: &[Vector3],
+    masses: &[Float],
+    softening: &[Float],
+) -> Vec<Float> {
+    match config.gravity_solver {
+        GravitySolver::Direct => direct_potentials(config, positions, masses, softening),
+        GravitySolver::BarnesHut => barnes_hut(
+            config,
+            positions,
+            masses,
+            softening,
+            &vec![true; positions.len()],
+        )
+        .into_iter()
+        .map(|(_, potential)| potential)
+        .collect(),
+        GravitySolver::Fmm => fmm::fmm(config, positions, masses, softening)
+            .into_iter()
+            .map(|(_, potential)| potential)
+            .collect(),
+    }
+}
+
+// O(n^2) direct summation
+pub fn direct_accelerations(
+    config: &SimulationConfig,
+    positions: &[Vector3],
+    masses: &[Float],
+    softening: &[Float],
+) -> Vec<Vector3> {
+    (0..positions.len())
+        .into_par_iter()
+        .map(|i| {
+            particle::gravitational_acceleration(
+                config,
+                positions[i],
+                softening[i],
+                positions,
+                masses,
+                softening,
+            )
+        })
+        .collect()
+}
+
+fn direct_potentials(
+    config: &SimulationConfig,
+    positions: &[Vector3],
+    masses: &[Float],
+    softening: &[Float],
+) -> Vec<Float> {
+    (0..positions.len())
+        .into_par_iter()
+        .map(|i| {
+            let mut potential = 0.0;
+            for j in 0..positions.len() {
+                if i != j {
+                    potential += particle::gravitational_potential_from(
+                        config,
+                        positions[i],
+                        softening[i],
+                        positions[j],
+                        masses[j],
+                        softening[j],
+                    );
+                }
+            }
+            potential
+        })
+        .collect()
+}
+
+// O(n log n) Barnes-Hut tree walk, approximating every node that is sufficiently far away by a
+// point mass at its center of mass. Like a pair of particles, the particle and the node are
+// softened by the mean of their softening lengths, with that of the node the mass weighted mean
+// of its particles, so that the forces stay close to antisymmetric. Returns the
+// (acceleration, potential) of every `active` particle, and zero for the others
+fn barnes_hut(
+    config: &SimulationConfig,
+    positions: &[Vector3],
+    masses: &[Float],
+    softening: &[Float],
+    active: &[bool],
+) -> Vec<(Vector3, Float)> {
+    let tree = Octree::new(positions, 8);
+    let monopoles = monopoles(&tree, positions, masses, softening);
+    (0..positions.len())
+        .into_par_iter()
+        .map(|i| {
+            if !active[i] {
+                return (Vector3::zero(), 0.0);
+            }
+            barnes_hut_walk(
+                config,
+                &tree,
+                &monopoles,
+                positions,
+                masses,
+                softening,
+                i,
+                Octree::ROOT,
+            )
+        })
+        .collect()
+}
+
+// (mass, center of mass, mass weighted mean softening length) of every node
+fn monopoles(
+    tree: &Octree,
+    positions: &[Vector3],
+    masses: &[Float],
+    softening: &[Float],
+) -> Vec<(Float, Vector3, Float)> {
+    let mut monopoles = vec![(0.0, Vector3::zero(), 0.0); tree.nodes.len()];
+    for node in (0..tree.nodes.len()).rev() {
+        monopoles[node] = if tree.is_leaf(node) {
+            let points = tree.points(node);
+            let mass: Float = points.iter().map(|&i| masses[i]).sum();
+            let center = points
+                .iter()
+                .map(|&i| positions[i] * masses[i])
+                .sum::<Vector3>()
+                / mass;
+            let soft = points
+                .iter()
+                .map(|&i| softening[i] * masses[i])
+                .sum::<Float>()
+                / mass;
+            (mass, center, soft)
+        } else {
+            let mass: Float = tree.nodes[node]
+                .children
+                .iter()
+                .map(|&child| monopoles[child].0)
+                .sum();
+            let center = tree.nodes[node]
+                .children
+                .iter()
+                .map(|&child| monopoles[child].1 * monopoles[child].0)
+                .sum::<Vector3>()
+                / mass;
+            let soft = tree.nodes[node]
+                .children
+                .iter()
+                .map(|&child| monopoles[child].2 * monopoles[child].0)
+                .sum::<Float>()
+                / mass;
+            (mass, center, soft)
+        };
+    }
+    monopoles
+}
+
+fn barnes_hut_walk(
+    config: &SimulationConfig,
+    tree: &Octree,
+    monopoles: &[(Float, Vector3, Float)],
+    positions: &[Vector3],
+    masses: &[Float],
+    softening: &[Float],
+    i: usize,
+    node: usize,
+) -> (Vector3, Float) {
+    let self_pos = positions[i];
+    let (mass, center_of_mass, soft) = monopoles[node];
+    let size = 2.0 * tree.nodes[node].half_width;
+    // Opening criterion with the offset of the center of mass from the geometric center added.
+    // Every point of the node is within `offset + size * sqrt(3) / 2` of its center of mass, so
+    // with the opening angle at most 2 / sqrt(3) a node is never approximated as seen from inside
+    // itself
+    let offset = (center_of_mass - tree.nodes[node].center).norm();
+    let dist = (center_of_mass - self_pos).norm();
+    if size / config.opening_angle + offset < dist {
+        let v = center_of_mass - self_pos;
+        let (force, potential) =
+            particle::softened_gravity(config, dist, (softening[i] + soft) / 2.0);
+        (
+            GRAVITATIONAL_CONSTANT * mass * force * v,
+            GRAVITATIONAL_CONSTANT * mass * potential,
+        )
+    } else if tree.is_leaf(node) {
+        let mut accel = Vector3::zero();
+        let mut potential = 0.0;
+        for &j in tree.points(node).iter().filter(|&&j| j != i) {
+            let (self_soft, other_soft) = (softening[i], softening[j]);
+            let (other_pos, other_mass) = (positions[j], masses[j]);
+            accel += particle::gravitational_acceleration_from(
+                config, self_pos, self_soft, other_pos, other_mass, other_soft,
+            );
+            potential += particle::gravitational_potential_from(
+                config, self_pos, self_soft, other_pos, other_mass, other_soft,
+            );
+        }
+        (accel, potential)
+    } else {
+        tree.nodes[node]
+            .children
+            .iter()
+            .map(|&child| {
+                barnes_hut_walk(
+                    config, tree, monopoles, positions, masses, softening, i, child,
+                )
+            })
+            .fold((Vector3::zero(), 0.0), |(accel, potential), (a, p)| {
+                (accel + a, potential + p)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Softening;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn particles(count: usize) -> (Vec<Vector3>, Vec<Float>) {
+        let mut rng = StdRng::seed_from_u64(2);
+        let positions = (0..count)
+            .map(|_| (0..3).map(|_| rng.gen_range(-1e15, 1e15)).collect())
+            .collect();
+        let masses = (0..count).map(|_| rng.gen_range(1e29, 2e29)).collect();
+        (positions, masses)
+    }
+
+    // The rms relative errors of the Barnes-Hut accelerations and potentials against direct
+    // summation
+    fn barnes_hut_errors(opening_angle: Float, softening: Float) -> (Float, Float) {
+        let config = SimulationConfig {
+            gravity_solver: GravitySolver::BarnesHut,
+            opening_angle,
+            softening: Softening::Plummer,
+            ..SimulationConfig::default()
+        };
+        let count = 1000;
+        let (positions, masses) = particles(count);
+        // Varied, so that the softening of the nodes matters
+        let softening: Vec<Float> = (0..count)
+            .map(|i| softening * (1 + i % 3) as Float / 2.0)
+            .collect();
+        let accelerations = direct_accelerations(&config, &positions, &masses, &softening);
+        let potentials = direct_potentials(&config, &positions, &masses, &softening);
+        let approximate = barnes_hut(&config, &positions, &masses, &softening, &vec![true; count]);
+        let (mut accel_sum, mut potential_sum) = (0.0, 0.0);
+        for i in 0..count {
+            let (accel, potential) = approximate[i];
+            accel_sum += ((accel - accelerations[i]).norm() / accelerations[i].norm()).powi(2);
+            potential_sum += ((potential - potentials[i]) / potentials[i]).powi(2);
+        }
+        (
+            (accel_sum / count as Float).sqrt(),
+            (potential_sum / count as Float).sqrt(),
+        )
+    }
+
+    #[test]
+    fn barnes_hut_matches_direct_summation() {
+        let (accel, potential) = barnes_hut_errors(0.5, 0.0);
+        assert!(accel < 1e-2 && potential < 1e-3, "{} {}", accel, potential);
+        let (finer, _) = barnes_hut_errors(0.3, 0.0);
+        assert!(finer < accel, "{} at 0.3, {} at 0.5", finer, accel);
+        // The target-only softening of the nodes gives (7.6e-3, 1.6e-3) and (8.7e-2, 2.8e-2)
+        let (accel, potential) = barnes_hut_errors(0.5, 1e14);
+        assert!(accel < 6e-3 && potential < 6e-4, "{} {}", accel, potential);
+        let (accel, potential) = barnes_hut_errors(0.5, 5e14);
+        assert!(accel < 2e-2 && potential < 5e-3, "{} {}", accel, potential);
+    }
+}
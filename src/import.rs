@@ -0,0 +1,432 @@
+use crate::config::SimulationConfig;
+use crate::vector::{Float, Vector3};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs;
+use std::path::Path;
+
+// Initial conditions read from a file, converted to SI units
+pub struct Particles {
+    // Of the gas particles followed by the sinks
+    pub positions: Vec<Vector3>,
+    pub velocities: Vec<Vector3>,
+    pub masses: Vec<Float>,
+    // Of the gas particles only, per unit mass
+    pub internal_energies: Vec<Float>,
+    pub time: Float,
+}
+
+// Reads particles from a `.csv`, `.txt` or `.dat` ASCII table, or otherwise from a GADGET-2
+// snapshot. Values in the file are in units of `import_length_unit`, `import_velocity_unit` and
+// `import_mass_unit`, from which internal energies are in velocity units squared and times in
+// length over velocity units
+pub fn import(config: &SimulationConfig, path: &Path) -> Result<Particles, String> {
+    let error = |e: String| format!("Could not import {}: {}", path.display(), e);
+    let bytes = fs::read(path).map_err(|e| error(e.to_string()))?;
+    let extension = path.extension().and_then(|ext| ext.to_str());
+    parse(config, &bytes, extension).map_err(error)
+}
+
+// The particles in the contents of a file with the given extension, as described for `import`
+fn parse(
+    config: &SimulationConfig,
+    bytes: &[u8],
+    extension: Option<&str>,
+) -> Result<Particles, String> {
+    let mut particles = match extension {
+        Some("csv") | Some("txt") | Some("dat") => read_ascii(bytes),
+        Some("hdf5") | Some("h5") => Err("HDF5 snapshots cannot be imported".to_owned()),
+        _ => read_gadget(bytes),
+    }?;
+
+    let length = config.import_length_unit;
+    let velocity = config.import_velocity_unit;
+    for p in particles.positions.iter_mut() {
+        *p *= length;
+    }
+    for v in particles.velocities.iter_mut() {
+        *v *= velocity;
+    }
+    for m in particles.masses.iter_mut() {
+        *m *= config.import_mass_unit;
+    }
+    for u in particles.internal_energies.iter_mut() {
+        *u *= velocity * velocity;
+    }
+    particles.time *= length / velocity;
+
+    if particles.internal_energies.is_empty() {
+        return Err("no gas particles".to_owned());
+    }
+    let mut vectors = particles
+        .positions
+        .iter()
+        .chain(particles.velocities.iter());
+    if !vectors.all(|v| v.is_finite()) || !particles.time.is_finite() {
+        return Err("positions, velocities and time must be finite".to_owned());
+    }
+    if !particles.masses.iter().all(|&m| m.is_finite() && m > 0.0) {
+        return Err("masses must be positive".to_owned());
+    }
+    if !particles
+        .internal_energies
+        .iter()
+        .all(|&u| u.is_finite() && u >= 0.0)
+    {
+        return Err("internal energies must be non-negative".to_owned());
+    }
+    Ok(particles)
+}
+
+// One particle per line as x, y, z, vx, vy, vz, mass and specific internal energy, separated by
+// commas or whitespace. Lines starting with # are comments, and a first line that is not numeric
+// is a column header
+fn read_ascii(bytes: &[u8]) -> Result<Particles, String> {
+    let text = std::str::from_utf8(bytes).map_err(|e| e.to_string())?;
+    let mut particles = Particles {
+        positions: Vec::new(),
+        velocities: Vec::new(),
+        masses: Vec::new(),
+        internal_energies: Vec::new(),
+        time: 0.0,
+    };
+    let lines = text
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'));
+    for (index, (number, line)) in lines.enumerate() {
+        let values: Result<Vec<Float>, _> = line
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|value| !value.is_empty())
+            .map(|value| value.parse::<Float>())
+            .collect();
+        let values = match values {
+            Ok(values) => values,
+            Err(_) if index == 0 => continue,
+            Err(e) => return Err(format!("line {}: {}", number + 1, e)),
+        };
+        if values.len() != 8 {
+            return Err(format!(
+                "line {}: expected 8 columns but found {}",
+                number + 1,
+                values.len()
+            ));
+        }
+        particles
+            .positions
+            .push(values[0..3].iter().copied().collect());
+        particles
+            .velocities
+            .push(values[3..6].iter().copied().collect());
+        particles.masses.push(values[6]);
+        particles.internal_energies.push(values[7]);
+    }
+    Ok(particles)
+}
+
+// Reads the gas particles, type 0, and sinks, type 5, of a single file GADGET-2 snapshot in
+// SnapFormat 1 or 2, in single or double precision. Other particle types are not supported
+fn read_gadget(bytes: &[u8]) -> Result<Particles, String> {
+    let mut input = bytes;
+    // Fortran records are enclosed by their length in bytes
+    let mut record = || -> Result<Option<&[u8]>, String> {
+        if input.is_empty() {
+            return Ok(None);
+        }
+        let len = u32_at(input, 0)? as usize;
+        let data = input.get(4..4 + len).ok_or("truncated record")?;
+        if u32_at(input, 4 + len)? as usize != len {
+            return Err("corrupt record length".to_owned());
+        }
+        input = &input[8 + len..];
+        Ok(Some(data))
+    };
+
+    // In format 2 each block is preceded by a record holding its name and the size of the
+    // rest of the file up to the next name record. In format 1 the block order is fixed
+    let first = record()?.ok_or("empty file")?;
+    let format2 = first.len() == 8;
+    let header = if format2 { record()? } else { Some(first) }.ok_or("missing header")?;
+    if header.len() != 256 {
+        return Err(format!("header is {} bytes instead of 256", header.len()));
+    }
+    let counts: Vec<u32> = (0..6)
+        .map(|k| u32_at(header, 4 * k))
+        .collect::<Result<_, _>>()?;
+    if counts[1..5].iter().any(|&n| n != 0) {
+        return Err("only gas and sink particles, types 0 and 5, are supported".to_owned());
+    }
+    if u32_at(header, 124)? > 1 {
+        return Err("snapshots split over several files are not supported".to_owned());
+    }
+    let (gas, sinks) = (counts[0] as usize, counts[5] as usize);
+    // Particles of a type with no mass in the mass table have theirs in the MASS block
+    let table_masses = [(gas, f64_at(header, 24)?), (sinks, f64_at(header, 64)?)];
+    let listed_masses: usize = table_masses
+        .iter()
+        .filter(|&&(_, mass)| mass == 0.0)
+        .map(|&(count, _)| count)
+        .sum();
+    let time = f64_at(header, 72)?;
+
+    let mut order = vec![b"POS ", b"VEL ", b"ID  "];
+    if listed_masses > 0 {
+        order.push(b"MASS");
+    }
+    order.push(b"U   ");
+    let mut blocks: HashMap<[u8; 4], &[u8]> = HashMap::new();
+    for index in 0.. {
+        let name = if format2 {
+            match record()? {
+                Some(name) if name.len() == 8 => name[..4].try_into().unwrap(),
+                Some(_) => return Err("corrupt block name".to_owned()),
+                None => break,
+            }
+        } else if index < order.len() {
+            *order[index]
+        } else {
+            break;
+        };
+        let data = record()?.ok_or("missing block data")?;
+        blocks.insert(name, data);
+    }
+
+    let mut floats =
+        |name: &[u8; 4], count: usize, per_particle: usize| -> Result<Vec<Float>, String> {
+            let display = String::from_utf8_lossy(name).trim().to_owned();
+            let data = blocks
+                .remove(name)
+                .ok_or_else(|| format!("missing {} block", display))?;
+            let values = count * per_particle;
+            if data.len() == 4 * values {
+                Ok(data
+                    .chunks(4)
+                    .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()) as Float)
+                    .collect())
+            } else if data.len() == 8 * values {
+                Ok(data
+                    .chunks(8)
+                    .map(|bytes| f64::from_le_bytes(bytes.try_into().unwrap()))
+                    .collect())
+            } else {
+                Err(format!(
+                    "{} block is {} bytes, which does not match {} particles",
+                    display,
+                    data.len(),
+                    count
+                ))
+            }
+        };
+    let vectors = |values: Vec<Float>| -> Vec<Vector3> {
+        values
+            .chunks(3)
+            .map(|xyz| xyz.iter().copied().collect())
+            .collect()
+    };
+    let mut listed = if listed_masses > 0 {
+        floats(b"MASS", listed_masses, 1)?
+    } else {
+        Vec::new()
+    }
+    .into_iter();
+    let mut masses = Vec::with_capacity(gas + sinks);
+    for &(count, mass) in &table_masses {
+        if mass == 0.0 {
+            masses.extend(listed.by_ref().take(count));
+        } else {
+            masses.extend(std::iter::repeat_n(mass, count));
+        }
+    }
+    Ok(Particles {
+        positions: vectors(floats(b"POS ", gas + sinks, 3)?),
+        velocities: vectors(floats(b"VEL ", gas + sinks, 3)?),
+        masses,
+        internal_energies: floats(b"U   ", gas, 1)?,
+        time,
+    })
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Result<u32, String> {
+    let bytes = bytes.get(offset..offset + 4).ok_or("truncated file")?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn f64_at(bytes: &[u8], offset: usize) -> Result<f64, String> {
+    let bytes = bytes.get(offset..offset + 8).ok_or("truncated file")?;
+    Ok(f64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> SimulationConfig {
+        SimulationConfig {
+            import_length_unit: 2.0,
+            import_velocity_unit: 3.0,
+            import_mass_unit: 5.0,
+            ..SimulationConfig::default()
+        }
+    }
+
+    fn ascii(text: &str) -> Result<Particles, String> {
+        parse(&config(), text.as_bytes(), Some("csv"))
+    }
+
+    #[test]
+    fn ascii_tables_are_read_in_units() {
+        let particles = ascii(
+            "x,y,z,vx,vy,vz,m,u\n\
+             # A comment\n\
+             1,2,3,4,5,6,7,8\n\
+             \n\
+             -1 0.5  0\t1e-3 0 0 2 0\n",
+        )
+        .unwrap();
+        let vector = |values: [Float; 3]| -> Vector3 { values.iter().copied().collect() };
+        assert_eq!(
+            particles.positions,
+            [vector([2.0, 4.0, 6.0]), vector([-2.0, 1.0, 0.0])]
+        );
+        assert_eq!(
+            particles.velocities,
+            [vector([12.0, 15.0, 18.0]), vector([3e-3, 0.0, 0.0])]
+        );
+        assert_eq!(particles.masses, [35.0, 10.0]);
+        assert_eq!(particles.internal_energies, [72.0, 0.0]);
+        assert_eq!(particles.time, 0.0);
+    }
+
+    #[test]
+    fn invalid_ascii_tables_are_rejected() {
+        for (text, message) in [
+            ("", "no gas particles"),
+            ("1,2,3,4,5,6,7\n", "line 1: expected 8 columns but found 7"),
+            ("1,2,3,4,5,6,7,8\n1,2,3,4,5,6,x,8\n", "line 2: "),
+            ("1,2,3,4,5,6,0,8\n", "masses must be positive"),
+            (
+                "1,2,3,4,5,6,7,-8\n",
+                "internal energies must be non-negative",
+            ),
+            (
+                "1,2,3,4,5,6,7,inf\n",
+                "internal energies must be non-negative",
+            ),
+            ("1,2,3,nan,5,6,7,8\n", "must be finite"),
+        ] {
+            let error = ascii(text).err().unwrap();
+            assert!(error.contains(message), "{:?} gave {:?}", text, error);
+        }
+    }
+
+    // A GADGET-2 snapshot of `gas` gas particles and `sinks` sinks at time 1, with the masses of
+    // either type in the mass table if given, and values in single or double precision
+    fn gadget(
+        format2: bool,
+        double: bool,
+        (gas, sinks): (usize, usize),
+        table: (Float, Float),
+    ) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut block = |name: &[u8; 4], data: Vec<u8>| {
+            if format2 {
+                bytes.extend(&8u32.to_le_bytes());
+                bytes.extend(name);
+                bytes.extend(&(data.len() as u32 + 8).to_le_bytes());
+                bytes.extend(&8u32.to_le_bytes());
+            }
+            bytes.extend(&(data.len() as u32).to_le_bytes());
+            bytes.extend(&data);
+            bytes.extend(&(data.len() as u32).to_le_bytes());
+        };
+        let floats = |values: Vec<Float>| -> Vec<u8> {
+            values
+                .into_iter()
+                .flat_map(|x| {
+                    if double {
+                        x.to_le_bytes().to_vec()
+                    } else {
+                        (x as f32).to_le_bytes().to_vec()
+                    }
+                })
+                .collect()
+        };
+        let counts = [gas as u32, 0, 0, 0, 0, sinks as u32];
+        let mut header: Vec<u8> = counts.iter().flat_map(|n| n.to_le_bytes()).collect();
+        for mass in [table.0, 0.0, 0.0, 0.0, 0.0, table.1] {
+            header.extend(&mass.to_le_bytes());
+        }
+        header.extend(&1.0f64.to_le_bytes());
+        header.resize(256, 0);
+
+        let total = gas + sinks;
+        block(b"HEAD", header);
+        block(
+            b"POS ",
+            floats((0..3 * total).map(|i| i as Float).collect()),
+        );
+        block(b"VEL ", floats(vec![1.0; 3 * total]));
+        block(
+            b"ID  ",
+            (0..total as u32).flat_map(|id| id.to_le_bytes()).collect(),
+        );
+        let listed: Vec<Float> = (0..gas)
+            .filter(|_| table.0 == 0.0)
+            .map(|_| 2.0)
+            .chain((0..sinks).filter(|_| table.1 == 0.0).map(|_| 4.0))
+            .collect();
+        if !listed.is_empty() {
+            block(b"MASS", floats(listed));
+        }
+        block(b"U   ", floats(vec![0.5; gas]));
+        bytes
+    }
+
+    #[test]
+    fn gadget_snapshots_are_read_in_units() {
+        for format2 in [false, true] {
+            for double in [false, true] {
+                for table in [(0.0, 0.0), (2.0, 0.0), (0.0, 4.0), (2.0, 4.0)] {
+                    let bytes = gadget(format2, double, (3, 2), table);
+                    let particles = parse(&config(), &bytes, None).unwrap();
+                    assert_eq!(particles.positions.len(), 5);
+                    assert_eq!(particles.positions[4].items()[2], 28.0);
+                    assert_eq!(particles.velocities[4].items()[0], 3.0);
+                    assert_eq!(particles.masses, [10.0, 10.0, 10.0, 20.0, 20.0]);
+                    assert_eq!(particles.internal_energies, [4.5; 3]);
+                    assert_eq!(particles.time, 2.0 / 3.0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_gadget_snapshots_are_rejected() {
+        let valid = gadget(false, false, (3, 0), (0.0, 0.0));
+        let mut other_type = valid.clone();
+        other_type[4 + 4..4 + 8].copy_from_slice(&1u32.to_le_bytes());
+        let mut short_block = valid.clone();
+        short_block.truncate(valid.len() - 4 - 12 - 4);
+        short_block.extend(&8u32.to_le_bytes());
+        short_block.extend(&[0; 8]);
+        short_block.extend(&8u32.to_le_bytes());
+        for (bytes, message) in [
+            (&[][..], "empty file"),
+            (&valid[..valid.len() - 1], "truncated"),
+            (&other_type[..], "only gas and sink particles"),
+            (
+                &short_block[..],
+                "U block is 8 bytes, which does not match 3 particles",
+            ),
+        ] {
+            let error = parse(&config(), bytes, None).err().unwrap();
+            assert!(
+                error.contains(message),
+                "{:?} instead of {:?}",
+                error,
+                message
+            );
+        }
+        assert!(parse(&config(), &valid, Some("h5")).is_err());
+    }
+}
@@ -0,0 +1,732 @@
+use crate::config::{Integrator, SimulationConfig};
+use crate::timestep::{self, Criterion};
+use crate::vector::{Float, Vector3};
+
+// The evolved quantities of every particle, and their masses and radiation energies, which the
+// integrators leave unchanged
+#[derive(Clone)]
+pub struct State {
+    pub positions: Vec<Vector3>,
+    pub velocities: Vec<Vector3>,
+    pub masses: Vec<Float>,
+    pub thermal_energies: Vec<Float>,
+    pub viscosity_alphas: Vec<Float>,
+    pub radiation_energies: Vec<Float>,
+    pub magnetic_fields: Vec<Vector3>,
+    // The divergence cleaning field psi / c_h, in the units of the magnetic field
+    pub cleaning_fields: Vec<Float>,
+}
+
+// The time derivatives of a `State`, along with the SPH quantities they were computed from
+pub struct Derivatives {
+    // Added to the velocities to get the time derivative of the positions (XSPH)
+    pub velocity_corrections: Vec<Vector3>,
+    pub accelerations: Vec<Vector3>,
+    pub thermal_energies: Vec<Float>,
+    pub viscosity_alphas: Vec<Float>,
+    pub magnetic_fields: Vec<Vector3>,
+    pub cleaning_fields: Vec<Float>,
+    pub smoothing_lengths: Vec<Float>,
+    pub densities: Vec<Float>,
+    // The most iterations any particle took to solve for its smoothing length, and the number of
+    // particles for which that did not converge
+    pub smoothing_iterations: usize,
+    pub unconverged_smoothing: usize,
+    // The fast magnetosonic speeds with `magnetic_fields`
+    pub sound_speeds: Vec<Float>,
+    pub velocity_divergences: Vec<Float>,
+    // The particles whose smoothing lengths, densities, sound speeds and velocity divergences
+    // were evaluated
+    pub evaluated: Vec<bool>,
+}
+
+impl State {
+    // Removes every particle that is not to be kept
+    pub fn retain(&mut self, keep: &[bool]) {
+        retain(&mut self.positions, keep);
+        retain(&mut self.velocities, keep);
+        retain(&mut self.masses, keep);
+        retain(&mut self.thermal_energies, keep);
+        retain(&mut self.viscosity_alphas, keep);
+        retain(&mut self.radiation_energies, keep);
+        retain(&mut self.magnetic_fields, keep);
+        retain(&mut self.cleaning_fields, keep);
+    }
+
+    // Appends a sink particle, which has no thermal, radiation or magnetic energy
+    pub fn push_sink(
+        &mut self,
+        config: &SimulationConfig,
+        position: Vector3,
+        velocity: Vector3,
+        mass: Float,
+    ) {
+        self.positions.push(position);
+        self.velocities.push(velocity);
+        self.masses.push(mass);
+        self.thermal_energies.push(0.0);
+        self.viscosity_alphas.push(config.viscosity_alpha);
+        self.radiation_energies.push(0.0);
+        self.magnetic_fields.push(Vector3::zero());
+        self.cleaning_fields.push(0.0);
+    }
+
+    // Moves the particles with the given velocities and the corrections of `derivatives`
+    fn drift(&mut self, velocities: &[Vector3], derivatives: &Derivatives, dt: Float) {
+        for (i, &velocity) in velocities.iter().enumerate() {
+            self.positions[i] += (velocity + derivatives.velocity_corrections[i]) * dt;
+        }
+    }
+
+    // Advances everything but the positions
+    fn kick(&mut self, config: &SimulationConfig, derivatives: &Derivatives, dt: Float) {
+        for i in 0..self.positions.len() {
+            self.kick_particle(config, derivatives, i, dt);
+        }
+    }
+
+    fn kick_particle(
+        &mut self,
+        config: &SimulationConfig,
+        derivatives: &Derivatives,
+        i: usize,
+        dt: Float,
+    ) {
+        self.velocities[i] += derivatives.accelerations[i] * dt;
+        self.thermal_energies[i] =
+            (self.thermal_energies[i] + derivatives.thermal_energies[i] * dt).max(0.0);
+        self.viscosity_alphas[i] = (self.viscosity_alphas[i]
+            + derivatives.viscosity_alphas[i] * dt)
+            .max(config.viscosity_alpha_min)
+            .min(config.viscosity_alpha);
+        self.magnetic_fields[i] += derivatives.magnetic_fields[i] * dt;
+        self.cleaning_fields[i] += derivatives.cleaning_fields[i] * dt;
+    }
+}
+
+// Removes every value that is not to be kept
+pub fn retain<T>(values: &mut Vec<T>, keep: &[bool]) {
+    let mut index = 0;
+    values.retain(|_| {
+        index += 1;
+        keep[index - 1]
+    });
+}
+
+// Advances `state` by `dt`, given the `initial` derivatives of `state`. `evaluate`
+// computes the derivatives of any state. Returns the derivatives of the new state if the scheme
+// already computed them, so that they can be reused as the initial derivatives of the next step
+pub fn step(
+    config: &SimulationConfig,
+    dt: Float,
+    state: &mut State,
+    initial: &Derivatives,
+    evaluate: impl FnMut(&State) -> Derivatives,
+) -> Option<Derivatives> {
+    match config.integrator {
+        Integrator::Euler => {
+            euler(config, dt, state, initial);
+            None
+        }
+        Integrator::Leapfrog => Some(leapfrog(config, dt, state, initial, evaluate)),
+        Integrator::RungeKutta4 => {
+            runge_kutta_4(config, dt, state, initial, evaluate);
+            None
+        }
+    }
+}
+
+// First order, with the acceleration also applied to the positions
+fn euler(config: &SimulationConfig, dt: Float, state: &mut State, initial: &Derivatives) {
+    let velocities = state.velocities.clone();
+    state.drift(&velocities, initial, dt);
+    for i in 0..state.positions.len() {
+        state.positions[i] += initial.accelerations[i] * dt * dt / 2.0;
+    }
+    state.kick(config, initial, dt);
+}
+
+// Second order and symplectic kick-drift-kick leapfrog, with one evaluation per step. The
+// velocity dependent forces at the end of the step are evaluated with everything but the
+// positions predicted from the first half kick
+fn leapfrog(
+    config: &SimulationConfig,
+    dt: Float,
+    state: &mut State,
+    initial: &Derivatives,
+    mut evaluate: impl FnMut(&State) -> Derivatives,
+) -> Derivatives {
+    state.kick(config, initial, dt / 2.0);
+    let velocities = state.velocities.clone();
+    state.drift(&velocities, initial, dt);
+
+    let mut predicted = state.clone();
+    predicted.kick(config, initial, dt / 2.0);
+    let end = evaluate(&predicted);
+    state.kick(config, &end, dt / 2.0);
+    end
+}
+
+// Classical fourth order Runge-Kutta, with four evaluations per step
+fn runge_kutta_4(
+    config: &SimulationConfig,
+    dt: Float,
+    state: &mut State,
+    initial: &Derivatives,
+    mut evaluate: impl FnMut(&State) -> Derivatives,
+) {
+    // The state after `dt` at the rates of `derivatives` evaluated at `from`
+    let advanced = |from: &State, derivatives: &Derivatives, dt: Float| {
+        let mut next = state.clone();
+        next.drift(&from.velocities, derivatives, dt);
+        next.kick(config, derivatives, dt);
+        next
+    };
+    let second_state = advanced(state, initial, dt / 2.0);
+    let second = evaluate(&second_state);
+    let third_state = advanced(&second_state, &second, dt / 2.0);
+    let third = evaluate(&third_state);
+    let fourth_state = advanced(&third_state, &third, dt);
+    let fourth = evaluate(&fourth_state);
+
+    let stages = [
+        (&*state, initial, dt / 6.0),
+        (&second_state, &second, dt / 3.0),
+        (&third_state, &third, dt / 3.0),
+        (&fourth_state, &fourth, dt / 6.0),
+    ];
+    let mut next = state.clone();
+    for &(from, derivatives, weight) in stages.iter() {
+        for i in 0..next.positions.len() {
+            next.positions[i] +=
+                (from.velocities[i] + derivatives.velocity_corrections[i]) * weight;
+            next.velocities[i] += derivatives.accelerations[i] * weight;
+            next.thermal_energies[i] += derivatives.thermal_energies[i] * weight;
+            next.viscosity_alphas[i] += derivatives.viscosity_alphas[i] * weight;
+            next.magnetic_fields[i] += derivatives.magnetic_fields[i] * weight;
+            next.cleaning_fields[i] += derivatives.cleaning_fields[i] * weight;
+        }
+    }
+    for i in 0..next.positions.len() {
+        next.thermal_energies[i] = next.thermal_energies[i].max(0.0);
+        next.viscosity_alphas[i] = next.viscosity_alphas[i]
+            .max(config.viscosity_alpha_min)
+            .min(config.viscosity_alpha);
+    }
+    *state = next;
+}
+
+// Hierarchical block timesteps, where every particle advances with its own kick-drift-kick
+// leapfrog step of `config.delta_t / 2^bin`. All particles are drifted together, but only those
+// at the end of their step are evaluated, with everything but the positions of the others
+// predicted from their last evaluation
+pub struct BlockSteps {
+    // In units of the smallest step, `config.delta_t / 2^config.timestep_bins`
+    time: u64,
+    bins: Vec<usize>,
+    // When the current step of every particle started
+    starts: Vec<u64>,
+    // The derivatives of every particle from the start of its current step
+    derivatives: Derivatives,
+    // The length of the step every particle finished at the current time, or zero
+    finished: Vec<Float>,
+}
+
+impl BlockSteps {
+    // Evaluates every particle and starts its first step, with everything but its position
+    // kicked to the middle of the step
+    pub fn new(
+        config: &SimulationConfig,
+        state: &mut State,
+        mut evaluate: impl FnMut(&State, &[bool]) -> Derivatives,
+    ) -> Self {
+        let count = state.positions.len();
+        let mut blocks = BlockSteps {
+            time: 0,
+            bins: vec![0; count],
+            starts: vec![0; count],
+            derivatives: evaluate(state, &vec![true; count]),
+            finished: vec![0.0; count],
+        };
+        let timesteps = timestep::particle_timesteps(config, state, &blocks.derivatives);
+        for (i, &(dt, _)) in timesteps.iter().enumerate() {
+            blocks.bins[i] = blocks.bin(config, dt);
+            let half = blocks.duration(config, blocks.bins[i]) / 2.0;
+            state.kick_particle(config, &blocks.derivatives, i, half);
+        }
+        blocks
+    }
+
+    // The derivatives from the last evaluation of every particle, and the smoothing lengths and
+    // densities of the last evaluation of any particle
+    pub fn derivatives(&self) -> &Derivatives {
+        &self.derivatives
+    }
+
+    // Advances to the end of the next step of any particle. Returns the time advanced, and the
+    // criterion limiting the shortest next step of the evaluated particles
+    pub fn step(
+        &mut self,
+        config: &SimulationConfig,
+        state: &mut State,
+        mut evaluate: impl FnMut(&State, &[bool]) -> Derivatives,
+    ) -> (Float, Criterion) {
+        let count = state.positions.len();
+        let end = (0..count)
+            .map(|i| self.starts[i] + self.ticks(config, self.bins[i]))
+            .min()
+            .unwrap();
+        let delta_t = (end - self.time) as Float * self.unit(config);
+        let velocities = state.velocities.clone();
+        state.drift(&velocities, &self.derivatives, delta_t);
+        self.time = end;
+
+        let active: Vec<bool> = (0..count)
+            .map(|i| self.starts[i] + self.ticks(config, self.bins[i]) == end)
+            .collect();
+        let derivatives = evaluate(&self.predict(config, state), &active);
+
+        // Finish the step of the active particles and start their next
+        let mut limit = (Float::INFINITY, Criterion::Maximum);
+        self.finished = (0..count)
+            .map(|i| {
+                if active[i] {
+                    self.duration(config, self.bins[i])
+                } else {
+                    0.0
+                }
+            })
+            .collect();
+        for i in (0..count).filter(|&i| active[i]) {
+            let half = self.finished[i] / 2.0;
+            state.kick_particle(config, &derivatives, i, half);
+        }
+        let timesteps = timestep::particle_timesteps(config, state, &derivatives);
+        for i in (0..count).filter(|&i| active[i]) {
+            if timesteps[i].0 < limit.0 {
+                limit = timesteps[i];
+            }
+            self.bins[i] = self.bin(config, timesteps[i].0);
+            self.starts[i] = self.time;
+            let half = self.duration(config, self.bins[i]) / 2.0;
+            state.kick_particle(config, &derivatives, i, half);
+        }
+        self.merge(derivatives, &active);
+        (delta_t, limit.1)
+    }
+
+    // The length of the step every particle finished at the current time, and zero for the
+    // particles in the middle of a step
+    pub fn finished(&self) -> &[Float] {
+        &self.finished
+    }
+
+    // `state` with everything but the positions, which is half a step ahead of the last
+    // evaluation of every particle, predicted to the current time
+    pub fn predict(&self, config: &SimulationConfig, state: &State) -> State {
+        let mut predicted = state.clone();
+        for i in 0..state.positions.len() {
+            let elapsed = (self.time - self.starts[i]) as Float * self.unit(config);
+            let ahead = self.duration(config, self.bins[i]) / 2.0;
+            predicted.kick_particle(config, &self.derivatives, i, elapsed - ahead);
+        }
+        predicted
+    }
+
+    // The longest step that is at most `dt` and that can start now
+    fn bin(&self, config: &SimulationConfig, dt: Float) -> usize {
+        let mut bin = 0;
+        while bin < config.timestep_bins
+            && (self.duration(config, bin) > dt
+                || !self.time.is_multiple_of(self.ticks(config, bin)))
+        {
+            bin += 1;
+        }
+        bin
+    }
+
+    // The length of the smallest step
+    fn unit(&self, config: &SimulationConfig) -> Float {
+        config.delta_t / (1u64 << config.timestep_bins) as Float
+    }
+
+    fn ticks(&self, config: &SimulationConfig, bin: usize) -> u64 {
+        1 << (config.timestep_bins - bin)
+    }
+
+    fn duration(&self, config: &SimulationConfig, bin: usize) -> Float {
+        self.ticks(config, bin) as Float * self.unit(config)
+    }
+
+    // Takes the rates of the `active` particles from `new`, and the smoothing lengths, densities,
+    // sound speeds and velocity divergences of the particles it evaluated
+    fn merge(&mut self, new: Derivatives, active: &[bool]) {
+        let old = &mut self.derivatives;
+        for i in (0..active.len()).filter(|&i| active[i]) {
+            old.velocity_corrections[i] = new.velocity_corrections[i];
+            old.accelerations[i] = new.accelerations[i];
+            old.thermal_energies[i] = new.thermal_energies[i];
+            old.viscosity_alphas[i] = new.viscosity_alphas[i];
+            old.magnetic_fields[i] = new.magnetic_fields[i];
+            old.cleaning_fields[i] = new.cleaning_fields[i];
+        }
+        for i in (0..active.len()).filter(|&i| new.evaluated[i]) {
+            old.smoothing_lengths[i] = new.smoothing_lengths[i];
+            old.densities[i] = new.densities[i];
+            old.sound_speeds[i] = new.sound_speeds[i];
+            old.velocity_divergences[i] = new.velocity_divergences[i];
+        }
+        old.smoothing_iterations = new.smoothing_iterations;
+        old.unconverged_smoothing = new.unconverged_smoothing;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Softening;
+    use crate::constants::{AU, GRAVITATIONAL_CONSTANT, PI, SOLAR_MASS};
+    use crate::gravity;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn state(
+        config: &SimulationConfig,
+        positions: Vec<Vector3>,
+        velocities: Vec<Vector3>,
+        masses: Vec<Float>,
+    ) -> State {
+        let count = positions.len();
+        State {
+            positions,
+            velocities,
+            masses,
+            thermal_energies: vec![0.0; count],
+            viscosity_alphas: vec![config.viscosity_alpha; count],
+            radiation_energies: vec![0.0; count],
+            magnetic_fields: vec![Vector3::zero(); count],
+            cleaning_fields: vec![0.0; count],
+        }
+    }
+
+    // Derivatives with the given accelerations, for particles of smoothing length `smooth`
+    fn derivatives(accelerations: Vec<Vector3>, smooth: Float) -> Derivatives {
+        let count = accelerations.len();
+        Derivatives {
+            velocity_corrections: vec![Vector3::zero(); count],
+            accelerations,
+            thermal_energies: vec![0.0; count],
+            viscosity_alphas: vec![0.0; count],
+            magnetic_fields: vec![Vector3::zero(); count],
+            cleaning_fields: vec![0.0; count],
+            smoothing_lengths: vec![smooth; count],
+            densities: vec![0.0; count],
+            smoothing_iterations: 0,
+            unconverged_smoothing: 0,
+            sound_speeds: vec![0.0; count],
+            velocity_divergences: vec![0.0; count],
+            evaluated: vec![true; count],
+        }
+    }
+
+    fn gravity(config: &SimulationConfig, state: &State) -> Derivatives {
+        let softening = vec![config.softening_length; state.positions.len()];
+        let accelerations =
+            gravity::direct_accelerations(config, &state.positions, &state.masses, &softening);
+        derivatives(accelerations, AU)
+    }
+
+    // A binary of a solar mass and half a solar mass with a semi-major axis of 10 AU and an
+    // eccentricity of 1/2, starting at apocenter around its center of mass at rest. Returns the
+    // state and the period
+    fn kepler(config: &SimulationConfig) -> (State, Float) {
+        let (masses, axis, eccentricity) = (vec![SOLAR_MASS, SOLAR_MASS / 2.0], 10.0 * AU, 0.5);
+        let total = masses[0] + masses[1];
+        let separation = axis * (1.0 + eccentricity);
+        let speed = (GRAVITATIONAL_CONSTANT * total * (1.0 - eccentricity) / separation).sqrt();
+        let (x, y) = (Vector3::unit_x() * separation, Vector3::unit_y() * speed);
+        let positions = vec![x * (-masses[1] / total), x * (masses[0] / total)];
+        let velocities = vec![y * (-masses[1] / total), y * (masses[0] / total)];
+        let period = 2.0 * PI * (axis.powi(3) / (GRAVITATIONAL_CONSTANT * total)).sqrt();
+        (state(config, positions, velocities, masses), period)
+    }
+
+    fn energy(state: &State) -> Float {
+        let mut energy = 0.0;
+        for (i, &mass) in state.masses.iter().enumerate() {
+            energy += mass * state.velocities[i].norm_squared() / 2.0;
+            for j in 0..i {
+                let dist = (state.positions[i] - state.positions[j]).norm();
+                energy -= GRAVITATIONAL_CONSTANT * mass * state.masses[j] / dist;
+            }
+        }
+        energy
+    }
+
+    fn angular_momentum(state: &State) -> Vector3 {
+        (0..state.positions.len())
+            .map(|i| state.positions[i].cross(state.velocities[i]) * state.masses[i])
+            .sum()
+    }
+
+    // Integrates `state` for `steps` steps of `dt`, and calls `observe` after every step
+    fn run(
+        config: &SimulationConfig,
+        state: &mut State,
+        dt: Float,
+        steps: usize,
+        mut observe: impl FnMut(&State),
+    ) {
+        let mut initial = gravity(config, state);
+        for _ in 0..steps {
+            initial = match step(config, dt, state, &initial, |s| gravity(config, s)) {
+                Some(end) => end,
+                None => gravity(config, state),
+            };
+            observe(state);
+        }
+    }
+
+    // The largest relative change of the energy of a Kepler orbit integrated for 50 periods in
+    // the first and last period
+    fn energy_errors(integrator: Integrator) -> (Float, Float) {
+        let config = SimulationConfig {
+            integrator,
+            ..SimulationConfig::default()
+        };
+        let (mut state, period) = kepler(&config);
+        let initial = energy(&state);
+        let (periods, per_period) = (50, 200);
+        let mut errors = Vec::new();
+        run(
+            &config,
+            &mut state,
+            period / per_period as Float,
+            periods * per_period,
+            |s| errors.push(((energy(s) - initial) / initial).abs()),
+        );
+        let max = |errors: &[Float]| errors.iter().cloned().fold(0.0, Float::max);
+        (
+            max(&errors[..per_period]),
+            max(&errors[errors.len() - per_period..]),
+        )
+    }
+
+    #[test]
+    fn leapfrog_energy_error_is_bounded() {
+        let (first, last) = energy_errors(Integrator::Leapfrog);
+        assert!(last < 1e-2, "{}", last);
+        assert!(
+            last < 1.1 * first,
+            "{} in the first period, {} in the last",
+            first,
+            last
+        );
+        let (_, euler) = energy_errors(Integrator::Euler);
+        assert!(
+            euler > 10.0 * last,
+            "{} with euler, {} with leapfrog",
+            euler,
+            last
+        );
+    }
+
+    #[test]
+    fn leapfrog_conserves_angular_momentum() {
+        let config = SimulationConfig {
+            softening: Softening::Plummer,
+            softening_length: AU,
+            ..SimulationConfig::default()
+        };
+        // A small rotating cloud
+        let mut rng = StdRng::seed_from_u64(5);
+        let count = 10;
+        let positions: Vec<Vector3> = (0..count)
+            .map(|_| {
+                (0..3)
+                    .map(|_| rng.gen_range(-10.0 * AU, 10.0 * AU))
+                    .collect()
+            })
+            .collect();
+        let velocities = positions
+            .iter()
+            .map(|&pos| Vector3::unit_z().cross(pos) * 1e-9)
+            .collect();
+        let masses = (0..count)
+            .map(|_| rng.gen_range(0.1, 1.0) * SOLAR_MASS)
+            .collect();
+        let mut state = state(&config, positions, velocities, masses);
+        let initial = angular_momentum(&state);
+        let mut error: Float = 0.0;
+        run(&config, &mut state, 1e6, 2000, |s| {
+            error = error.max((angular_momentum(s) - initial).norm() / initial.norm())
+        });
+        assert!(error < 1e-12, "{}", error);
+        assert!(state.positions.iter().all(|pos| pos.is_finite()));
+    }
+
+    // Steps of at most 16 in units of 1, with the acceleration criterion sqrt(h / |a|)
+    fn block_config() -> SimulationConfig {
+        SimulationConfig {
+            delta_t: 16.0,
+            timestep_bins: 4,
+            block_timesteps: true,
+            acceleration_factor: 1.0,
+            ..SimulationConfig::default()
+        }
+    }
+
+    #[test]
+    fn bins_are_the_longest_steps_that_fit_and_can_start_now() {
+        let config = block_config();
+        let at = |time: u64| BlockSteps {
+            time,
+            bins: Vec::new(),
+            starts: Vec::new(),
+            derivatives: derivatives(Vec::new(), 1.0),
+            finished: Vec::new(),
+        };
+        let bins = |time: u64| -> Vec<usize> {
+            let blocks = at(time);
+            [20.0, 16.0, 10.0, 8.0, 3.0, 1.0, 0.5]
+                .iter()
+                .map(|&dt| blocks.bin(&config, dt))
+                .collect()
+        };
+        // Down to the smallest step, even when it is longer than `dt`
+        assert_eq!(bins(0), vec![0, 0, 1, 1, 3, 4, 4]);
+        assert_eq!(bins(32), bins(0));
+        // Only steps that divide the current time
+        assert_eq!(bins(8), vec![1, 1, 1, 1, 3, 4, 4]);
+        assert_eq!(bins(4), vec![2, 2, 2, 2, 3, 4, 4]);
+        assert_eq!(bins(6), vec![3, 3, 3, 3, 3, 4, 4]);
+        assert_eq!(bins(3), vec![4; 7]);
+    }
+
+    #[test]
+    fn steps_change_only_when_they_end_and_start_in_sync() {
+        // The steps of the eccentric binary shorten towards pericenter and lengthen after it
+        let (mut state, period) = kepler(&SimulationConfig::default());
+        let config = SimulationConfig {
+            delta_t: period / 8.0,
+            timestep_bins: 6,
+            block_timesteps: true,
+            ..SimulationConfig::default()
+        };
+        let evaluate = |s: &State, _: &[bool]| gravity(&config, s);
+        let mut blocks = BlockSteps::new(&config, &mut state, evaluate);
+        let (mut shortened, mut lengthened) = (false, false);
+        let end = 8 * blocks.ticks(&config, 0);
+        while blocks.time < end {
+            let bins = blocks.bins.clone();
+            blocks.step(&config, &mut state, evaluate);
+            for (i, &previous) in bins.iter().enumerate() {
+                let bin = blocks.bins[i];
+                assert_eq!(blocks.starts[i] % blocks.ticks(&config, bin), 0);
+                if blocks.finished()[i] == 0.0 {
+                    assert_eq!(bin, previous);
+                } else {
+                    assert_eq!(blocks.starts[i], blocks.time);
+                }
+                shortened |= bin > previous;
+                lengthened |= bin < previous;
+            }
+        }
+        assert!(shortened && lengthened);
+        assert_ne!(blocks.bins[0], blocks.bins[1]);
+    }
+
+    #[test]
+    fn inactive_particles_are_predicted_and_keep_their_rates() {
+        let config = block_config();
+        // Constant accelerations with steps of 2 and 16, along which leapfrog is exact
+        let accelerations = vec![Vector3::unit_x() * 0.2, Vector3::unit_y() * (1.0 / 300.0)];
+        let velocity = Vector3::unit_z();
+        let mut state = state(
+            &config,
+            vec![Vector3::zero(); 2],
+            vec![velocity; 2],
+            vec![1.0; 2],
+        );
+        let exact = |time: Float, i: usize| {
+            (
+                velocity * time + accelerations[i] * (time * time / 2.0),
+                velocity + accelerations[i] * time,
+            )
+        };
+        let close = |a: Vector3, b: Vector3| (a - b).norm() < 1e-12 * (1.0 + b.norm());
+
+        let time = std::cell::Cell::new(0.0);
+        // Only the active particles are evaluated, with the other rates and smoothing lengths
+        // left invalid
+        let evaluate = |s: &State, active: &[bool]| {
+            for i in 0..2 {
+                assert!(close(s.velocities[i], exact(time.get(), i).1));
+            }
+            let mut new = derivatives(
+                (0..2)
+                    .map(|i| {
+                        if active[i] {
+                            accelerations[i]
+                        } else {
+                            Vector3::zero()
+                        }
+                    })
+                    .collect(),
+                1.0,
+            );
+            for i in (0..2).filter(|&i| !active[i]) {
+                new.smoothing_lengths[i] = Float::NAN;
+                new.evaluated[i] = false;
+            }
+            new
+        };
+        let mut blocks = BlockSteps::new(&config, &mut state, evaluate);
+        assert_eq!(blocks.bins, vec![3, 0]);
+        for step in 1..=16 {
+            time.set(2.0 * step as Float);
+            let (delta_t, _) = blocks.step(&config, &mut state, evaluate);
+            assert_eq!(delta_t, 2.0);
+            let expected = if step % 8 == 0 { 16.0 } else { 0.0 };
+            assert_eq!(blocks.finished(), &[2.0, expected][..]);
+            assert_eq!(blocks.derivatives().accelerations, accelerations);
+            assert_eq!(blocks.derivatives().smoothing_lengths, vec![1.0; 2]);
+            let predicted = blocks.predict(&config, &state);
+            for i in 0..2 {
+                let (position, velocity) = exact(time.get(), i);
+                // Drifted with the velocity of the middle of the step, and so exact at its end
+                if blocks.finished()[i] > 0.0 {
+                    assert!(close(state.positions[i], position));
+                }
+                assert!(close(predicted.velocities[i], velocity));
+            }
+        }
+    }
+
+    #[test]
+    fn a_single_bin_is_the_global_leapfrog() {
+        let (state, period) = kepler(&SimulationConfig::default());
+        let config = SimulationConfig {
+            delta_t: period / 100.0,
+            timestep_bins: 0,
+            block_timesteps: true,
+            ..SimulationConfig::default()
+        };
+        let mut global = state.clone();
+        let mut blocked = state;
+        let steps = 300;
+        run(&config, &mut global, config.delta_t, steps, |_| {});
+        let evaluate = |s: &State, _: &[bool]| gravity(&config, s);
+        let mut blocks = BlockSteps::new(&config, &mut blocked, evaluate);
+        for _ in 0..steps {
+            assert_eq!(
+                blocks.step(&config, &mut blocked, evaluate).0,
+                config.delta_t
+            );
+        }
+        assert_eq!(blocked.positions, global.positions);
+        // The block stepped velocities are half a step ahead
+        let predicted = blocks.predict(&config, &blocked);
+        assert_eq!(predicted.velocities, global.velocities);
+    }
+}
@@ -0,0 +1,294 @@
+use crate::config::{KernelType, SimulationConfig};
+use crate::constants::PI;
+use crate::vector::{Float, Vector3};
+
+// A normalized 3D smoothing kernel W(r, h) = shape(r / h) / h^3
+pub trait Kernel {
+    // The radius beyond which the kernel vanishes, in units of the smoothing length
+    fn support(&self) -> Float;
+    // The kernel for h = 1, including the normalization
+    fn shape(&self, q: Float) -> Float;
+    // The derivative of `shape` with respect to q
+    fn shape_derivative(&self, q: Float) -> Float;
+
+    fn value(&self, dist: Float, smooth: Float) -> Float {
+        let q = dist / smooth;
+        if q > self.support() {
+            0.0
+        } else {
+            self.shape(q) / smooth.powi(3)
+        }
+    }
+
+    // The gradient with respect to the first particle, given `offset = first - second`
+    fn gradient(&self, offset: Vector3, smooth: Float) -> Vector3 {
+        let dist = offset.norm();
+        let q = dist / smooth;
+        if q > self.support() || dist == 0.0 {
+            Vector3::zero()
+        } else {
+            offset / dist * self.shape_derivative(q) / smooth.powi(4)
+        }
+    }
+
+    // The derivative of `value` with respect to the smoothing length
+    fn smoothing_derivative(&self, dist: Float, smooth: Float) -> Float {
+        let q = dist / smooth;
+        if q > self.support() {
+            0.0
+        } else {
+            -(3.0 * self.shape(q) + q * self.shape_derivative(q)) / smooth.powi(4)
+        }
+    }
+}
+
+// Calls `f` with the configured kernel
+pub fn with_kernel<R>(config: &SimulationConfig, f: impl FnOnce(&dyn Kernel) -> R) -> R {
+    match config.kernel {
+        KernelType::Gaussian => f(&Gaussian::new(config.smoothing_dist_factor)),
+        KernelType::CubicSpline => f(&CubicSpline),
+        KernelType::QuinticSpline => f(&QuinticSpline),
+        KernelType::WendlandC2 => f(&WendlandC2),
+        KernelType::WendlandC4 => f(&WendlandC4),
+        KernelType::WendlandC6 => f(&WendlandC6),
+    }
+}
+
+// exp(-q^2), truncated at `support` and renormalized
+pub struct Gaussian {
+    support: Float,
+    normalization: Float,
+}
+
+impl Gaussian {
+    pub fn new(support: Float) -> Self {
+        let normalization =
+            (PI.powf(1.5) * erf(support) - 2.0 * PI * support * (-support * support).exp()).recip();
+        Gaussian {
+            support,
+            normalization,
+        }
+    }
+}
+
+impl Kernel for Gaussian {
+    fn support(&self) -> Float {
+        self.support
+    }
+    fn shape(&self, q: Float) -> Float {
+        self.normalization * (-q * q).exp()
+    }
+    fn shape_derivative(&self, q: Float) -> Float {
+        -2.0 * q * self.normalization * (-q * q).exp()
+    }
+}
+
+// The M4 cubic spline
+pub struct CubicSpline;
+
+impl Kernel for CubicSpline {
+    fn support(&self) -> Float {
+        2.0
+    }
+    fn shape(&self, q: Float) -> Float {
+        if q < 1.0 {
+            (1.0 - 1.5 * q * q + 0.75 * q.powi(3)) / PI
+        } else if q < 2.0 {
+            0.25 * (2.0 - q).powi(3) / PI
+        } else {
+            0.0
+        }
+    }
+    fn shape_derivative(&self, q: Float) -> Float {
+        if q < 1.0 {
+            (-3.0 * q + 2.25 * q * q) / PI
+        } else if q < 2.0 {
+            -0.75 * (2.0 - q).powi(2) / PI
+        } else {
+            0.0
+        }
+    }
+}
+
+// The M6 quintic spline
+pub struct QuinticSpline;
+
+impl Kernel for QuinticSpline {
+    fn support(&self) -> Float {
+        3.0
+    }
+    fn shape(&self, q: Float) -> Float {
+        [(3.0, 1.0), (2.0, -6.0), (1.0, 15.0)]
+            .iter()
+            .map(|&(edge, weight)| weight * (edge - q).max(0.0).powi(5))
+            .sum::<Float>()
+            / (120.0 * PI)
+    }
+    fn shape_derivative(&self, q: Float) -> Float {
+        [(3.0, 1.0), (2.0, -6.0), (1.0, 15.0)]
+            .iter()
+            .map(|&(edge, weight)| -5.0 * weight * (edge - q).max(0.0).powi(4))
+            .sum::<Float>()
+            / (120.0 * PI)
+    }
+}
+
+// The Wendland kernels, with support 2h as for the cubic spline
+pub struct WendlandC2;
+pub struct WendlandC4;
+pub struct WendlandC6;
+
+impl Kernel for WendlandC2 {
+    fn support(&self) -> Float {
+        2.0
+    }
+    fn shape(&self, q: Float) -> Float {
+        let a = (1.0 - q / 2.0).max(0.0);
+        21.0 / (16.0 * PI) * a.powi(4) * (1.0 + 2.0 * q)
+    }
+    fn shape_derivative(&self, q: Float) -> Float {
+        let a = (1.0 - q / 2.0).max(0.0);
+        21.0 / (16.0 * PI) * -5.0 * q * a.powi(3)
+    }
+}
+
+impl Kernel for WendlandC4 {
+    fn support(&self) -> Float {
+        2.0
+    }
+    fn shape(&self, q: Float) -> Float {
+        let a = (1.0 - q / 2.0).max(0.0);
+        495.0 / (256.0 * PI) * a.powi(6) * (1.0 + 3.0 * q + 35.0 / 12.0 * q * q)
+    }
+    fn shape_derivative(&self, q: Float) -> Float {
+        let a = (1.0 - q / 2.0).max(0.0);
+        495.0 / (256.0 * PI) * -14.0 / 3.0 * q * a.powi(5) * (1.0 + 2.5 * q)
+    }
+}
+
+impl Kernel for WendlandC6 {
+    fn support(&self) -> Float {
+        2.0
+    }
+    fn shape(&self, q: Float) -> Float {
+        let a = (1.0 - q / 2.0).max(0.0);
+        1365.0 / (512.0 * PI) * a.powi(8) * (1.0 + 4.0 * q + 6.25 * q * q + 4.0 * q.powi(3))
+    }
+    fn shape_derivative(&self, q: Float) -> Float {
+        let a = (1.0 - q / 2.0).max(0.0);
+        1365.0 / (512.0 * PI) * -5.5 * q * a.powi(7) * (1.0 + 3.5 * q + 4.0 * q * q)
+    }
+}
+
+// Abramowitz and Stegun 7.1.26, with an absolute error below 1.5e-7
+fn erf(x: Float) -> Float {
+    let t = 1.0 / (1.0 + 0.327_591_1 * x.abs());
+    let poly = t
+        * (0.254_829_592
+            + t * (-0.284_496_736
+                + t * (1.421_413_741 + t * (-1.453_152_027 + t * 1.061_405_429))));
+    (1.0 - poly * (-x * x).exp()).copysign(x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kernels() -> Vec<(&'static str, Box<dyn Kernel>)> {
+        vec![
+            ("gaussian", Box::new(Gaussian::new(2.0))),
+            ("wide gaussian", Box::new(Gaussian::new(3.0))),
+            ("cubic spline", Box::new(CubicSpline)),
+            ("quintic spline", Box::new(QuinticSpline)),
+            ("wendland c2", Box::new(WendlandC2)),
+            ("wendland c4", Box::new(WendlandC4)),
+            ("wendland c6", Box::new(WendlandC6)),
+        ]
+    }
+
+    // Simpson's rule for the integral of 4 pi r^2 W(r, h) over the support
+    fn integrate(kernel: &dyn Kernel, smooth: Float) -> Float {
+        let steps = 10_000;
+        let width = kernel.support() * smooth / steps as Float;
+        (0..=steps)
+            .map(|i| {
+                let r = i as Float * width;
+                let weight = if i == 0 || i == steps {
+                    1.0
+                } else if i % 2 == 1 {
+                    4.0
+                } else {
+                    2.0
+                };
+                weight * 4.0 * PI * r * r * kernel.value(r, smooth)
+            })
+            .sum::<Float>()
+            * width
+            / 3.0
+    }
+
+    #[test]
+    fn kernels_integrate_to_one() {
+        for (name, kernel) in kernels() {
+            for &smooth in &[1.0, 0.01, 1e15] {
+                let integral = integrate(&*kernel, smooth);
+                assert!(
+                    (integral - 1.0).abs() < 1e-6,
+                    "{} integrates to {} with h = {}",
+                    name,
+                    integral,
+                    smooth
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn kernels_vanish_outside_support() {
+        for (name, kernel) in kernels() {
+            let support = kernel.support();
+            assert_eq!(kernel.value(support * 1.001, 1.0), 0.0, "{}", name);
+            assert_eq!(
+                kernel.gradient(Vector3::unit_x() * support * 1.001, 1.0),
+                Vector3::zero(),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn derivatives_match_finite_differences() {
+        for (name, kernel) in kernels() {
+            for i in 1..20 {
+                let q = kernel.support() * i as Float / 20.0;
+                let step = 1e-6;
+                let numeric = (kernel.shape(q + step) - kernel.shape(q - step)) / (2.0 * step);
+                assert!(
+                    (numeric - kernel.shape_derivative(q)).abs() < 1e-6,
+                    "{} at q = {}",
+                    name,
+                    q
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn smoothing_derivatives_match_finite_differences() {
+        for (name, kernel) in kernels() {
+            for i in 0..20 {
+                let dist = kernel.support() * i as Float / 20.0;
+                let step = 1e-6;
+                let numeric = (kernel.value(dist, 1.0 + step) - kernel.value(dist, 1.0 - step))
+                    / (2.0 * step);
+                assert!(
+                    (numeric - kernel.smoothing_derivative(dist, 1.0)).abs() < 1e-6,
+                    "{} at r = {}",
+                    name,
+                    dist
+                );
+            }
+        }
+    }
+}
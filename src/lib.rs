@@ -0,0 +1,29 @@
+#![allow(clippy::too_many_arguments)]
+
+// The simulation as a library, for custom drivers, parameter sweeps and tests. The viewer in
+// `main.rs` is built on this API
+pub mod config;
+pub mod constants;
+pub mod cooling;
+pub mod eos;
+pub mod export;
+mod fmm;
+mod gravity;
+pub mod import;
+pub mod integrator;
+pub mod kernel;
+mod neighbors;
+mod octree;
+pub mod opacity;
+pub mod particle;
+pub mod radiation;
+pub mod simulation;
+pub mod sink;
+pub mod snapshot;
+pub mod statistics;
+pub mod timestep;
+pub mod vector;
+
+pub use crate::config::SimulationConfig;
+pub use crate::simulation::Simulation;
+pub use crate::vector::{Float, Vector3};
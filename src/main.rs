@@ -1,27 +1,89 @@
+#![allow(clippy::too_many_arguments)]
+
 mod camera;
-mod constants;
-mod neighbors;
-mod particle;
-mod simulation;
-mod statistics;
-mod vector;
 
 use crate::camera::Camera;
-use crate::constants::{DELTA_T, HEIGHT, RADIUS, WIDTH, YEAR};
-use crate::simulation::Simulation;
-use crate::vector::{Float, Vector3};
+use binary_accretion::config::Cooling;
+use binary_accretion::constants::{AU, HEIGHT, SOLAR_MASS, WIDTH, YEAR};
+use binary_accretion::{export, statistics, Float, Simulation, SimulationConfig, Vector3};
 use minifb::{Key, Window, WindowOptions};
 
 use std::cmp;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
 use std::time::Instant;
 
 pub fn main() {
+    let (config, restart) = or_exit(SimulationConfig::from_args(std::env::args().skip(1)), 2);
+    let restarted = restart.is_some();
+    let simulation = or_exit(
+        match restart {
+            Some(path) => Simulation::load(&path, config),
+            None if !config.initial_conditions.is_empty() => Simulation::import(config),
+            None => Ok(Simulation::new(config)),
+        },
+        2,
+    );
+    if simulation.seed() != 0 {
+        println!("Seed {}", simulation.seed());
+    }
+    let output = or_exit(Output::new(&simulation, restarted), 2);
+    if simulation.config().headless {
+        run_headless(simulation, output);
+    } else {
+        run_window(simulation, output);
+    }
+}
+
+fn or_exit<T>(result: Result<T, String>, status: i32) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(status);
+    })
+}
+
+// Steps until `max_steps` or `end_time` is reached, then saves a final checkpoint. Exits with
+// status 1 if any output cannot be written
+fn run_headless(mut simulation: Simulation, mut output: Output) {
+    let mut last_time = Instant::now();
+    let mut seconds_per_tick = 1.0;
+    loop {
+        let config = simulation.config();
+        let finished = (config.max_steps > 0 && simulation.steps() >= config.max_steps)
+            || (config.end_time > 0.0 && simulation.time() >= config.end_time);
+        if finished {
+            break;
+        }
+        let densities = simulation.step();
+        or_exit(output.write(&simulation, &densities), 1);
+
+        let now = Instant::now();
+        seconds_per_tick =
+            0.9 * seconds_per_tick + 0.1 * now.duration_since(last_time).as_secs_f64();
+        last_time = now;
+        let interval = simulation.config().statistics_interval as u64;
+        if interval > 0 && simulation.steps().is_multiple_of(interval) {
+            or_exit(output.report(&simulation, &densities, seconds_per_tick), 1);
+        }
+    }
+    if simulation.config().checkpoint_interval > 0 {
+        or_exit(simulation.save(&output.checkpoint_file), 1);
+    }
+    println!(
+        "Finished after {} steps and {} years",
+        simulation.steps(),
+        (simulation.time() / YEAR) as usize,
+    );
+}
+
+fn run_window(mut simulation: Simulation, mut output: Output) {
+    let radius = simulation.config().radius;
     let mut buffer: Vec<u32> = vec![0; WIDTH * HEIGHT];
-    let mut simulation = Simulation::new();
     let mut camera = Camera::new(
         Vector3::zero(),
-        WIDTH as Float * 4.0 * RADIUS / cmp::min(WIDTH, HEIGHT) as Float,
-        HEIGHT as Float * 4.0 * RADIUS / cmp::min(WIDTH, HEIGHT) as Float,
+        WIDTH as Float * 4.0 * radius / cmp::min(WIDTH, HEIGHT) as Float,
+        HEIGHT as Float * 4.0 * radius / cmp::min(WIDTH, HEIGHT) as Float,
     );
 
     let mut window = Window::new(
@@ -40,13 +102,14 @@ pub fn main() {
     let mut seconds_per_tick = 1.0 / 30.0;
     let mut tick = 0;
 
-    println!("UPS Years    Move    Energy    Poten   Kinetic  Temp Pressure");
-
     while window.is_open() {
         // Simulation step and display
         let densities = simulation.step();
+        if let Err(e) = output.write(&simulation, &densities) {
+            eprintln!("{}", e);
+        }
         camera.take_input(
-            (1.0 / seconds_per_tick) as f64,
+            1.0 / seconds_per_tick,
             window.is_key_down(Key::A),
             window.is_key_down(Key::D),
             window.is_key_down(Key::W),
@@ -65,27 +128,307 @@ pub fn main() {
             0.9 * seconds_per_tick + 0.1 * now.duration_since(last_time).as_secs_f64();
         last_time = now;
 
-        if tick % 100 == 0 {
-            let movement = statistics::observe_movement(simulation.velocities());
-            let kinetic_energy = statistics::observe_kinetic_energy(simulation.velocities());
-            let thermal_energy = statistics::observe_thermal_energy(simulation.thermal_energies());
-            let potential_energy = statistics::observe_potential_energy(simulation.positions());
-            let temp = statistics::observe_average_temperature(simulation.thermal_energies());
-            let pressure =
-                statistics::observe_average_pressure(simulation.thermal_energies(), &*densities);
-
-            println!(
-                "{:2} {:7} {:8.1e} {:8.2e} {:8.2e} {:8.2e} {:5.2} {:8.1e}",
+        let interval = simulation.config().statistics_interval;
+        if interval > 0 && tick % interval == 0 {
+            if let Err(e) = output.report(&simulation, &densities, seconds_per_tick) {
+                eprintln!("{}", e);
+            }
+        }
+        tick += 1;
+    }
+}
+
+// Checkpoints, exports and statistics
+struct Output {
+    checkpoint_file: PathBuf,
+    export_interval: Float,
+    // The number of the next export
+    exports: usize,
+    // Standard output, or `statistics_file` appended to when restarting
+    statistics: Box<dyn Write>,
+    statistics_name: String,
+    // `sink_file`, if any
+    sinks: Option<Box<dyn Write>>,
+    // The sink masses and time of the last report, for the accretion rates
+    sink_masses: Vec<Float>,
+    sink_time: Float,
+}
+
+impl Output {
+    fn new(simulation: &Simulation, restarted: bool) -> Result<Self, String> {
+        let config = simulation.config();
+        let export_interval = config.export_interval;
+        let exports = if export_interval > 0.0 {
+            (simulation.time() / export_interval).ceil() as usize
+        } else {
+            0
+        };
+        let (statistics, statistics_name, header): (Box<dyn Write>, String, bool) =
+            if config.statistics_file.is_empty() {
+                (Box::new(io::stdout()), "standard output".to_owned(), true)
+            } else {
+                let (file, empty) = open(&config.statistics_file, restarted)?;
+                (file, config.statistics_file.clone(), empty)
+            };
+        let sinks = if config.sink_file.is_empty() {
+            None
+        } else {
+            let (mut file, empty) = open(&config.sink_file, restarted)?;
+            if empty {
+                writeln!(
+                    file,
+                    "# sink Years Index Mass (Msun) Rate (Msun/yr) Disc (Msun)\n\
+                     # binary Years Primary Secondary a (AU) e P (yr) q Disc (Msun)"
+                )
+                .map_err(|e| format!("Could not write {}: {}", config.sink_file, e))?;
+            }
+            Some(file)
+        };
+        let mut output = Output {
+            checkpoint_file: PathBuf::from(&config.checkpoint_file),
+            export_interval,
+            exports,
+            statistics,
+            statistics_name,
+            sinks,
+            sink_masses: simulation.masses()[config.count..].to_vec(),
+            sink_time: simulation.time(),
+        };
+        if header {
+            output.statistics(|out| {
+                writeln!(
+                    out,
+                    "UPS Years    Move    Energy    Poten   Kinetic  Temp Pressure dt (yr) Limit"
+                )
+            })?;
+        }
+        Ok(output)
+    }
+
+    // Exports and checkpoints that are due after a step
+    fn write(&mut self, simulation: &Simulation, densities: &[Float]) -> Result<(), String> {
+        if self.export_interval > 0.0
+            && simulation.time() >= self.exports as Float * self.export_interval
+        {
+            let path = export::export(simulation, densities, self.exports)?;
+            println!("Exported {}", path.display());
+            self.exports += 1;
+        }
+        let interval = simulation.config().checkpoint_interval as u64;
+        if interval > 0 && simulation.steps().is_multiple_of(interval) {
+            simulation.save(&self.checkpoint_file)?;
+        }
+        Ok(())
+    }
+
+    fn report(
+        &mut self,
+        simulation: &Simulation,
+        densities: &[Float],
+        seconds_per_tick: Float,
+    ) -> Result<(), String> {
+        let config = simulation.config();
+        let masses = simulation.masses();
+        let gas = config.count;
+        let movement = statistics::observe_movement(masses, simulation.velocities());
+        let kinetic_energy = statistics::observe_kinetic_energy(masses, simulation.velocities());
+        let thermal_energy = statistics::observe_thermal_energy(simulation.thermal_energies());
+        let radiation_energy =
+            statistics::observe_radiation_energy(simulation.radiation_energies());
+        let magnetic_energy = statistics::observe_magnetic_energy(
+            &masses[..gas],
+            &simulation.magnetic_fields()[..gas],
+            &densities[..gas],
+        );
+        let softening = simulation.softening_lengths();
+        let potential_energy = statistics::observe_potential_energy(
+            config,
+            simulation.positions(),
+            masses,
+            &softening,
+        );
+        let energies = simulation.thermal_energies();
+        let temp = statistics::observe_average_temperature(
+            config,
+            &masses[..gas],
+            &energies[..gas],
+            &densities[..gas],
+        );
+        let pressure = statistics::observe_average_pressure(
+            config,
+            &masses[..gas],
+            &energies[..gas],
+            &densities[..gas],
+        );
+        let luminosity = if config.cooling != Cooling::None || config.heating_rate > 0.0 {
+            Some(statistics::observe_luminosity(
+                config,
+                &masses[..gas],
+                &energies[..gas],
+                &densities[..gas],
+            ))
+        } else {
+            None
+        };
+        let (delta_t, criterion) = simulation.last_timestep();
+        let gravity_error = if config.report_gravity_error {
+            Some(statistics::observe_gravity_error(
+                config,
+                simulation.positions(),
+                masses,
+                &softening,
+            ))
+        } else {
+            None
+        };
+        let (positions, velocities) = (simulation.positions(), simulation.velocities());
+        let binaries = statistics::observe_binaries(positions, velocities, masses, gas);
+        let (circumstellar, circumbinary) = statistics::observe_disc_masses(
+            positions, velocities, masses, energies, gas, &binaries,
+        );
+        // The (mass, accretion rate, disc mass) of every sink, with the accretion rate in solar
+        // masses per year and sinks formed since the last report starting from zero
+        let elapsed = (simulation.time() - self.sink_time) / YEAR;
+        let sinks: Vec<(Float, Float, Float)> = masses[gas..]
+            .iter()
+            .enumerate()
+            .map(|(s, &mass)| {
+                let previous = self.sink_masses.get(s).copied().unwrap_or(0.0);
+                let rate = if elapsed > 0.0 {
+                    (mass - previous) / SOLAR_MASS / elapsed
+                } else {
+                    0.0
+                };
+                (mass / SOLAR_MASS, rate, circumstellar[s] / SOLAR_MASS)
+            })
+            .collect();
+        self.sink_masses = masses[gas..].to_vec();
+        self.sink_time = simulation.time();
+
+        self.statistics(|out| {
+            writeln!(
+                out,
+                "{:2} {:7} {:8.1e} {:8.2e} {:8.2e} {:8.2e} {:5.2} {:8.1e} {:7.1e} {}",
                 seconds_per_tick.powi(-1) as u32,
-                (tick as f64 * DELTA_T / YEAR) as usize,
+                (simulation.time() / YEAR) as usize,
                 movement.norm(),
-                potential_energy + kinetic_energy + thermal_energy,
+                potential_energy
+                    + kinetic_energy
+                    + thermal_energy
+                    + radiation_energy
+                    + magnetic_energy,
                 potential_energy,
                 kinetic_energy,
                 temp,
                 pressure,
-            );
+                delta_t / YEAR,
+                criterion,
+            )?;
+            if let Some((rms, max)) = gravity_error {
+                writeln!(
+                    out,
+                    "   {:?} gravity relative error: rms {:8.2e}, max {:8.2e}",
+                    config.gravity_solver, rms, max
+                )?;
+            }
+            let (iterations, unconverged) = simulation.smoothing_iterations();
+            writeln!(
+                out,
+                "   Smoothing lengths: at most {} iterations, {} did not converge",
+                iterations, unconverged
+            )?;
+            if config.radiation {
+                match simulation.radiation_iterations() {
+                    Some(iterations) => writeln!(
+                        out,
+                        "   Radiation energy {:8.2e} J, {} implicit iterations",
+                        radiation_energy, iterations
+                    )?,
+                    None => writeln!(
+                        out,
+                        "   Radiation energy {:8.2e} J, implicit iterations did not converge",
+                        radiation_energy
+                    )?,
+                }
+            }
+            if config.magnetic_fields {
+                writeln!(out, "   Magnetic energy {:8.2e} J", magnetic_energy)?;
+            }
+            if let Some(luminosity) = luminosity {
+                writeln!(out, "   Net cooling luminosity {:8.2e} W", luminosity)?;
+            }
+            for (s, (mass, rate, disc)) in sinks.iter().enumerate() {
+                writeln!(
+                    out,
+                    "   Sink {}: mass {:8.2e} Msun, accretion rate {:8.2e} Msun/yr, disc {:8.2e} Msun",
+                    s, mass, rate, disc
+                )?;
+            }
+            for (binary, disc) in binaries.iter().zip(circumbinary.iter()) {
+                writeln!(
+                    out,
+                    "   Binary {}-{}: a {:8.2e} AU, e {:4.2}, P {:8.2e} yr, q {:4.2}, disc {:8.2e} Msun",
+                    binary.primary - gas,
+                    binary.secondary - gas,
+                    binary.semi_major_axis / AU,
+                    binary.eccentricity,
+                    binary.period / YEAR,
+                    binary.mass_ratio,
+                    disc / SOLAR_MASS,
+                )?;
+            }
+            out.flush()
+        })?;
+
+        if let Some(file) = &mut self.sinks {
+            let years = simulation.time() / YEAR;
+            let mut write = || {
+                for (s, (mass, rate, disc)) in sinks.iter().enumerate() {
+                    writeln!(
+                        file,
+                        "sink {:.6e} {} {:.6e} {:.6e} {:.6e}",
+                        years, s, mass, rate, disc
+                    )?;
+                }
+                for (binary, disc) in binaries.iter().zip(circumbinary.iter()) {
+                    writeln!(
+                        file,
+                        "binary {:.6e} {} {} {:.6e} {:.6e} {:.6e} {:.6e} {:.6e}",
+                        years,
+                        binary.primary - gas,
+                        binary.secondary - gas,
+                        binary.semi_major_axis / AU,
+                        binary.eccentricity,
+                        binary.period / YEAR,
+                        binary.mass_ratio,
+                        disc / SOLAR_MASS,
+                    )?;
+                }
+                file.flush()
+            };
+            write().map_err(|e| format!("Could not write {}: {}", config.sink_file, e))?;
         }
-        tick += 1;
+        Ok(())
+    }
+
+    fn statistics(
+        &mut self,
+        write: impl FnOnce(&mut dyn Write) -> io::Result<()>,
+    ) -> Result<(), String> {
+        write(&mut self.statistics)
+            .map_err(|e| format!("Could not write {}: {}", self.statistics_name, e))
+    }
+}
+
+// `path` truncated, or appended to when restarting, and whether it is empty
+fn open(path: &str, restarted: bool) -> Result<(Box<dyn Write>, bool), String> {
+    let error = |e: io::Error| format!("Could not write {}: {}", path, e);
+    let file = if restarted {
+        OpenOptions::new().create(true).append(true).open(path)
+    } else {
+        File::create(path)
     }
+    .map_err(error)?;
+    let empty = file.metadata().map_err(error)?.len() == 0;
+    Ok((Box::new(io::BufWriter::new(file)), empty))
 }
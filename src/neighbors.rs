@@ -1,45 +1,400 @@
-use crate::constants::{COUNT, NEIGHBORS};
+use crate::config::{NeighborSearch, SimulationConfig};
 use crate::vector::{Float, Vector3};
 use ordered_float::NotNan;
 use rayon::prelude::*;
 use std::collections::BinaryHeap;
 
-pub fn nearest_neighbors(points: &[Vector3; COUNT]) -> Box<[[usize; NEIGHBORS]; COUNT]> {
-    assert!(NEIGHBORS < COUNT);
-    nearest_neighbors_quadratic(points)
+// Returns `config.neighbors` indices per point, flattened so that the neighbors of point `i` are
+// `result[i * config.neighbors..(i + 1) * config.neighbors]`
+pub fn nearest_neighbors(config: &SimulationConfig, points: &[Vector3]) -> Vec<usize> {
+    assert!(config.neighbors < points.len());
+    match config.neighbor_search {
+        NeighborSearch::Tree => nearest_neighbors_tree(config.neighbors, points),
+        NeighborSearch::Quadratic => nearest_neighbors_quadratic(config.neighbors, points),
+    }
+}
+
+// O(n log n + n k log n) time, O(n k) space
+fn nearest_neighbors_tree(k: usize, points: &[Vector3]) -> Vec<usize> {
+    let tree = KdTree::new(points);
+    let neighbors: Vec<usize> = (0..points.len())
+        .into_par_iter()
+        .flat_map(|i| {
+            let mut surrounding: BinaryHeap<(NotNan<Float>, usize)> = BinaryHeap::new();
+            tree.search(KdTree::ROOT, i, k, &mut surrounding);
+            assert_eq!(surrounding.len(), k);
+            surrounding
+                .into_iter()
+                .map(|(_dist, index)| index)
+                .collect::<Vec<usize>>()
+        })
+        .collect();
+    assert_eq!(neighbors.len(), points.len() * k);
+    neighbors
 }
 
 // O(n^2 + n k log k) time, O(n k) space
-fn nearest_neighbors_quadratic(points: &[Vector3; COUNT]) -> Box<[[usize; NEIGHBORS]; COUNT]> {
-    let neighbors: Vec<usize> = (0..COUNT)
+fn nearest_neighbors_quadratic(k: usize, points: &[Vector3]) -> Vec<usize> {
+    let count = points.len();
+    let neighbors: Vec<usize> = (0..count)
         .into_par_iter()
-        .map(|i| {
+        .flat_map(|i| {
             let mut surrounding: BinaryHeap<(NotNan<Float>, usize)> = BinaryHeap::new();
-            for j in 0..COUNT {
+            for j in 0..count {
                 if i == j {
                     continue;
                 }
                 let dist = (points[i] - points[j]).norm_squared();
-                if surrounding.len() < NEIGHBORS {
-                    surrounding.push((NotNan::new(dist).unwrap(), j));
-                } else if dist < *surrounding.peek().unwrap().0 {
-                    surrounding.pop();
-                    surrounding.push((NotNan::new(dist).unwrap(), j));
-                }
+                offer(&mut surrounding, k, dist, j);
             }
-            assert_eq!(surrounding.len(), NEIGHBORS);
-            let surrounding: Vec<usize> = surrounding
+            assert_eq!(surrounding.len(), k);
+            surrounding
                 .into_iter()
                 .map(|(_dist, index)| index)
-                .collect();
-            surrounding
+                .collect::<Vec<usize>>()
         })
-        .flatten()
         .collect();
-    assert_eq!(neighbors.len(), COUNT * NEIGHBORS);
-    unsafe {
-        Box::from_raw(
-            Box::into_raw(neighbors.into_boxed_slice()) as *mut [[usize; NEIGHBORS]; COUNT]
+    assert_eq!(neighbors.len(), count * k);
+    neighbors
+}
+
+// Variable length neighbor lists, stored back to back so that the neighbors of point `i` are
+// `indices[offsets[i]..offsets[i + 1]]`
+pub struct Neighbors {
+    offsets: Vec<usize>,
+    indices: Vec<usize>,
+}
+
+impl Neighbors {
+    fn from_lists(lists: Vec<Vec<usize>>) -> Self {
+        let mut offsets = Vec::with_capacity(lists.len() + 1);
+        offsets.push(0);
+        for list in &lists {
+            offsets.push(offsets[offsets.len() - 1] + list.len());
+        }
+        Neighbors {
+            offsets,
+            indices: lists.concat(),
+        }
+    }
+
+    // The number of points
+    pub fn points(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    pub fn of(&self, i: usize) -> &[usize] {
+        &self.indices[self.offsets[i]..self.offsets[i + 1]]
+    }
+
+    // Keeps the neighbors `j` of every point `i` for which `keep(i, j)`
+    pub fn filter(&self, keep: impl Fn(usize, usize) -> bool + Sync) -> Self {
+        Self::from_lists(
+            (0..self.points())
+                .into_par_iter()
+                .map(|i| self.of(i).iter().copied().filter(|&j| keep(i, j)).collect())
+                .collect(),
         )
     }
+
+    // Adds every point to the lists of its neighbors, so that `i` is a neighbor of `j` exactly when
+    // `j` is a neighbor of `i`. Each list ends up sorted. O(p log p) time for p pairs, O(p) space
+    pub fn symmetric(&self) -> Self {
+        let mut pairs: Vec<(usize, usize)> = (0..self.points())
+            .into_par_iter()
+            .flat_map(|i| {
+                self.of(i)
+                    .iter()
+                    .flat_map(|&j| vec![(i, j), (j, i)])
+                    .collect::<Vec<(usize, usize)>>()
+            })
+            .collect();
+        pairs.par_sort_unstable();
+        pairs.dedup();
+
+        let mut offsets = vec![0; self.points() + 1];
+        for &(i, _) in &pairs {
+            offsets[i + 1] += 1;
+        }
+        for i in 0..self.points() {
+            offsets[i + 1] += offsets[i];
+        }
+        Neighbors {
+            offsets,
+            indices: pairs.into_par_iter().map(|(_, j)| j).collect(),
+        }
+    }
+}
+
+// The points other than `i` within `radii[i]` of every `queried` point `i`, and none for the
+// other points
+pub fn neighbors_within(
+    config: &SimulationConfig,
+    points: &[Vector3],
+    radii: &[Float],
+    queried: &[bool],
+) -> Neighbors {
+    assert_eq!(points.len(), radii.len());
+    assert_eq!(points.len(), queried.len());
+    match config.neighbor_search {
+        NeighborSearch::Tree => neighbors_within_tree(points, radii, queried),
+        NeighborSearch::Quadratic => neighbors_within_quadratic(points, radii, queried),
+    }
+}
+
+// O(n log n + q m) time for q queried points with m neighbors each, O(q m) space
+fn neighbors_within_tree(points: &[Vector3], radii: &[Float], queried: &[bool]) -> Neighbors {
+    let tree = KdTree::new(points);
+    Neighbors::from_lists(
+        (0..points.len())
+            .into_par_iter()
+            .map(|i| {
+                let mut surrounding = Vec::new();
+                if queried[i] {
+                    tree.search_within(KdTree::ROOT, i, radii[i], &mut surrounding);
+                }
+                surrounding
+            })
+            .collect(),
+    )
+}
+
+// O(n q) time, O(q m) space
+fn neighbors_within_quadratic(points: &[Vector3], radii: &[Float], queried: &[bool]) -> Neighbors {
+    Neighbors::from_lists(
+        (0..points.len())
+            .into_par_iter()
+            .map(|i| {
+                if !queried[i] {
+                    return Vec::new();
+                }
+                (0..points.len())
+                    .filter(|&j| {
+                        j != i && (points[j] - points[i]).norm_squared() <= radii[i] * radii[i]
+                    })
+                    .collect()
+            })
+            .collect(),
+    )
+}
+
+// Keeps the k closest candidates in a max-heap on distance
+fn offer(surrounding: &mut BinaryHeap<(NotNan<Float>, usize)>, k: usize, dist: Float, j: usize) {
+    if surrounding.len() < k {
+        surrounding.push((NotNan::new(dist).unwrap(), j));
+    } else if dist < *surrounding.peek().unwrap().0 {
+        surrounding.pop();
+        surrounding.push((NotNan::new(dist).unwrap(), j));
+    }
+}
+
+enum KdNode {
+    Leaf {
+        start: usize,
+        end: usize,
+    },
+    Split {
+        axis: usize,
+        value: Float,
+        left: usize,
+        right: usize,
+    },
+}
+
+// A k-d tree over point indices, splitting at the median of the widest axis
+struct KdTree<'a> {
+    points: &'a [Vector3],
+    indices: Vec<usize>,
+    nodes: Vec<KdNode>,
+}
+
+impl<'a> KdTree<'a> {
+    const ROOT: usize = 0;
+    const LEAF_SIZE: usize = 8;
+
+    fn new(points: &'a [Vector3]) -> Self {
+        let mut tree = KdTree {
+            points,
+            indices: (0..points.len()).collect(),
+            nodes: Vec::with_capacity(2 * points.len() / Self::LEAF_SIZE + 1),
+        };
+        tree.build(0, points.len());
+        tree
+    }
+
+    // Builds the subtree over `indices[start..end]` and returns its node index
+    fn build(&mut self, start: usize, end: usize) -> usize {
+        let node = self.nodes.len();
+        if end - start <= Self::LEAF_SIZE {
+            self.nodes.push(KdNode::Leaf { start, end });
+            return node;
+        }
+        let mut min = [Float::INFINITY; 3];
+        let mut max = [Float::NEG_INFINITY; 3];
+        for &i in &self.indices[start..end] {
+            for (axis, &x) in self.points[i].items().iter().enumerate() {
+                min[axis] = min[axis].min(x);
+                max[axis] = max[axis].max(x);
+            }
+        }
+        let axis = (0..3)
+            .max_by(|&a, &b| (max[a] - min[a]).partial_cmp(&(max[b] - min[b])).unwrap())
+            .unwrap();
+        let mid = (start + end) / 2;
+        let points = self.points;
+        self.indices[start..end].select_nth_unstable_by(mid - start, |&a, &b| {
+            points[a].items()[axis]
+                .partial_cmp(&points[b].items()[axis])
+                .unwrap()
+        });
+        let value = points[self.indices[mid]].items()[axis];
+
+        // Placeholder until the children are known
+        self.nodes.push(KdNode::Leaf { start, end });
+        let left = self.build(start, mid);
+        let right = self.build(mid, end);
+        self.nodes[node] = KdNode::Split {
+            axis,
+            value,
+            left,
+            right,
+        };
+        node
+    }
+
+    // Offers all points in the subtree that may be among the k nearest to point `i`
+    fn search(
+        &self,
+        node: usize,
+        i: usize,
+        k: usize,
+        surrounding: &mut BinaryHeap<(NotNan<Float>, usize)>,
+    ) {
+        let target = self.points[i];
+        match self.nodes[node] {
+            KdNode::Leaf { start, end } => {
+                for &j in &self.indices[start..end] {
+                    if j != i {
+                        offer(surrounding, k, (self.points[j] - target).norm_squared(), j);
+                    }
+                }
+            }
+            KdNode::Split {
+                axis,
+                value,
+                left,
+                right,
+            } => {
+                let diff = target.items()[axis] - value;
+                let (near, far) = if diff < 0.0 {
+                    (left, right)
+                } else {
+                    (right, left)
+                };
+                self.search(near, i, k, surrounding);
+                if surrounding.len() < k || diff * diff < *surrounding.peek().unwrap().0 {
+                    self.search(far, i, k, surrounding);
+                }
+            }
+        }
+    }
+
+    // Collects all points in the subtree within `radius` of point `i`, other than `i` itself
+    fn search_within(&self, node: usize, i: usize, radius: Float, surrounding: &mut Vec<usize>) {
+        let target = self.points[i];
+        match self.nodes[node] {
+            KdNode::Leaf { start, end } => {
+                for &j in &self.indices[start..end] {
+                    if j != i && (self.points[j] - target).norm_squared() <= radius * radius {
+                        surrounding.push(j);
+                    }
+                }
+            }
+            KdNode::Split {
+                axis,
+                value,
+                left,
+                right,
+            } => {
+                let diff = target.items()[axis] - value;
+                if diff <= radius {
+                    self.search_within(left, i, radius, surrounding);
+                }
+                if diff >= -radius {
+                    self.search_within(right, i, radius, surrounding);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn points(count: usize) -> Vec<Vector3> {
+        let mut rng = StdRng::seed_from_u64(3);
+        (0..count)
+            .map(|_| (0..3).map(|_| rng.gen_range(-1.0, 1.0)).collect())
+            .collect()
+    }
+
+    fn sorted(neighbors: &[usize]) -> Vec<usize> {
+        let mut neighbors = neighbors.to_vec();
+        neighbors.sort_unstable();
+        neighbors
+    }
+
+    #[test]
+    fn tree_nearest_neighbors_match_quadratic() {
+        let (points, k) = (points(500), 30);
+        let tree = nearest_neighbors_tree(k, &points);
+        let quadratic = nearest_neighbors_quadratic(k, &points);
+        for i in 0..points.len() {
+            let range = i * k..(i + 1) * k;
+            assert_eq!(sorted(&tree[range.clone()]), sorted(&quadratic[range]));
+        }
+    }
+
+    #[test]
+    fn tree_neighbors_within_match_quadratic() {
+        let points = points(500);
+        let mut rng = StdRng::seed_from_u64(4);
+        let radii: Vec<Float> = (0..points.len()).map(|_| rng.gen_range(0.0, 0.5)).collect();
+        let queried: Vec<bool> = (0..points.len()).map(|i| i % 3 != 0).collect();
+        let tree = neighbors_within_tree(&points, &radii, &queried);
+        let quadratic = neighbors_within_quadratic(&points, &radii, &queried);
+        assert_eq!(tree.points(), points.len());
+        for (i, &queried) in queried.iter().enumerate() {
+            assert_eq!(sorted(tree.of(i)), sorted(quadratic.of(i)));
+            assert!(queried || tree.of(i).is_empty());
+        }
+    }
+
+    #[test]
+    fn symmetric_neighbors_are_symmetric_and_keep_every_pair() {
+        let points = points(300);
+        let mut rng = StdRng::seed_from_u64(5);
+        let radii: Vec<Float> = (0..points.len()).map(|_| rng.gen_range(0.0, 0.4)).collect();
+        let queried: Vec<bool> = (0..points.len()).map(|i| i % 4 != 0).collect();
+        let neighbors = neighbors_within_tree(&points, &radii, &queried);
+        let symmetric = neighbors.symmetric();
+        assert_eq!(symmetric.points(), points.len());
+        for i in 0..points.len() {
+            let list = symmetric.of(i);
+            // Sorted and without duplicates
+            assert!(list.windows(2).all(|pair| pair[0] < pair[1]));
+            for &j in neighbors.of(i) {
+                assert!(list.contains(&j));
+            }
+            for &j in list {
+                assert!(symmetric.of(j).contains(&i));
+                assert!(neighbors.of(i).contains(&j) || neighbors.of(j).contains(&i));
+            }
+        }
+        // Points that were not queried get the points that found them
+        assert!((0..points.len()).any(|i| !queried[i] && !symmetric.of(i).is_empty()));
+    }
 }
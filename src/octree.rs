@@ -0,0 +1,117 @@
+use crate::vector::{Float, Vector3};
+
+pub struct OctreeNode {
+    pub center: Vector3,
+    pub half_width: Float,
+    // The points of this node are `Octree::indices[start..end]`
+    pub start: usize,
+    pub end: usize,
+    // Empty for leaves. Empty octants are not stored
+    pub children: Vec<usize>,
+}
+
+// A cubic octree over point indices. Node 0 is the root, and every child has a higher index than
+// its parent, so iterating the nodes in reverse visits children before parents
+pub struct Octree {
+    pub nodes: Vec<OctreeNode>,
+    pub indices: Vec<usize>,
+}
+
+impl Octree {
+    pub const ROOT: usize = 0;
+    const MAX_DEPTH: usize = 48;
+
+    // Nodes with at most `leaf_size` points are not subdivided further
+    pub fn new(points: &[Vector3], leaf_size: usize) -> Self {
+        let mut min = [Float::INFINITY; 3];
+        let mut max = [Float::NEG_INFINITY; 3];
+        for point in points {
+            for (axis, &x) in point.items().iter().enumerate() {
+                min[axis] = min[axis].min(x);
+                max[axis] = max[axis].max(x);
+            }
+        }
+        let center: Vector3 = (0..3).map(|axis| (min[axis] + max[axis]) / 2.0).collect();
+        let half_width = (0..3)
+            .map(|axis| (max[axis] - min[axis]) / 2.0)
+            .fold(Float::MIN_POSITIVE, Float::max);
+
+        let mut tree = Octree {
+            nodes: Vec::new(),
+            indices: (0..points.len()).collect(),
+        };
+        tree.build(points, leaf_size, center, half_width, 0, points.len(), 0);
+        tree
+    }
+
+    pub fn points(&self, node: usize) -> &[usize] {
+        &self.indices[self.nodes[node].start..self.nodes[node].end]
+    }
+
+    pub fn is_leaf(&self, node: usize) -> bool {
+        self.nodes[node].children.is_empty()
+    }
+
+    // Builds the subtree over `indices[start..end]` and returns its node index
+    fn build(
+        &mut self,
+        points: &[Vector3],
+        leaf_size: usize,
+        center: Vector3,
+        half_width: Float,
+        start: usize,
+        end: usize,
+        depth: usize,
+    ) -> usize {
+        let node = self.nodes.len();
+        self.nodes.push(OctreeNode {
+            center,
+            half_width,
+            start,
+            end,
+            children: Vec::new(),
+        });
+        if end - start <= leaf_size || depth >= Self::MAX_DEPTH {
+            return node;
+        }
+
+        // Sort the points of this node by octant
+        let octant = |i: usize| {
+            let offset = points[i] - center;
+            offset
+                .items()
+                .iter()
+                .enumerate()
+                .map(|(axis, &x)| if x >= 0.0 { 1 << axis } else { 0 })
+                .sum::<usize>()
+        };
+        self.indices[start..end].sort_unstable_by_key(|&i| octant(i));
+
+        let mut children = Vec::new();
+        let mut child_start = start;
+        while child_start < end {
+            let current = octant(self.indices[child_start]);
+            let child_end = child_start
+                + self.indices[child_start..end]
+                    .iter()
+                    .take_while(|&&i| octant(i) == current)
+                    .count();
+            let child_center = center
+                + Vector3::unit_x() * half_width / 2.0 * if current & 1 != 0 { 1.0 } else { -1.0 }
+                + Vector3::unit_y() * half_width / 2.0 * if current & 2 != 0 { 1.0 } else { -1.0 }
+                + Vector3::unit_z() * half_width / 2.0 * if current & 4 != 0 { 1.0 } else { -1.0 };
+            children.push(self.build(
+                points,
+                leaf_size,
+                child_center,
+                half_width / 2.0,
+                child_start,
+                child_end,
+                depth + 1,
+            ));
+            child_start = child_end;
+        }
+        self.nodes[node].children = children;
+        node
+    }
+}
@@ -0,0 +1,51 @@
+use crate::config::{OpacityType, SimulationConfig};
+use crate::vector::Float;
+
+// The mean opacities of the gas per unit mass, weighted for emission (Planck) and for diffusion
+// (Rosseland)
+pub trait Opacity {
+    fn planck(&self, density: Float, temperature: Float) -> Float;
+    fn rosseland(&self, density: Float, temperature: Float) -> Float;
+}
+
+// Calls `f` with the configured opacity
+pub fn with_opacity<R>(config: &SimulationConfig, f: impl FnOnce(&dyn Opacity) -> R) -> R {
+    match config.opacity {
+        OpacityType::Constant => f(&Constant(config.constant_opacity)),
+        OpacityType::Dust => f(&Dust),
+    }
+}
+
+pub struct Constant(Float);
+
+impl Opacity for Constant {
+    fn planck(&self, _density: Float, _temperature: Float) -> Float {
+        self.0
+    }
+    fn rosseland(&self, _density: Float, _temperature: Float) -> Float {
+        self.0
+    }
+}
+
+// Dust grains, with the same Planck and Rosseland means
+pub struct Dust;
+
+impl Opacity for Dust {
+    fn planck(&self, _density: Float, temperature: Float) -> Float {
+        dust(temperature)
+    }
+    fn rosseland(&self, _density: Float, temperature: Float) -> Float {
+        dust(temperature)
+    }
+}
+
+// The opacity of ice grains and, once the ice has evaporated, metal grains, from Bell & Lin
+// (1994) in cm^2/g converted to m^2/kg
+pub fn dust(temperature: Float) -> Float {
+    let t = temperature;
+    if t < 166.8 {
+        2e-4 * t * t * 0.1
+    } else {
+        (2e16 * t.powi(-7)).max(0.1 * t.sqrt()) * 0.1
+    }
+}
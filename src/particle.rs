@@ -1,163 +1,927 @@
-use crate::constants::{
-    GRAVITATIONAL_CONSTANT, NEIGHBORS, PARTICLE_MASS, PI, SMOOTHING_DIST_FACTOR, FLOAT_ZERO,
-};
+use crate::config::{SimulationConfig, Softening};
+use crate::constants::{EPSILON, FLOAT_ZERO, GRAVITATIONAL_CONSTANT, VACUUM_PERMEABILITY};
+use crate::eos;
+use crate::kernel;
 use crate::vector::{Float, Vector3};
 
-pub fn smoothing_length(self_pos: Vector3, surround_pos: &[Vector3]) -> Float {
+// The smoothing length at which the kernel support just reaches the farthest neighbor, as a first
+// guess for `smoothing`
+pub fn smoothing_length(
+    config: &SimulationConfig,
+    self_pos: Vector3,
+    surround_pos: &[Vector3],
+) -> Float {
     surround_pos
         .iter()
         .map(|&other_pos| (other_pos - self_pos).norm_squared())
         .fold(FLOAT_ZERO, |a, b| a.max(b))
         .sqrt()
-        / SMOOTHING_DIST_FACTOR
+        / kernel::with_kernel(config, |kernel| kernel.support())
+}
+
+// Smoothing lengths are converged when a step changes them by less than this fraction
+const SMOOTHING_TOLERANCE: Float = 1e-4;
+const SMOOTHING_ITERATIONS: usize = 50;
+
+// A smoothing length and the density it gives
+pub struct Smoothing {
+    pub smooth: Float,
+    pub density: Float,
+    // The grad-h correction 1 - dh/drho * drho/dh, from the variation of h with the density
+    pub omega: Float,
+    pub iterations: usize,
+    pub converged: bool,
 }
 
-pub fn density(
+// Solves h = smoothing_length_factor (m / rho)^(1/3) for the smoothing length h, with the density
+// rho summed over the particle itself and its neighbors with the kernel of h. Iterates by
+// Newton-Raphson from `initial`, bisecting instead whenever a step would leave the bracket of the
+// root found so far (Price & Monaghan 2007)
+pub fn smoothing(
+    config: &SimulationConfig,
     self_pos: Vector3,
-    self_smooth: Float,
+    self_mass: Float,
+    initial: Float,
     surround_pos: &[Vector3],
-    surround_smooth: &[Float],
-) -> Float {
-    surround_pos
-        .iter()
-        .zip(surround_smooth.iter())
-        .map(|(&pos, &smooth)| PARTICLE_MASS * kernel(self_pos, self_smooth, pos, smooth))
-        .sum()
+    surround_mass: &[Float],
+) -> Smoothing {
+    kernel::with_kernel(config, |kernel| {
+        // The density at `smooth` and its derivative with respect to `smooth`
+        let density = |smooth: Float| {
+            (0..surround_pos.len()).fold(
+                (
+                    self_mass * kernel.value(0.0, smooth),
+                    self_mass * kernel.smoothing_derivative(0.0, smooth),
+                ),
+                |(density, derivative), i| {
+                    let dist = (surround_pos[i] - self_pos).norm();
+                    (
+                        density + surround_mass[i] * kernel.value(dist, smooth),
+                        derivative + surround_mass[i] * kernel.smoothing_derivative(dist, smooth),
+                    )
+                },
+            )
+        };
+        let (mut lower, mut upper) = (0.0, Float::INFINITY);
+        let mut smooth = initial;
+        let mut iterations = 0;
+        loop {
+            iterations += 1;
+            let (density, derivative) = density(smooth);
+            let omega = 1.0 + smooth * derivative / (3.0 * density);
+            // The density summed is too low for the smoothing length when it is too short
+            let target = self_mass * (config.smoothing_length_factor / smooth).powi(3);
+            if density < target {
+                lower = smooth;
+            } else {
+                upper = smooth;
+            }
+            let mut next = smooth - (density - target) / (derivative + 3.0 * target / smooth);
+            if !(next > lower && next < upper) {
+                next = if upper.is_finite() {
+                    (lower + upper) / 2.0
+                } else {
+                    2.0 * smooth
+                };
+            }
+            let converged = ((next - smooth) / smooth).abs() < SMOOTHING_TOLERANCE;
+            if converged || iterations == SMOOTHING_ITERATIONS {
+                return Smoothing {
+                    smooth,
+                    density,
+                    omega,
+                    iterations,
+                    converged,
+                };
+            }
+            smooth = next;
+        }
+    })
 }
 
-pub fn gravitational_acceleration(self_pos: Vector3, other_pos: &[Vector3]) -> Vector3 {
-    other_pos
-        .iter()
-        .filter(|&&pos| self_pos != pos)
-        .fold(Vector3::zero(), |acc, &pos| {
-            acc + gravitational_acceleration_from(self_pos, pos)
+pub fn gravitational_acceleration(
+    config: &SimulationConfig,
+    self_pos: Vector3,
+    self_soft: Float,
+    other_pos: &[Vector3],
+    other_mass: &[Float],
+    other_soft: &[Float],
+) -> Vector3 {
+    (0..other_pos.len())
+        .filter(|&j| self_pos != other_pos[j])
+        .fold(Vector3::zero(), |acc, j| {
+            acc + gravitational_acceleration_from(
+                config,
+                self_pos,
+                self_soft,
+                other_pos[j],
+                other_mass[j],
+                other_soft[j],
+            )
         })
 }
 
-// The acceleration due to pressure according to the euler momentum equation
+// The acceleration due to pressure according to the euler momentum equation, and with
+// `magnetic_fields` also due to the magnetic stress
 pub fn pressure_acceleration(
+    config: &SimulationConfig,
     self_pos: Vector3,
+    self_mass: Float,
     self_energy: Float,
     self_smooth: Float,
     self_density: Float,
+    self_omega: Float,
+    self_field: Vector3,
     surround_pos: &[Vector3],
+    surround_mass: &[Float],
     surround_energy: &[Float],
     surround_smooth: &[Float],
     surround_density: &[Float],
+    surround_omega: &[Float],
+    surround_field: &[Vector3],
 ) -> Vector3 {
-    -1.0 * grad_pressure(
-        self_pos,
-        self_energy,
-        self_smooth,
-        self_density,
-        surround_pos,
-        surround_energy,
-        surround_smooth,
-        surround_density,
-    ) / self_density
+    let pressure =
+        -1.0 * grad_pressure(
+            config,
+            self_pos,
+            self_mass,
+            self_energy,
+            self_smooth,
+            self_density,
+            self_omega,
+            surround_pos,
+            surround_mass,
+            surround_energy,
+            surround_smooth,
+            surround_density,
+            surround_omega,
+        ) / self_density;
+    if !config.magnetic_fields {
+        return pressure;
+    }
+    pressure
+        + magnetic_acceleration(
+            config,
+            self_pos,
+            self_mass,
+            self_energy,
+            self_smooth,
+            self_density,
+            self_omega,
+            self_field,
+            surround_pos,
+            surround_mass,
+            surround_smooth,
+            surround_density,
+            surround_omega,
+            surround_field,
+        )
 }
 
+// The work done by pressure, which with grad-h terms is that of the pressure acceleration
 pub fn time_derivative_thermal_energy(
+    config: &SimulationConfig,
     self_pos: Vector3,
     self_vel: Vector3,
+    self_mass: Float,
     self_energy: Float,
     self_smooth: Float,
     self_density: Float,
+    self_omega: Float,
     surround_pos: &[Vector3],
     surround_vel: &[Vector3],
-    surround_energy: &[Float],
-    surround_smooth: &[Float],
-    surround_density: &[Float],
+    surround_mass: &[Float],
 ) -> Float {
-    (0..NEIGHBORS)
-        .into_iter()
-        .map(|i| {
-            (pressure(self_energy, self_density) / self_density.powi(2)
-                + pressure(surround_energy[i], surround_density[i]) / surround_density[i].powi(2))
-                * grad_kernel(self_pos, self_smooth, surround_pos[i], surround_smooth[i])
-                    .dot(self_vel - surround_vel[i])
-        })
-        .sum::<Float>()
-        * PARTICLE_MASS.powi(2)
-        / 2.0
+    let divergence = kernel::with_kernel(config, |kernel| {
+        (0..surround_pos.len())
+            .map(|i| {
+                surround_mass[i]
+                    * kernel
+                        .gradient(self_pos - surround_pos[i], self_smooth)
+                        .dot(self_vel - surround_vel[i])
+            })
+            .sum::<Float>()
+    });
+    pressure(config, self_mass, self_energy, self_density) / (self_omega * self_density.powi(2))
+        * divergence
+        * self_mass
 }
 
 pub fn neighborhood_velocity(
+    config: &SimulationConfig,
     self_pos: Vector3,
     self_vel: Vector3,
     self_smooth: Float,
     self_density: Float,
     surround_pos: &[Vector3],
     surround_vel: &[Vector3],
+    surround_mass: &[Float],
     surround_smooth: &[Float],
     surround_density: &[Float],
 ) -> Vector3 {
-    (0..NEIGHBORS)
-        .into_iter()
+    (0..surround_pos.len())
         .map(|i| {
-            (surround_vel[i] - self_vel) / (self_density + surround_density[i])
-                * kernel(self_pos, self_smooth, surround_pos[i], surround_smooth[i])
+            (surround_vel[i] - self_vel) * surround_mass[i] / (self_density + surround_density[i])
+                * kernel(
+                    config,
+                    self_pos,
+                    self_smooth,
+                    surround_pos[i],
+                    surround_smooth[i],
+                )
         })
         .sum::<Vector3>()
-        * PARTICLE_MASS
         * 2.0
 }
 
-pub fn pressure(energy: Float, density: Float) -> Float {
-    energy * density / PARTICLE_MASS / 1.5
+// The pressure at a particle of the given mass and thermal energy, from the configured equation of
+// state
+pub fn pressure(config: &SimulationConfig, mass: Float, energy: Float, density: Float) -> Float {
+    eos::with_equation_of_state(config, |eos| eos.pressure(density, energy / mass))
+}
+
+pub fn sound_speed(config: &SimulationConfig, mass: Float, energy: Float, density: Float) -> Float {
+    eos::with_equation_of_state(config, |eos| eos.sound_speed(density, energy / mass))
+}
+
+pub fn temperature(config: &SimulationConfig, mass: Float, energy: Float, density: Float) -> Float {
+    eos::with_equation_of_state(config, |eos| eos.temperature(density, energy / mass))
+}
+
+// The divergence and the magnitude of the curl of the velocity field at this particle
+pub fn velocity_divergence_curl(
+    config: &SimulationConfig,
+    self_pos: Vector3,
+    self_vel: Vector3,
+    self_smooth: Float,
+    self_density: Float,
+    surround_pos: &[Vector3],
+    surround_vel: &[Vector3],
+    surround_mass: &[Float],
+    surround_smooth: &[Float],
+) -> (Float, Float) {
+    let (divergence, curl) = (0..surround_pos.len())
+        .map(|i| {
+            let grad = grad_kernel(
+                config,
+                self_pos,
+                self_smooth,
+                surround_pos[i],
+                surround_smooth[i],
+            );
+            let relative_vel = (self_vel - surround_vel[i]) * surround_mass[i];
+            (relative_vel.dot(grad), relative_vel.cross(grad))
+        })
+        .fold((0.0, Vector3::zero()), |(div, curl), (d, c)| {
+            (div + d, curl + c)
+        });
+    (-divergence / self_density, curl.norm() / self_density)
+}
+
+// The gradient of the radiation energy density at this particle
+pub fn radiation_energy_gradient(
+    config: &SimulationConfig,
+    self_pos: Vector3,
+    self_smooth: Float,
+    self_density: Float,
+    self_radiation: Float,
+    surround_pos: &[Vector3],
+    surround_mass: &[Float],
+    surround_smooth: &[Float],
+    surround_radiation: &[Float],
+) -> Vector3 {
+    (0..surround_pos.len())
+        .map(|i| {
+            grad_kernel(
+                config,
+                self_pos,
+                self_smooth,
+                surround_pos[i],
+                surround_smooth[i],
+            ) * surround_mass[i]
+                * (surround_radiation[i] - self_radiation)
+        })
+        .sum::<Vector3>()
+        / self_density
+}
+
+// The weight of every neighbor in the diffusion of radiation energy, such that the radiation
+// energy per unit mass of this particle changes at the rate sum_j w_j (E_j - E_self), where E is
+// the radiation energy density. The diffusion coefficients are combined by their harmonic mean
+// (Whitehouse & Bate 2004)
+pub fn radiation_diffusion_weights(
+    config: &SimulationConfig,
+    self_pos: Vector3,
+    self_smooth: Float,
+    self_density: Float,
+    self_diffusion: Float,
+    surround_pos: &[Vector3],
+    surround_mass: &[Float],
+    surround_smooth: &[Float],
+    surround_density: &[Float],
+    surround_diffusion: &[Float],
+) -> Vec<Float> {
+    (0..surround_pos.len())
+        .map(|i| {
+            let offset = self_pos - surround_pos[i];
+            let diffusion = self_diffusion + surround_diffusion[i];
+            if offset.norm_squared() == 0.0 || diffusion == 0.0 {
+                return 0.0;
+            }
+            let grad = grad_kernel(
+                config,
+                self_pos,
+                self_smooth,
+                surround_pos[i],
+                surround_smooth[i],
+            );
+            -surround_mass[i] / (self_density * surround_density[i])
+                * 4.0
+                * self_diffusion
+                * surround_diffusion[i]
+                / diffusion
+                * offset.dot(grad)
+                / offset.norm_squared()
+        })
+        .collect()
+}
+
+// The fast magnetosonic speed perpendicular to the field, which is the sound speed without
+// `magnetic_fields`
+pub fn fast_speed(
+    config: &SimulationConfig,
+    sound_speed: Float,
+    field: Vector3,
+    density: Float,
+) -> Float {
+    if !config.magnetic_fields {
+        return sound_speed;
+    }
+    (sound_speed * sound_speed + field.norm_squared() / (VACUUM_PERMEABILITY * density)).sqrt()
+}
+
+// The divergence of the magnetic field at this particle, in the difference form that is
+// consistent with the divergence cleaning (Tricco & Price 2012), with this particle's own
+// smoothing length and grad-h correction
+pub fn magnetic_divergence(
+    config: &SimulationConfig,
+    self_pos: Vector3,
+    self_smooth: Float,
+    self_density: Float,
+    self_omega: Float,
+    self_field: Vector3,
+    surround_pos: &[Vector3],
+    surround_mass: &[Float],
+    surround_field: &[Vector3],
+) -> Float {
+    -kernel::with_kernel(config, |kernel| {
+        (0..surround_pos.len())
+            .map(|i| {
+                let grad = kernel.gradient(self_pos - surround_pos[i], self_smooth);
+                surround_mass[i] * (self_field - surround_field[i]).dot(grad)
+            })
+            .sum::<Float>()
+    }) / (self_omega * self_density)
+}
+
+// The time derivative of the magnetic field by the ideal induction equation (Price 2012), with
+// this particle's own smoothing length and grad-h correction. With `divergence_cleaning` the
+// gradient of the cleaning field psi = c_h psi' is subtracted, where `cleaning` is psi' and
+// `cleaning_speed` is c_h, in the form conjugate to `magnetic_divergence`
+pub fn time_derivative_magnetic_field(
+    config: &SimulationConfig,
+    self_pos: Vector3,
+    self_vel: Vector3,
+    self_smooth: Float,
+    self_density: Float,
+    self_omega: Float,
+    self_field: Vector3,
+    self_cleaning: Float,
+    self_cleaning_speed: Float,
+    surround_pos: &[Vector3],
+    surround_vel: &[Vector3],
+    surround_mass: &[Float],
+    surround_smooth: &[Float],
+    surround_density: &[Float],
+    surround_omega: &[Float],
+    surround_cleaning: &[Float],
+    surround_cleaning_speed: &[Float],
+) -> Vector3 {
+    let self_psi = self_cleaning_speed * self_cleaning / self_density.powi(2);
+    let (induction, cleaning) = (0..surround_pos.len())
+        .map(|i| {
+            let (self_grad, surround_grad) = grad_kernels(
+                config,
+                self_pos,
+                self_smooth,
+                self_omega,
+                surround_pos[i],
+                surround_smooth[i],
+                surround_omega[i],
+            );
+            let relative_vel = self_vel - surround_vel[i];
+            let surround_psi =
+                surround_cleaning_speed[i] * surround_cleaning[i] / surround_density[i].powi(2);
+            (
+                (relative_vel * self_field.dot(self_grad)
+                    - self_field * relative_vel.dot(self_grad))
+                    * surround_mass[i],
+                (self_grad * self_psi + surround_grad * surround_psi) * surround_mass[i],
+            )
+        })
+        .fold((Vector3::zero(), Vector3::zero()), |(a, b), (da, db)| {
+            (a + da, b + db)
+        });
+    if config.divergence_cleaning {
+        induction / -self_density - cleaning * self_density
+    } else {
+        induction / -self_density
+    }
+}
+
+// The time derivative of the cleaning field psi' = psi / c_h (Tricco, Price & Bate 2016), which
+// is driven by the divergence of the magnetic field and decays over `1 / cleaning_damping`
+// crossings of the smoothing length at `cleaning_speed`
+pub fn time_derivative_cleaning_field(
+    config: &SimulationConfig,
+    cleaning: Float,
+    smooth: Float,
+    cleaning_speed: Float,
+    magnetic_divergence: Float,
+    velocity_divergence: Float,
+) -> Float {
+    if !config.divergence_cleaning {
+        return 0.0;
+    }
+    -cleaning_speed * magnetic_divergence
+        - cleaning * config.cleaning_damping * cleaning_speed / smooth
+        - 0.5 * cleaning * velocity_divergence
+}
+
+// The per-particle state of the artificial viscosity
+#[derive(Clone, Copy)]
+pub struct Viscosity {
+    pub sound_speed: Float,
+    pub alpha: Float,
+    // The Balsara switch, close to 1 in compressive flow and close to 0 in shear flow
+    pub balsara: Float,
+}
+
+impl Viscosity {
+    pub fn new(
+        config: &SimulationConfig,
+        sound_speed: Float,
+        alpha: Float,
+        smooth: Float,
+        divergence: Float,
+        curl: Float,
+    ) -> Self {
+        let balsara = if config.balsara_switch {
+            divergence.abs() / (divergence.abs() + curl + 1e-4 * sound_speed / smooth + EPSILON)
+        } else {
+            1.0
+        };
+        Viscosity {
+            sound_speed,
+            alpha,
+            balsara,
+        }
+    }
+}
+
+// The (acceleration, time derivative of thermal energy) due to Monaghan artificial viscosity,
+// which is only active for approaching particles. Pairs interact through the mean of their grad-h
+// corrected kernel gradients, which keeps the forces antisymmetric
+pub fn artificial_viscosity(
+    config: &SimulationConfig,
+    self_pos: Vector3,
+    self_vel: Vector3,
+    self_mass: Float,
+    self_smooth: Float,
+    self_density: Float,
+    self_omega: Float,
+    self_visc: Viscosity,
+    surround_pos: &[Vector3],
+    surround_vel: &[Vector3],
+    surround_mass: &[Float],
+    surround_smooth: &[Float],
+    surround_density: &[Float],
+    surround_omega: &[Float],
+    surround_visc: &[Viscosity],
+) -> (Vector3, Float) {
+    let (accel, energy) = (0..surround_pos.len())
+        .map(|i| {
+            let relative_pos = self_pos - surround_pos[i];
+            let relative_vel = self_vel - surround_vel[i];
+            let approach = relative_vel.dot(relative_pos);
+            if approach >= 0.0 {
+                return (Vector3::zero(), 0.0);
+            }
+            let smooth = (self_smooth + surround_smooth[i]) / 2.0;
+            let mu = smooth * approach / (relative_pos.norm_squared() + 0.01 * smooth * smooth);
+            let alpha = (self_visc.alpha + surround_visc[i].alpha) / 2.0;
+            let beta = config.viscosity_beta * alpha / config.viscosity_alpha;
+            let sound_speed = (self_visc.sound_speed + surround_visc[i].sound_speed) / 2.0;
+            let density = (self_density + surround_density[i]) / 2.0;
+            let balsara = (self_visc.balsara + surround_visc[i].balsara) / 2.0;
+            let viscosity =
+                (-alpha * sound_speed * mu + beta * mu * mu) / density * balsara * surround_mass[i];
+            let (self_grad, surround_grad) = grad_kernels(
+                config,
+                self_pos,
+                self_smooth,
+                self_omega,
+                surround_pos[i],
+                surround_smooth[i],
+                surround_omega[i],
+            );
+            let grad = (self_grad + surround_grad) / 2.0;
+            (-viscosity * grad, viscosity * grad.dot(relative_vel))
+        })
+        .fold((Vector3::zero(), 0.0), |(a, e), (da, de)| (a + da, e + de));
+    (accel, energy * self_mass / 2.0)
 }
 
-fn gravitational_acceleration_from(self_pos: Vector3, other_pos: Vector3) -> Vector3 {
+// The Morris-Monaghan time derivative of the viscosity parameter, decaying towards
+// `viscosity_alpha_min` and growing in converging flows
+pub fn time_derivative_viscosity_alpha(
+    config: &SimulationConfig,
+    alpha: Float,
+    smooth: Float,
+    sound_speed: Float,
+    divergence: Float,
+) -> Float {
+    let decay_time = smooth / (config.viscosity_decay * sound_speed + EPSILON);
+    -(alpha - config.viscosity_alpha_min) / decay_time
+        + (-divergence).max(0.0) * (config.viscosity_alpha - alpha)
+}
+
+pub fn gravitational_acceleration_from(
+    config: &SimulationConfig,
+    self_pos: Vector3,
+    self_soft: Float,
+    other_pos: Vector3,
+    other_mass: Float,
+    other_soft: Float,
+) -> Vector3 {
     let v = other_pos - self_pos;
-    PARTICLE_MASS * GRAVITATIONAL_CONSTANT * v / v.norm().powi(3)
+    let (force, _) = softened_gravity(config, v.norm(), (self_soft + other_soft) / 2.0);
+    other_mass * GRAVITATIONAL_CONSTANT * force * v
 }
 
-// The gradient of pressure at this particle
-fn grad_pressure(
+pub fn gravitational_potential_from(
+    config: &SimulationConfig,
+    self_pos: Vector3,
+    self_soft: Float,
+    other_pos: Vector3,
+    other_mass: Float,
+    other_soft: Float,
+) -> Float {
+    let dist = (other_pos - self_pos).norm();
+    let (_, potential) = softened_gravity(config, dist, (self_soft + other_soft) / 2.0);
+    other_mass * GRAVITATIONAL_CONSTANT * potential
+}
+
+// The softened law of gravity as (f, phi), such that a unit mass at distance `dist` gives the
+// acceleration `G f dist` towards it and the potential `G phi`. The spline softening is the
+// potential of a mass distributed as the M4 cubic spline kernel with support `2 * softening`,
+// and is exactly newtonian beyond that
+pub fn softened_gravity(
+    config: &SimulationConfig,
+    dist: Float,
+    softening: Float,
+) -> (Float, Float) {
+    let newtonian = (dist.powi(-3), -dist.recip());
+    if softening <= 0.0 {
+        return newtonian;
+    }
+    match config.softening {
+        Softening::None => newtonian,
+        Softening::Plummer => {
+            let soft_dist2 = dist * dist + softening * softening;
+            (soft_dist2.powf(-1.5), -soft_dist2.sqrt().recip())
+        }
+        Softening::Spline => {
+            let h = softening;
+            let q = dist / h;
+            if q < 1.0 {
+                (
+                    (4.0 / 3.0 - 6.0 / 5.0 * q.powi(2) + 0.5 * q.powi(3)) / h.powi(3),
+                    (2.0 / 3.0 * q.powi(2) - 0.3 * q.powi(4) + 0.1 * q.powi(5) - 7.0 / 5.0) / h,
+                )
+            } else if q < 2.0 {
+                (
+                    (8.0 / 3.0 * q - 3.0 * q.powi(2) + 6.0 / 5.0 * q.powi(3)
+                        - 1.0 / 6.0 * q.powi(4)
+                        - 1.0 / 15.0 / q.powi(2))
+                        / (q * h.powi(3)),
+                    (4.0 / 3.0 * q.powi(2) - q.powi(3) + 0.3 * q.powi(4) - q.powi(5) / 30.0
+                        + 1.0 / 15.0 / q
+                        - 8.0 / 5.0)
+                        / h,
+                )
+            } else {
+                newtonian
+            }
+        }
+    }
+}
+
+// The acceleration due to the magnetic stress tensor (B B - B^2 I / 2) / mu_0, with each
+// particle's own smoothing length and grad-h correction for its term as for pressure. Where the
+// magnetic pressure is comparable to the gas pressure, the contribution of the divergence of the
+// field is subtracted to avoid the tensile instability (Borve, Omang & Trulsen 2001), fully below
+// a plasma beta of 2 and not at all above 10 as in Phantom (Price et al. 2018), since it does not
+// conserve momentum
+fn magnetic_acceleration(
+    config: &SimulationConfig,
     self_pos: Vector3,
+    self_mass: Float,
     self_energy: Float,
     self_smooth: Float,
     self_density: Float,
+    self_omega: Float,
+    self_field: Vector3,
     surround_pos: &[Vector3],
-    surround_energy: &[Float],
+    surround_mass: &[Float],
     surround_smooth: &[Float],
     surround_density: &[Float],
+    surround_omega: &[Float],
+    surround_field: &[Vector3],
 ) -> Vector3 {
-    (0..NEIGHBORS)
-        .into_iter()
+    // The stress over density squared, applied to the kernel gradient
+    let stress = |field: Vector3, density: Float, grad: Vector3| {
+        (field * field.dot(grad) - grad * (field.norm_squared() / 2.0)) / density.powi(2)
+    };
+    let (accel, divergence) = (0..surround_pos.len())
         .map(|i| {
-            (pressure(self_energy, self_density) / self_density.powi(2)
-                + pressure(surround_energy[i], surround_density[i]) / surround_density[i].powi(2))
-                * grad_kernel(self_pos, self_smooth, surround_pos[i], surround_smooth[i])
+            let (self_grad, surround_grad) = grad_kernels(
+                config,
+                self_pos,
+                self_smooth,
+                self_omega,
+                surround_pos[i],
+                surround_smooth[i],
+                surround_omega[i],
+            );
+            let (field, density) = (surround_field[i], surround_density[i]);
+            (
+                (stress(self_field, self_density, self_grad)
+                    + stress(field, density, surround_grad))
+                    * surround_mass[i],
+                ((self_field / self_density.powi(2)).dot(self_grad)
+                    + (field / density.powi(2)).dot(surround_grad))
+                    * surround_mass[i],
+            )
         })
-        .sum::<Vector3>()
-        * self_density
-        * PARTICLE_MASS
+        .fold((Vector3::zero(), 0.0), |(a, d), (da, dd)| (a + da, d + dd));
+    // Without a field the correction has nothing to act on, but beta would be NaN at zero pressure
+    let field2 = self_field.norm_squared();
+    let correction = if field2 == 0.0 {
+        1.0
+    } else {
+        let beta =
+            pressure(config, self_mass, self_energy, self_density) * 2.0 * VACUUM_PERMEABILITY
+                / field2;
+        ((10.0 - beta) / 8.0).clamp(0.0, 1.0)
+    };
+    (accel - self_field * divergence * correction) / VACUUM_PERMEABILITY
+}
+
+// The gradient of pressure at this particle, with each particle's own smoothing length and grad-h
+// correction for its term (Springel & Hernquist 2002)
+fn grad_pressure(
+    config: &SimulationConfig,
+    self_pos: Vector3,
+    self_mass: Float,
+    self_energy: Float,
+    self_smooth: Float,
+    self_density: Float,
+    self_omega: Float,
+    surround_pos: &[Vector3],
+    surround_mass: &[Float],
+    surround_energy: &[Float],
+    surround_smooth: &[Float],
+    surround_density: &[Float],
+    surround_omega: &[Float],
+) -> Vector3 {
+    let self_term = pressure(config, self_mass, self_energy, self_density)
+        / (self_omega * self_density.powi(2));
+    kernel::with_kernel(config, |kernel| {
+        (0..surround_pos.len())
+            .map(|i| {
+                let offset = self_pos - surround_pos[i];
+                let surround_term = pressure(
+                    config,
+                    surround_mass[i],
+                    surround_energy[i],
+                    surround_density[i],
+                ) / (surround_omega[i] * surround_density[i].powi(2));
+                surround_mass[i]
+                    * (self_term * kernel.gradient(offset, self_smooth)
+                        + surround_term * kernel.gradient(offset, surround_smooth[i]))
+            })
+            .sum::<Vector3>()
+    }) * self_density
 }
 
-// The gaussian kernel
-fn kernel(self_pos: Vector3, self_smooth: Float, other_pos: Vector3, other_smooth: Float) -> Float {
+// The configured kernel, using the smaller of the two smoothing lengths
+fn kernel(
+    config: &SimulationConfig,
+    self_pos: Vector3,
+    self_smooth: Float,
+    other_pos: Vector3,
+    other_smooth: Float,
+) -> Float {
     let h = self_smooth.min(other_smooth);
-    let d2 = (self_pos - other_pos).norm_squared();
-    if d2 > (h * SMOOTHING_DIST_FACTOR).powi(2) {
-        0.0
-    } else {
-        PI.powf(-1.5) * (-d2 / (h * h)).exp() / h.powi(3)
-    }
+    kernel::with_kernel(config, |kernel| {
+        kernel.value((self_pos - other_pos).norm(), h)
+    })
 }
 
-// The gradient of the gaussian kernel
+// The gradients of the configured kernel with respect to `self_pos` at the smoothing length of
+// either particle, each divided by that particle's grad-h correction
+fn grad_kernels(
+    config: &SimulationConfig,
+    self_pos: Vector3,
+    self_smooth: Float,
+    self_omega: Float,
+    other_pos: Vector3,
+    other_smooth: Float,
+    other_omega: Float,
+) -> (Vector3, Vector3) {
+    let offset = self_pos - other_pos;
+    kernel::with_kernel(config, |kernel| {
+        (
+            kernel.gradient(offset, self_smooth) / self_omega,
+            kernel.gradient(offset, other_smooth) / other_omega,
+        )
+    })
+}
+
+// The gradient of the configured kernel with respect to `self_pos`
 fn grad_kernel(
+    config: &SimulationConfig,
     self_pos: Vector3,
     self_smooth: Float,
     other_pos: Vector3,
     other_smooth: Float,
 ) -> Vector3 {
     let h = self_smooth.min(other_smooth);
-    let d2 = (self_pos - other_pos).norm_squared();
-    if d2 > (h * SMOOTHING_DIST_FACTOR).powi(2) {
-        Vector3::zero()
-    } else {
-        (other_pos - self_pos) * 2.0 * PI.powf(-1.5) * (-d2 / (h * h)).exp() / h.powi(5)
+    kernel::with_kernel(config, |kernel| kernel.gradient(self_pos - other_pos, h))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SMOOTH: Float = 1.2;
+
+    fn config() -> SimulationConfig {
+        SimulationConfig {
+            magnetic_fields: true,
+            divergence_cleaning: true,
+            ..SimulationConfig::default()
+        }
+    }
+
+    // The offsets of the neighbors of a particle in a cubic lattice of unit spacing and mass,
+    // and so of unit density
+    fn stencil(config: &SimulationConfig) -> Vec<Vector3> {
+        let reach = kernel::with_kernel(config, |kernel| kernel.support()) * SMOOTH;
+        let n = reach.ceil() as i32;
+        let mut offsets = Vec::new();
+        for x in -n..=n {
+            for y in -n..=n {
+                for z in -n..=n {
+                    let offset: Vector3 = [x, y, z].iter().map(|&i| i as Float).collect();
+                    if offset.norm() > 0.0 && offset.norm() < reach {
+                        offsets.push(offset);
+                    }
+                }
+            }
+        }
+        offsets
+    }
+
+    fn divergence(
+        config: &SimulationConfig,
+        pos: Vector3,
+        field: impl Fn(Vector3) -> Vector3,
+    ) -> Float {
+        let surround: Vec<Vector3> = stencil(config).iter().map(|&o| pos + o).collect();
+        let fields: Vec<Vector3> = surround.iter().map(|&p| field(p)).collect();
+        let masses = vec![1.0; surround.len()];
+        magnetic_divergence(
+            config,
+            pos,
+            SMOOTH,
+            1.0,
+            1.0,
+            field(pos),
+            &surround,
+            &masses,
+            &fields,
+        )
+    }
+
+    // The time derivative of the field in a flow of velocity `velocity`, with the cleaning field
+    // `cleaning` and a cleaning speed of 1
+    fn induction(
+        config: &SimulationConfig,
+        pos: Vector3,
+        velocity: impl Fn(Vector3) -> Vector3,
+        field: impl Fn(Vector3) -> Vector3,
+        cleaning: impl Fn(Vector3) -> Float,
+    ) -> Vector3 {
+        let surround: Vec<Vector3> = stencil(config).iter().map(|&o| pos + o).collect();
+        let count = surround.len();
+        let velocities: Vec<Vector3> = surround.iter().map(|&p| velocity(p)).collect();
+        let cleanings: Vec<Float> = surround.iter().map(|&p| cleaning(p)).collect();
+        let ones = vec![1.0; count];
+        time_derivative_magnetic_field(
+            config,
+            pos,
+            velocity(pos),
+            SMOOTH,
+            1.0,
+            1.0,
+            field(pos),
+            cleaning(pos),
+            1.0,
+            &surround,
+            &velocities,
+            &ones,
+            &vec![SMOOTH; count],
+            &ones,
+            &ones,
+            &cleanings,
+            &ones,
+        )
+    }
+
+    #[test]
+    fn uniform_field_in_uniform_flow_is_unchanged() {
+        let config = config();
+        let field: Vector3 = [1e-9, -2e-9, 3e-9].iter().copied().collect();
+        let velocity: Vector3 = [4e3, 5e3, -6e3].iter().copied().collect();
+        let pos: Vector3 = [3.0, -1.0, 2.0].iter().copied().collect();
+        assert_eq!(divergence(&config, pos, |_| field), 0.0);
+        let rate = induction(&config, pos, |_| velocity, |_| field, |_| 1e-9);
+        assert!(rate.norm() < 1e-12 * field.norm(), "{}", rate);
+        let cleaning = time_derivative_cleaning_field(&config, 0.0, SMOOTH, 1.0, 0.0, 0.0);
+        assert_eq!(cleaning, 0.0);
+    }
+
+    #[test]
+    fn cleaning_reduces_the_divergence() {
+        let config = config();
+        // A bump in the x component, with a divergence of opposite signs on either side
+        let field =
+            |pos: Vector3| Vector3::unit_x() * 1e-9 * (-(pos.items()[0] / 2.0).powi(2)).exp();
+        let dt = 1.0;
+        // One step of the cleaning field from zero, and then of the magnetic field
+        let cleaning = |pos: Vector3| {
+            let div = divergence(&config, pos, field);
+            time_derivative_cleaning_field(&config, 0.0, SMOOTH, 1.0, div, 0.0) * dt
+        };
+        let cleaned = |pos: Vector3| {
+            field(pos) + induction(&config, pos, |_| Vector3::zero(), field, cleaning) * dt
+        };
+        let (mut before, mut after) = (0.0, 0.0);
+        for x in -4..=4 {
+            let pos = Vector3::unit_x() * x as Float;
+            before += divergence(&config, pos, field).powi(2);
+            after += divergence(&config, pos, cleaned).powi(2);
+        }
+        assert!(after < 0.7 * before, "{} before, {} after", before, after);
+    }
+
+    #[test]
+    fn magnetic_pressure_of_a_uniform_field_vanishes() {
+        let config = config();
+        let field: Vector3 = [1e-9, -2e-9, 3e-9].iter().copied().collect();
+        let pos = Vector3::zero();
+        let surround = stencil(&config);
+        let count = surround.len();
+        let ones = vec![1.0; count];
+        let accel = magnetic_acceleration(
+            &config,
+            pos,
+            1.0,
+            1.0,
+            SMOOTH,
+            1.0,
+            1.0,
+            field,
+            &surround,
+            &ones,
+            &vec![SMOOTH; count],
+            &ones,
+            &ones,
+            &vec![field; count],
+        );
+        let scale = field.norm_squared() / (VACUUM_PERMEABILITY * SMOOTH);
+        assert!(accel.norm() < 1e-12 * scale, "{} against {}", accel, scale);
     }
 }
@@ -0,0 +1,411 @@
+use crate::config::SimulationConfig;
+use crate::constants::{RADIATION_CONSTANT, SPEED_OF_LIGHT};
+use crate::eos::IdealGas;
+use crate::integrator::State;
+use crate::kernel;
+use crate::neighbors::neighbors_within;
+use crate::opacity;
+use crate::particle;
+use crate::vector::{Float, Vector3};
+use rayon::prelude::*;
+
+// The largest relative change in any radiation or thermal energy at convergence
+const TOLERANCE: Float = 1e-3;
+const MAX_ITERATIONS: usize = 200;
+// The most substeps a step is divided into when the iterations do not converge
+const MAX_SUBSTEPS: usize = 1024;
+
+// The radiation energy of a gas particle in equilibrium with its thermal energy, m a T^4 / rho
+pub fn equilibrium(config: &SimulationConfig, mass: Float, energy: Float, density: Float) -> Float {
+    let temperature = particle::temperature(config, mass, energy, density);
+    mass * RADIATION_CONSTANT * temperature.powi(4) / density
+}
+
+// The flux limiter of Levermore & Pomraning (1981) and the Eddington factor, given the ratio R of
+// the mean free path to the length scale of the radiation energy density. The limiter is 1/3 in
+// the optically thick diffusion limit and 1/R in the optically thin free streaming limit
+fn flux_limiter(ratio: Float) -> (Float, Float) {
+    let limiter = (2.0 + ratio) / (6.0 + 3.0 * ratio + ratio * ratio);
+    (limiter, limiter + limiter * limiter * ratio * ratio)
+}
+
+// Advances the radiation and thermal energies of every gas particle `i`, the first `config.count`
+// particles of `state`, by `dts[i]` at the given smoothing lengths, densities and velocity
+// divergences. Particles with a zero step are left unchanged, and only take part as the neighbors
+// that radiation diffuses to and from. The work of the radiation pressure is applied first, and
+// then the diffusion of the radiation and its exchange with the gas are integrated with backward
+// Euler (Whitehouse, Bate & Monaghan 2005), with the diffusion coefficients and opacities of the
+// start of the step. The implicit equations are solved by Jacobi iteration, with the emission
+// linearized in the thermal energy of the last iteration. Gas beyond `radiation_boundary`, from the
+// center of mass at the origin, is instead set to `initial_temperature` in equilibrium with the
+// radiation. Returns the number of iterations, or None if they did not converge
+pub fn step(
+    config: &SimulationConfig,
+    state: &mut State,
+    smoothing_lengths: &[Float],
+    densities: &[Float],
+    divergences: &[Float],
+    dts: &[Float],
+) -> Option<usize> {
+    let count = config.count;
+    let masses = &state.masses;
+    // Radiation diffuses between the particles within the support of the smaller of their kernels,
+    // so each finds the other within its own support. The diffusion coefficients are needed for
+    // the advancing particles and their neighbors
+    let support = kernel::with_kernel(config, |kernel| kernel.support());
+    let radii: Vec<Float> = smoothing_lengths[..count]
+        .iter()
+        .map(|&smooth| support * smooth)
+        .collect();
+    let advancing: Vec<bool> = dts[..count].iter().map(|&dt| dt > 0.0).collect();
+    let positions = &state.positions[..count];
+    let mut neighbors = neighbors_within(config, positions, &radii, &advancing);
+    let mut needed = advancing.clone();
+    for i in (0..count).filter(|&i| advancing[i]) {
+        for &j in neighbors.of(i) {
+            needed[j] = true;
+        }
+    }
+    if needed != advancing {
+        neighbors = neighbors_within(config, positions, &radii, &needed);
+    }
+    let neighbor_indices: Vec<&[usize]> = (0..count).map(|i| neighbors.of(i)).collect();
+    let surround_pos: Vec<Vec<Vector3>> = neighbor_indices
+        .par_iter()
+        .map(|indices| indices.iter().map(|&idx| state.positions[idx]).collect())
+        .collect();
+    let surround = |values: &[Float]| -> Vec<Vec<Float>> {
+        neighbor_indices
+            .par_iter()
+            .map(|indices| indices.iter().map(|&idx| values[idx]).collect())
+            .collect()
+    };
+    let surround_mass = surround(masses);
+    let surround_smooth = surround(smoothing_lengths);
+    let surround_density = surround(densities);
+
+    let temperatures: Vec<Float> = (0..count)
+        .map(|i| particle::temperature(config, masses[i], state.thermal_energies[i], densities[i]))
+        .collect();
+    let radiation_densities: Vec<Float> = (0..count)
+        .map(|i| state.radiation_energies[i] / masses[i] * densities[i])
+        .collect();
+    let surround_radiation = surround(&radiation_densities);
+    // The (diffusion coefficient, Eddington factor) of every particle
+    let coefficients: Vec<(Float, Float)> = (0..count)
+        .into_par_iter()
+        .map(|i| {
+            if !needed[i] {
+                return (0.0, 0.0);
+            }
+            let gradient = particle::radiation_energy_gradient(
+                config,
+                state.positions[i],
+                smoothing_lengths[i],
+                densities[i],
+                radiation_densities[i],
+                &surround_pos[i],
+                &surround_mass[i],
+                &surround_smooth[i],
+                &surround_radiation[i],
+            );
+            let opacity = opacity::with_opacity(config, |opacity| {
+                opacity.rosseland(densities[i], temperatures[i])
+            });
+            // Free streaming if there is no radiation at all
+            let ratio =
+                (gradient.norm() / (opacity * densities[i] * radiation_densities[i])).min(1e30);
+            let (limiter, eddington) = flux_limiter(ratio);
+            (
+                SPEED_OF_LIGHT * limiter / (opacity * densities[i]),
+                eddington,
+            )
+        })
+        .collect();
+    let surround_diffusion = surround(&coefficients.iter().map(|c| c.0).collect::<Vec<_>>());
+    let weights: Vec<Vec<Float>> = (0..count)
+        .into_par_iter()
+        .map(|i| {
+            if !advancing[i] {
+                return Vec::new();
+            }
+            particle::radiation_diffusion_weights(
+                config,
+                state.positions[i],
+                smoothing_lengths[i],
+                densities[i],
+                coefficients[i].0,
+                &surround_pos[i],
+                &surround_mass[i],
+                &surround_smooth[i],
+                &surround_density[i],
+                &surround_diffusion[i],
+            )
+        })
+        .collect();
+
+    // c kappa_P, and the thermal energy per unit mass per temperature
+    let couplings: Vec<(Float, Float)> = (0..count)
+        .map(|i| {
+            let opacity = opacity::with_opacity(config, |opacity| {
+                opacity.planck(densities[i], temperatures[i])
+            });
+            let energy = state.thermal_energies[i] / masses[i];
+            let heat_capacity = if temperatures[i] > 0.0 && energy > 0.0 {
+                energy / temperatures[i]
+            } else {
+                IdealGas::new(config).energy(1.0)
+            };
+            (SPEED_OF_LIGHT * opacity, heat_capacity)
+        })
+        .collect();
+    let boundary: Vec<bool> = (0..count)
+        .map(|i| state.positions[i].norm() > config.radiation_boundary)
+        .collect();
+    let background = (
+        IdealGas::new(config).energy(config.initial_temperature),
+        RADIATION_CONSTANT * config.initial_temperature.powi(4),
+    );
+
+    // Advances the radiation and thermal energies per unit mass by a `fraction` of the step, or
+    // returns None if the iterations do not converge
+    let substep = |start: &[(Float, Float)],
+                   fraction: Float|
+     -> Option<(Vec<(Float, Float)>, usize)> {
+        // After the work of the radiation pressure, P = f E with f the Eddington factor
+        let initial: Vec<(Float, Float)> = (0..count)
+            .map(|i| {
+                let dt = dts[i] * fraction;
+                if !advancing[i] {
+                    return start[i];
+                }
+                if boundary[i] {
+                    return (background.1 / densities[i], background.0);
+                }
+                let work = (-coefficients[i].1 * divergences[i] * dt).exp();
+                (start[i].0 * work, start[i].1)
+            })
+            .collect();
+        let mut current = initial.clone();
+        for iteration in 1..=MAX_ITERATIONS {
+            let next: Vec<(Float, Float)> = (0..count)
+                .into_par_iter()
+                .map(|i| {
+                    if boundary[i] || !advancing[i] {
+                        return current[i];
+                    }
+                    let dt = dts[i] * fraction;
+                    let ((radiation, energy), density) = (initial[i], densities[i]);
+                    let (coupling, heat_capacity) = (couplings[i].0 * dt, couplings[i].1);
+                    let diffusion: Float = weights[i].iter().sum();
+                    let incoming: Float = weights[i]
+                        .iter()
+                        .zip(neighbor_indices[i].iter())
+                        .map(|(&weight, &j)| weight * densities[j] * current[j].0)
+                        .sum();
+                    // a T^4 ~ emission + slope * thermal energy per unit mass
+                    let temperature = current[i].1 / heat_capacity;
+                    let slope = 4.0 * RADIATION_CONSTANT * temperature.powi(3) / heat_capacity;
+                    let emission = RADIATION_CONSTANT * temperature.powi(4) - slope * current[i].1;
+                    let absorbed = 1.0 + coupling * slope;
+                    let new_radiation = (radiation
+                        + dt * incoming
+                        + coupling * emission
+                        + coupling * slope * (energy - coupling * emission) / absorbed)
+                        / (1.0 + dt * density * diffusion + coupling * density
+                            - coupling * slope * coupling * density / absorbed);
+                    let new_energy = (energy - coupling * emission
+                        + coupling * density * new_radiation)
+                        / absorbed;
+                    (new_radiation.max(0.0), new_energy.max(0.0))
+                })
+                .collect();
+            let converged = next.iter().zip(current.iter()).all(|(new, old)| {
+                (new.0 - old.0).abs() <= TOLERANCE * new.0
+                    && (new.1 - old.1).abs() <= TOLERANCE * new.1
+            });
+            current = next;
+            if converged {
+                return Some((current, iteration));
+            }
+        }
+        None
+    };
+
+    // Subdivide the step until every substep converges
+    let start: Vec<(Float, Float)> = (0..count)
+        .map(|i| {
+            (
+                state.radiation_energies[i] / masses[i],
+                state.thermal_energies[i] / masses[i],
+            )
+        })
+        .collect();
+    let mut substeps = 1;
+    let (result, iterations) = loop {
+        let mut current = start.clone();
+        let mut iterations = Some(0);
+        for _ in 0..substeps {
+            match substep(&current, 1.0 / substeps as Float) {
+                Some((next, count)) => {
+                    current = next;
+                    iterations = iterations.map(|total| total + count);
+                }
+                None => {
+                    iterations = None;
+                    break;
+                }
+            }
+        }
+        if iterations.is_some() || substeps == MAX_SUBSTEPS {
+            break (current, iterations);
+        }
+        substeps *= 2;
+    };
+    for (i, &(radiation, energy)) in result.iter().enumerate() {
+        state.radiation_energies[i] = masses[i] * radiation;
+        state.thermal_energies[i] = masses[i] * energy;
+    }
+    iterations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::OpacityType;
+    use crate::constants::AU;
+
+    // A uniform cubic lattice of gas at `density` and `temperature`, with its radiation at
+    // `radiation_temperature`, and the smoothing lengths and densities to step it with
+    fn lattice(
+        config: &SimulationConfig,
+        density: Float,
+        temperature: Float,
+        radiation_temperature: Float,
+    ) -> (State, Vec<Float>, Vec<Float>) {
+        let (side, spacing) = (6, 10.0 * AU);
+        let count = side * side * side;
+        assert_eq!(count, config.count);
+        let mass = density * spacing.powi(3);
+        let positions = (0..count)
+            .map(|i| {
+                [i % side, i / side % side, i / side / side]
+                    .iter()
+                    .map(|&n| (n as Float - (side - 1) as Float / 2.0) * spacing)
+                    .collect()
+            })
+            .collect();
+        let energy = mass * IdealGas::new(config).energy(temperature);
+        let radiation = mass * RADIATION_CONSTANT * radiation_temperature.powi(4) / density;
+        let state = State {
+            positions,
+            velocities: vec![Vector3::zero(); count],
+            masses: vec![mass; count],
+            thermal_energies: vec![energy; count],
+            viscosity_alphas: vec![config.viscosity_alpha; count],
+            radiation_energies: vec![radiation; count],
+            magnetic_fields: vec![Vector3::zero(); count],
+            cleaning_fields: vec![0.0; count],
+        };
+        (state, vec![1.5 * spacing; count], vec![density; count])
+    }
+
+    fn config() -> SimulationConfig {
+        SimulationConfig {
+            count: 216,
+            radiation: true,
+            radiation_boundary: 1e6 * AU,
+            opacity: OpacityType::Constant,
+            constant_opacity: 0.1,
+            ..SimulationConfig::default()
+        }
+    }
+
+    #[test]
+    fn uniform_medium_in_equilibrium_is_unchanged() {
+        let config = config();
+        let (mut state, smoothing_lengths, densities) = lattice(&config, 1e-7, 1e3, 1e3);
+        let initial = state.clone();
+        let count = config.count;
+        let dts = vec![1e3; count];
+        let iterations = step(
+            &config,
+            &mut state,
+            &smoothing_lengths,
+            &densities,
+            &vec![0.0; count],
+            &dts,
+        );
+        assert!(iterations.is_some());
+        let relative = |a: &[Float], b: &[Float]| {
+            a.iter()
+                .zip(b.iter())
+                .map(|(a, b)| ((a - b) / b).abs())
+                .fold(0.0, Float::max)
+        };
+        let radiation = relative(&state.radiation_energies, &initial.radiation_energies);
+        let thermal = relative(&state.thermal_energies, &initial.thermal_energies);
+        assert!(
+            radiation < 1e-9 && thermal < 1e-9,
+            "{} {}",
+            radiation,
+            thermal
+        );
+    }
+
+    #[test]
+    fn gas_and_radiation_relax_to_a_common_temperature() {
+        let config = config();
+        let density = 1e-7;
+        let (mut state, smoothing_lengths, densities) = lattice(&config, density, 1e4, 5e3);
+        let count = config.count;
+        let total = |state: &State| -> Float {
+            (0..count)
+                .map(|i| state.thermal_energies[i] + state.radiation_energies[i])
+                .sum()
+        };
+        let initial = total(&state);
+        // The gas and radiation exchange energy in about a second
+        for _ in 0..20 {
+            let dts = vec![1.0; count];
+            let divergences = vec![0.0; count];
+            step(
+                &config,
+                &mut state,
+                &smoothing_lengths,
+                &densities,
+                &divergences,
+                &dts,
+            )
+            .unwrap();
+        }
+        let conserved = ((total(&state) - initial) / initial).abs();
+        assert!(conserved < 1e-9, "{}", conserved);
+        for i in 0..count {
+            let (mass, energy) = (state.masses[i], state.thermal_energies[i]);
+            let gas = particle::temperature(&config, mass, energy, density);
+            let radiation =
+                (state.radiation_energies[i] / mass * density / RADIATION_CONSTANT).powf(0.25);
+            assert!(
+                (gas - radiation).abs() < 1e-2 * gas,
+                "{} {}",
+                gas,
+                radiation
+            );
+        }
+    }
+
+    #[test]
+    fn flux_limiter_has_the_diffusion_and_free_streaming_limits() {
+        let (limiter, eddington) = flux_limiter(1e-8);
+        assert!((limiter - 1.0 / 3.0).abs() < 1e-6 && (eddington - 1.0 / 3.0).abs() < 1e-6);
+        let ratio = 1e8;
+        let (limiter, eddington) = flux_limiter(ratio);
+        assert!((limiter * ratio - 1.0).abs() < 1e-6 && (eddington - 1.0).abs() < 1e-6);
+        // Monotonically decreasing in between
+        let limiters: Vec<Float> = (-3..=3)
+            .map(|n| flux_limiter((10.0 as Float).powi(n)).0)
+            .collect();
+        assert!(limiters.windows(2).all(|pair| pair[1] < pair[0]));
+    }
+}
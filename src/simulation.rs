@@ -1,181 +1,963 @@
-use crate::constants::{
-    DensityCurve::*, COUNT, DELTA_T, DENSITY_CURVE, ENABLE_GAS_DYNAMICS, ENABLE_GRAVITY, EPSILON,
-    INITIAL_THERMAL_ENERGY, RADIUS, ROTATIONAL_PERIOD, TWO_PI, VELOCITY_AVERAGING,
-};
+use crate::config::{DensityCurve::*, SimulationConfig};
+use crate::constants::TWO_PI;
+use crate::cooling;
+use crate::gravity;
+use crate::import;
+use crate::integrator::{self, retain, BlockSteps, Derivatives, State};
+use crate::kernel;
 use crate::neighbors::*;
 use crate::particle;
+use crate::radiation;
+use crate::sink::{self, Sink};
+use crate::snapshot::{self, Snapshot};
+use crate::timestep::{self, Criterion};
 use crate::vector::{Float, Vector3};
-use rand;
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use rayon::prelude::*;
+use std::path::Path;
 
+// The gas particles are followed by the sinks in `state` and in every per-particle quantity, with
+// `config.count` the number of gas particles
 pub struct Simulation {
-    positions: Box<[Vector3; COUNT]>,
-    velocities: Box<[Vector3; COUNT]>,
-    thermal_energies: Box<[Float; COUNT]>,
+    config: SimulationConfig,
+    state: State,
+    sinks: Vec<Sink>,
+    // The derivatives of `state`, if the integrator has already computed them
+    derivatives: Option<Derivatives>,
+    // Started by the first step with `block_timesteps`
+    blocks: Option<BlockSteps>,
+    // From the start of the last step
+    smoothing_lengths: Vec<Float>,
+    // Simulated time
+    time: Float,
+    last_timestep: (Float, Criterion),
+    steps: u64,
+    // Of the initial conditions, or 0 if they were imported
+    seed: u64,
+    radiation_iterations: Option<usize>,
+    // Of the smoothing lengths from the start of the last step
+    smoothing_iterations: (usize, usize),
 }
 
 impl Simulation {
-    pub fn new() -> Self {
-        let mut rng = rand::thread_rng();
-        let mut positions = Vec::with_capacity(COUNT);
-        let mut velocities = Vec::with_capacity(COUNT);
-        for _ in 0..COUNT {
+    pub fn new(config: SimulationConfig) -> Self {
+        let seed = match config.seed {
+            0 => rand::thread_rng().gen_range(1, u64::MAX),
+            seed => seed,
+        };
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut positions = Vec::with_capacity(config.count);
+        let mut velocities = Vec::with_capacity(config.count);
+        for _ in 0..config.count {
             let pos_unit = Vector3::from_polar(
                 (2.0 * rng.gen::<Float>() - 1.0).acos(),
                 TWO_PI * rng.gen::<Float>(),
-                match DENSITY_CURVE {
+                match config.density_curve {
                     Uniform => rng.gen::<Float>().cbrt(),
                     InverseLinear => rng.gen::<Float>().sqrt(),
                     InverseQuadratic => rng.gen::<Float>(),
                 },
             );
-            positions.push(pos_unit * RADIUS);
+            positions.push(pos_unit * config.radius);
             velocities.push(
                 (pos_unit - Vector3::unit_z() * Vector3::unit_z().dot(pos_unit))
                     .rotated(Vector3::unit_z(), TWO_PI / 4.0)
                     * TWO_PI
-                    * RADIUS
-                    / ROTATIONAL_PERIOD,
+                    * config.radius
+                    / config.rotational_period,
             );
         }
         let average_movement: Vector3 =
-            velocities.iter().map(|&p| p).sum::<Vector3>() / COUNT as f64;
+            velocities.iter().copied().sum::<Vector3>() / config.count as Float;
         for v in velocities.iter_mut() {
             *v -= average_movement;
         }
+        let masses = vec![config.particle_mass(); config.count];
+        let thermal_energies = vec![config.initial_thermal_energy(); config.count];
+        Simulation::from_particles(
+            config,
+            positions,
+            velocities,
+            masses,
+            thermal_energies,
+            0.0,
+            seed,
+        )
+    }
 
-        Simulation {
-            positions: unsafe {
-                Box::from_raw(Box::into_raw(positions.into_boxed_slice()) as *mut [Vector3; COUNT])
-            },
-            velocities: unsafe {
-                Box::from_raw(Box::into_raw(velocities.into_boxed_slice()) as *mut [Vector3; COUNT])
+    // Starts from the particles of the `initial_conditions` file, whose gas count and total gas
+    // mass replace those of `config`. Imported sinks are taken to have formed at the start
+    pub fn import(mut config: SimulationConfig) -> Result<Self, String> {
+        let path = Path::new(&config.initial_conditions);
+        let mut particles = import::import(&config, path)?;
+        let gas = particles.internal_energies.len();
+        config.count = gas;
+        config.mass = particles.masses[..gas].iter().sum();
+        config
+            .validate()
+            .map_err(|e| format!("Could not import {}: {}", path.display(), e))?;
+        let thermal_energies = particles
+            .internal_energies
+            .iter()
+            .zip(particles.masses.iter())
+            .map(|(&u, &m)| u * m)
+            .collect();
+        let sink_positions = particles.positions.split_off(gas);
+        let sink_velocities = particles.velocities.split_off(gas);
+        let sink_masses = particles.masses.split_off(gas);
+        let mut simulation = Simulation::from_particles(
+            config,
+            particles.positions,
+            particles.velocities,
+            particles.masses,
+            thermal_energies,
+            particles.time,
+            0,
+        );
+        let config = &simulation.config;
+        for ((&pos, &vel), &mass) in sink_positions
+            .iter()
+            .zip(sink_velocities.iter())
+            .zip(sink_masses.iter())
+        {
+            simulation.state.push_sink(config, pos, vel, mass);
+            simulation.sinks.push(Sink {
+                formed: particles.time,
+                spin: Vector3::zero(),
+            });
+            simulation.smoothing_lengths.push(config.sink_radius / 2.0);
+        }
+        // Derivatives kept from equilibrating the radiation do not include the sinks
+        if !sink_masses.is_empty() {
+            simulation.derivatives = None;
+        }
+        Ok(simulation)
+    }
+
+    fn from_particles(
+        config: SimulationConfig,
+        positions: Vec<Vector3>,
+        velocities: Vec<Vector3>,
+        masses: Vec<Float>,
+        thermal_energies: Vec<Float>,
+        time: Float,
+        seed: u64,
+    ) -> Self {
+        let neighbors = nearest_neighbors(&config, &positions);
+        let smoothing_lengths = neighbors
+            .chunks(config.neighbors)
+            .zip(positions.iter())
+            .map(|(indices, &pos)| {
+                let surround_pos: Vec<Vector3> = indices.iter().map(|&j| positions[j]).collect();
+                particle::smoothing_length(&config, pos, &surround_pos)
+            })
+            .collect();
+
+        let initial_alpha = if config.time_dependent_viscosity {
+            config.viscosity_alpha_min
+        } else {
+            config.viscosity_alpha
+        };
+
+        let mut simulation = Simulation {
+            state: State {
+                positions,
+                velocities,
+                masses,
+                thermal_energies,
+                viscosity_alphas: vec![initial_alpha; config.count],
+                radiation_energies: vec![0.0; config.count],
+                magnetic_fields: vec![initial_magnetic_field(&config); config.count],
+                cleaning_fields: vec![0.0; config.count],
             },
-            thermal_energies: Box::new([INITIAL_THERMAL_ENERGY; COUNT]),
+            sinks: Vec::new(),
+            derivatives: None,
+            blocks: None,
+            config,
+            smoothing_lengths,
+            time,
+            last_timestep: (0.0, Criterion::Maximum),
+            steps: 0,
+            seed,
+            radiation_iterations: None,
+            smoothing_iterations: (0, 0),
+        };
+        if simulation.config.radiation {
+            simulation.equilibrate_radiation();
+        }
+        simulation
+    }
+
+    // Continues a simulation saved by `save`, with `config` in place of the saved configuration,
+    // which can be read by `snapshot::read_config`. The continuation matches the original run up
+    // to the integration error, since derivatives cached by the integrator are not saved
+    pub fn load(path: &Path, config: SimulationConfig) -> Result<Self, String> {
+        let snapshot = snapshot::read(path)?;
+        if config.count != snapshot.config.count {
+            return Err(format!(
+                "Cannot change count from {} to {} when restarting",
+                snapshot.config.count, config.count
+            ));
+        }
+        let mut simulation = Simulation {
+            config,
+            state: snapshot.state,
+            sinks: snapshot.sinks,
+            derivatives: None,
+            blocks: None,
+            smoothing_lengths: snapshot.smoothing_lengths,
+            time: snapshot.time,
+            last_timestep: (0.0, Criterion::Maximum),
+            steps: snapshot.steps,
+            seed: snapshot.seed,
+            radiation_iterations: None,
+            smoothing_iterations: (0, 0),
+        };
+        // When the snapshot has no radiation or magnetic fields
+        let gas = simulation.config.count;
+        let state = &mut simulation.state;
+        if state.magnetic_fields[..gas]
+            .iter()
+            .all(|&field| field == Vector3::zero())
+        {
+            let field = initial_magnetic_field(&simulation.config);
+            state.magnetic_fields[..gas].fill(field);
         }
+        if simulation.config.radiation
+            && simulation.state.radiation_energies[..gas]
+                .iter()
+                .all(|&energy| energy == 0.0)
+        {
+            simulation.equilibrate_radiation();
+        }
+        Ok(simulation)
     }
+
+    // Sets the radiation energy of every gas particle to equilibrium with its thermal energy. The
+    // derivatives are kept for the first step, since they do not depend on the radiation
+    fn equilibrate_radiation(&mut self) {
+        let config = &self.config;
+        let all = vec![true; self.state.positions.len()];
+        let derivatives = derivatives(config, &self.state, &self.smoothing_lengths, &all);
+        let state = &mut self.state;
+        for i in 0..config.count {
+            state.radiation_energies[i] = radiation::equilibrium(
+                config,
+                state.masses[i],
+                state.thermal_energies[i],
+                derivatives.densities[i],
+            );
+        }
+        self.derivatives = Some(derivatives);
+    }
+
+    // With block timesteps, the particles are saved as predicted to the current time and all
+    // start new steps when loaded
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let state = match &self.blocks {
+            Some(blocks) => blocks.predict(&self.config, &self.state),
+            None => self.state.clone(),
+        };
+        snapshot::write(
+            path,
+            &Snapshot {
+                config: self.config.clone(),
+                steps: self.steps,
+                seed: self.seed,
+                time: self.time,
+                state,
+                sinks: self.sinks.clone(),
+                smoothing_lengths: self.smoothing_lengths.clone(),
+            },
+        )
+    }
+
+    // Advances by one global timestep, or with `block_timesteps` to the end of the next step of
+    // any particle. Returns the densities, which are zero for sinks
     pub fn step(&mut self) -> Vec<Float> {
-        let neighbor_indices = nearest_neighbors(&self.positions);
-        // Get smoothing lengths
-        let surround_pos: Vec<Vec<Vector3>> = neighbor_indices
-            .par_iter()
-            .map(|indices| indices.iter().map(|&idx| self.positions[idx]).collect())
-            .collect();
-        let smoothing_lengths: Vec<Float> = (0..COUNT)
-            .into_par_iter()
-            .map(|i| particle::smoothing_length(self.positions[i], &*surround_pos[i]))
-            .collect();
-        // Get densities
-        let surround_smooth: Vec<Vec<Float>> = neighbor_indices
-            .par_iter()
-            .map(|indices| indices.iter().map(|&idx| smoothing_lengths[idx]).collect())
-            .collect();
-        let densities: Vec<Float> = (0..COUNT)
+        let config = &self.config;
+        let all = vec![true; self.state.positions.len()];
+        let guesses = &self.smoothing_lengths;
+        let (mut densities, divergences, steps) = if config.block_timesteps {
+            let state = &mut self.state;
+            let blocks = self.blocks.get_or_insert_with(|| {
+                BlockSteps::new(config, state, |state, active| {
+                    derivatives(config, state, guesses, active)
+                })
+            });
+            let (delta_t, criterion) = blocks.step(config, state, |state, active| {
+                derivatives(config, state, guesses, active)
+            });
+            self.time += delta_t;
+            self.last_timestep = (delta_t, criterion);
+            self.smoothing_lengths = blocks.derivatives().smoothing_lengths.clone();
+            let derivatives = blocks.derivatives();
+            self.smoothing_iterations = (
+                derivatives.smoothing_iterations,
+                derivatives.unconverged_smoothing,
+            );
+            (
+                derivatives.densities.clone(),
+                derivatives.velocity_divergences.clone(),
+                blocks.finished().to_vec(),
+            )
+        } else {
+            let initial = match self.derivatives.take() {
+                Some(derivatives) => derivatives,
+                None => derivatives(config, &self.state, guesses, &all),
+            };
+            let (delta_t, criterion) = timestep::timestep(config, &self.state, &initial);
+            self.derivatives =
+                integrator::step(config, delta_t, &mut self.state, &initial, |state| {
+                    derivatives(config, state, guesses, &all)
+                });
+            self.time += delta_t;
+            self.last_timestep = (delta_t, criterion);
+            self.smoothing_iterations =
+                (initial.smoothing_iterations, initial.unconverged_smoothing);
+            self.smoothing_lengths = initial.smoothing_lengths;
+            let steps = vec![delta_t; self.state.positions.len()];
+            (initial.densities, initial.velocity_divergences, steps)
+        };
+        if (config.exact_cooling && config.evolves_thermal_energy()) || config.radiation {
+            self.apply_source_terms(&densities, &divergences, &steps);
+        }
+        if self.config.enable_sinks {
+            self.update_sinks(&mut densities);
+        }
+        // Translate to place center of mass at the origin
+        let masses = &self.state.masses;
+        let center_of_mass: Vector3 = (0..masses.len())
+            .map(|i| self.state.positions[i] * masses[i])
+            .sum::<Vector3>()
+            / masses.iter().sum::<Float>();
+        for p in self.state.positions.iter_mut() {
+            *p -= center_of_mass;
+        }
+        // Assert valid floats
+        assert!(self.state.positions.iter().all(|p| p.is_finite()));
+        assert!(self.state.velocities.iter().all(|p| p.is_finite()));
+        assert!(self
+            .state
+            .thermal_energies
+            .iter()
+            .all(|p| p.is_sign_positive()));
+        self.steps += 1;
+        // Return densities to aid computing statistics
+        densities
+    }
+
+    // Applies the exact cooling and the radiation, which are split from the integration, to every
+    // gas particle `i` over the step `steps[i]` it just finished. With block timesteps the state of
+    // every particle is predicted to the current time, and the changes are added to the particles
+    // that finished a step, whose state is otherwise already half a step ahead
+    fn apply_source_terms(&mut self, densities: &[Float], divergences: &[Float], steps: &[Float]) {
+        let config = &self.config;
+        let mut current = match &self.blocks {
+            Some(blocks) => blocks.predict(config, &self.state),
+            None => self.state.clone(),
+        };
+        let start = current.thermal_energies.clone();
+        if config.exact_cooling && config.evolves_thermal_energy() {
+            for i in (0..config.count).filter(|&i| steps[i] > 0.0) {
+                current.thermal_energies[i] = cooling::integrate(
+                    config,
+                    current.masses[i],
+                    current.thermal_energies[i],
+                    densities[i],
+                    steps[i],
+                );
+            }
+        }
+        if config.radiation {
+            self.radiation_iterations = radiation::step(
+                config,
+                &mut current,
+                &self.smoothing_lengths,
+                densities,
+                divergences,
+                steps,
+            );
+        }
+        let state = &mut self.state;
+        for i in (0..config.count).filter(|&i| steps[i] > 0.0) {
+            state.thermal_energies[i] =
+                (state.thermal_energies[i] + current.thermal_energies[i] - start[i]).max(0.0);
+            state.radiation_energies[i] = current.radiation_energies[i];
+        }
+    }
+
+    // Forms new sinks and lets the sinks accrete. With block timesteps, every particle is first
+    // predicted to the current time, and all start new steps if any gas was removed
+    fn update_sinks(&mut self, densities: &mut Vec<Float>) {
+        let config = &self.config;
+        let gas = config.count;
+        let predicted = self
+            .blocks
+            .as_ref()
+            .map(|blocks| blocks.predict(config, &self.state));
+        let current = predicted.as_ref().unwrap_or(&self.state);
+        let softening = gravity::softening_lengths(config, &self.smoothing_lengths);
+        let accretions = sink::accretions(config, current, gas);
+        let groups = sink::collapsing_groups(config, current, gas, densities, &softening);
+        if accretions.is_empty() && groups.is_empty() {
+            return;
+        }
+        let mut state = predicted.unwrap_or_else(|| self.state.clone());
+
+        let mut keep = vec![true; state.positions.len()];
+        for (j, s) in accretions {
+            sink::absorb(&mut state, &mut self.sinks[s - gas], s, j);
+            keep[j] = false;
+        }
+        for group in groups {
+            let mut new = Sink {
+                formed: self.time,
+                spin: Vector3::zero(),
+            };
+            let s = state.positions.len();
+            state.push_sink(config, Vector3::zero(), Vector3::zero(), 0.0);
+            for j in group {
+                sink::absorb(&mut state, &mut new, s, j);
+                keep[j] = false;
+            }
+            self.sinks.push(new);
+            self.smoothing_lengths.push(config.sink_radius / 2.0);
+            densities.push(0.0);
+            keep.push(true);
+        }
+        state.retain(&keep);
+        retain(&mut self.smoothing_lengths, &keep);
+        retain(densities, &keep);
+        self.config.count = keep[..gas].iter().filter(|&&keep| keep).count();
+        self.state = state;
+        self.derivatives = None;
+        self.blocks = None;
+    }
+
+    pub fn config(&self) -> &SimulationConfig {
+        &self.config
+    }
+    pub fn sinks(&self) -> &[Sink] {
+        &self.sinks
+    }
+    pub fn positions(&self) -> &[Vector3] {
+        &self.state.positions
+    }
+
+    pub fn velocities(&self) -> &[Vector3] {
+        &self.state.velocities
+    }
+    pub fn masses(&self) -> &[Float] {
+        &self.state.masses
+    }
+    pub fn thermal_energies(&self) -> &[Float] {
+        &self.state.thermal_energies
+    }
+    pub fn radiation_energies(&self) -> &[Float] {
+        &self.state.radiation_energies
+    }
+    pub fn magnetic_fields(&self) -> &[Vector3] {
+        &self.state.magnetic_fields
+    }
+    // The iterations of the last implicit radiation step, or None if they did not converge
+    pub fn radiation_iterations(&self) -> Option<usize> {
+        self.radiation_iterations
+    }
+    // The most iterations any particle took to solve for its smoothing length at the start of the
+    // last step, and the number of particles for which that did not converge
+    pub fn smoothing_iterations(&self) -> (usize, usize) {
+        self.smoothing_iterations
+    }
+    // From the start of the last step
+    pub fn smoothing_lengths(&self) -> &[Float] {
+        &self.smoothing_lengths
+    }
+    pub fn time(&self) -> Float {
+        self.time
+    }
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+    // The length of the last step and the criterion that limited it
+    pub fn last_timestep(&self) -> (Float, Criterion) {
+        self.last_timestep
+    }
+    pub fn softening_lengths(&self) -> Vec<Float> {
+        gravity::softening_lengths(&self.config, &self.smoothing_lengths)
+    }
+}
+
+// The uniform magnetic field of new gas particles along the rotation axis, with `magnetic_fields`
+fn initial_magnetic_field(config: &SimulationConfig) -> Vector3 {
+    if config.magnetic_fields {
+        Vector3::unit_z() * config.initial_magnetic_field
+    } else {
+        Vector3::zero()
+    }
+}
+
+// Neighbors are searched for within this multiple of the kernel support
+const SEARCH_MARGIN: Float = 1.1;
+
+// The time derivatives of `state`, with forces and rates only for the `active` particles, and
+// zero for the others. Smoothing lengths, densities, sound speeds and velocity divergences are
+// evaluated, starting from the smoothing lengths `guesses`, for the active gas particles and the
+// gas particles within their search radius. The others keep their guesses, with zero densities and
+// divergences, and with `symmetric_neighbors` only the evaluated particles are made symmetric.
+// Sinks only feel gravity
+fn derivatives(
+    config: &SimulationConfig,
+    state: &State,
+    guesses: &[Float],
+    active: &[bool],
+) -> Derivatives {
+    let count = config.count;
+    let total = state.positions.len();
+    let positions = &state.positions[..count];
+    let support = kernel::with_kernel(config, |kernel| kernel.support());
+    // Get smoothing lengths and densities, searching again around the particles whose kernel
+    // support outgrew the search, and around the neighbors of the active particles once they are
+    // known
+    let mut radii: Vec<Float> = guesses[..count]
+        .iter()
+        .map(|&smooth| SEARCH_MARGIN * support * smooth)
+        .collect();
+    let mut evaluated = active[..count].to_vec();
+    let (candidates, smoothings) = loop {
+        let candidates = neighbors_within(config, positions, &radii, &evaluated);
+        let smoothings: Vec<particle::Smoothing> = (0..count)
             .into_par_iter()
             .map(|i| {
-                particle::density(
-                    self.positions[i],
-                    smoothing_lengths[i],
-                    &*surround_pos[i],
-                    &*surround_smooth[i],
+                if !evaluated[i] {
+                    return particle::Smoothing {
+                        smooth: guesses[i],
+                        density: 0.0,
+                        omega: 1.0,
+                        iterations: 0,
+                        converged: true,
+                    };
+                }
+                let indices = candidates.of(i);
+                let surround_pos: Vec<Vector3> =
+                    indices.iter().map(|&idx| positions[idx]).collect();
+                let surround_mass: Vec<Float> =
+                    indices.iter().map(|&idx| state.masses[idx]).collect();
+                particle::smoothing(
+                    config,
+                    positions[i],
+                    state.masses[i],
+                    guesses[i],
+                    &surround_pos,
+                    &surround_mass,
                 )
-                .max(EPSILON)
             })
             .collect();
-        // Update positions and velocities
-        let deltas: Vec<(Vector3, Vector3, Float)> = (0..COUNT)
-            .into_par_iter()
-            .map(|i| {
-                let surround_density: Vec<Float> = neighbor_indices[i]
-                    .iter()
-                    .map(|&idx| densities[idx])
-                    .collect();
-                let surround_energy: Vec<Float> = neighbor_indices[i]
-                    .iter()
-                    .map(|&idx| self.thermal_energies[idx])
-                    .collect();
-                let surround_vel: Vec<Vector3> = neighbor_indices[i]
-                    .iter()
-                    .map(|&idx| self.velocities[idx])
-                    .collect();
-                let accel = if ENABLE_GRAVITY {
-                    particle::gravitational_acceleration(self.positions[i], &*self.positions)
-                } else {
-                    Vector3::zero()
-                } + if ENABLE_GAS_DYNAMICS {
+        let mut searched = true;
+        for (radius, smoothing) in radii.iter_mut().zip(&smoothings) {
+            if support * smoothing.smooth > *radius {
+                *radius = SEARCH_MARGIN * support * smoothing.smooth;
+                searched = false;
+            }
+        }
+        for i in (0..count).filter(|&i| active[i]) {
+            for &j in candidates.of(i) {
+                if !evaluated[j] {
+                    evaluated[j] = true;
+                    searched = false;
+                }
+            }
+        }
+        if searched {
+            break (candidates, smoothings);
+        }
+    };
+    // Every particle interacts with the neighbors within its kernel support, and with
+    // `symmetric_neighbors` also with those that have it within theirs
+    let neighbors = candidates
+        .filter(|i, j| (positions[j] - positions[i]).norm() <= support * smoothings[i].smooth);
+    let neighbors = if config.symmetric_neighbors {
+        neighbors.symmetric()
+    } else {
+        neighbors
+    };
+    let neighbor_indices: Vec<&[usize]> = (0..count).map(|i| neighbors.of(i)).collect();
+    let surround_pos: Vec<Vec<Vector3>> = neighbor_indices
+        .par_iter()
+        .map(|indices| indices.iter().map(|&idx| state.positions[idx]).collect())
+        .collect();
+    let surround_mass: Vec<Vec<Float>> = neighbor_indices
+        .par_iter()
+        .map(|indices| indices.iter().map(|&idx| state.masses[idx]).collect())
+        .collect();
+    let mut smoothing_lengths: Vec<Float> = smoothings.iter().map(|s| s.smooth).collect();
+    // So that the spline softened gravity of a sink is newtonian outside its accretion radius
+    smoothing_lengths.resize(total, config.sink_radius / 2.0);
+    let mut densities: Vec<Float> = smoothings.iter().map(|s| s.density).collect();
+    densities.resize(total, 0.0);
+    let surround_smooth: Vec<Vec<Float>> = neighbor_indices
+        .par_iter()
+        .map(|indices| indices.iter().map(|&idx| smoothing_lengths[idx]).collect())
+        .collect();
+    // Get artificial viscosity state
+    let surround_vel: Vec<Vec<Vector3>> = neighbor_indices
+        .par_iter()
+        .map(|indices| indices.iter().map(|&idx| state.velocities[idx]).collect())
+        .collect();
+    let divergences_curls: Vec<(Float, Float)> = (0..count)
+        .into_par_iter()
+        .map(|i| {
+            if !evaluated[i] {
+                return (0.0, 0.0);
+            }
+            particle::velocity_divergence_curl(
+                config,
+                state.positions[i],
+                state.velocities[i],
+                smoothing_lengths[i],
+                densities[i],
+                &surround_pos[i],
+                &surround_vel[i],
+                &surround_mass[i],
+                &surround_smooth[i],
+            )
+        })
+        .collect();
+    let viscosities: Vec<particle::Viscosity> = (0..count)
+        .into_par_iter()
+        .map(|i| {
+            let (divergence, curl) = divergences_curls[i];
+            let sound_speed = particle::sound_speed(
+                config,
+                state.masses[i],
+                state.thermal_energies[i],
+                densities[i],
+            );
+            particle::Viscosity::new(
+                config,
+                particle::fast_speed(config, sound_speed, state.magnetic_fields[i], densities[i]),
+                state.viscosity_alphas[i],
+                smoothing_lengths[i],
+                divergence,
+                curl,
+            )
+        })
+        .collect();
+    let gravity = if config.enable_gravity {
+        let softening = gravity::softening_lengths(config, &smoothing_lengths);
+        gravity::active_accelerations(config, &state.positions, &state.masses, &softening, active)
+    } else {
+        vec![Vector3::zero(); total]
+    };
+    let rates: Vec<(Vector3, Vector3, Float, Float, Vector3, Float)> = (0..total)
+        .into_par_iter()
+        .map(|i| {
+            if !active[i] {
+                return (
+                    Vector3::zero(),
+                    Vector3::zero(),
+                    0.0,
+                    0.0,
+                    Vector3::zero(),
+                    0.0,
+                );
+            }
+            if i >= count {
+                return (Vector3::zero(), gravity[i], 0.0, 0.0, Vector3::zero(), 0.0);
+            }
+            let surround_density: Vec<Float> = neighbor_indices[i]
+                .iter()
+                .map(|&idx| densities[idx])
+                .collect();
+            let surround_omega: Vec<Float> = neighbor_indices[i]
+                .iter()
+                .map(|&idx| smoothings[idx].omega)
+                .collect();
+            let surround_energy: Vec<Float> = neighbor_indices[i]
+                .iter()
+                .map(|&idx| state.thermal_energies[idx])
+                .collect();
+            let surround_visc: Vec<particle::Viscosity> = neighbor_indices[i]
+                .iter()
+                .map(|&idx| viscosities[idx])
+                .collect();
+            let surround_field: Vec<Vector3> = neighbor_indices[i]
+                .iter()
+                .map(|&idx| state.magnetic_fields[idx])
+                .collect();
+            let (visc_accel, visc_energy) = if config.enable_viscosity {
+                particle::artificial_viscosity(
+                    config,
+                    state.positions[i],
+                    state.velocities[i],
+                    state.masses[i],
+                    smoothing_lengths[i],
+                    densities[i],
+                    smoothings[i].omega,
+                    viscosities[i],
+                    &surround_pos[i],
+                    &surround_vel[i],
+                    &surround_mass[i],
+                    &surround_smooth[i],
+                    &surround_density,
+                    &surround_omega,
+                    &surround_visc,
+                )
+            } else {
+                (Vector3::zero(), 0.0)
+            };
+            let accel = gravity[i]
+                + visc_accel
+                + if config.enable_gas_dynamics {
                     particle::pressure_acceleration(
-                        self.positions[i],
-                        self.thermal_energies[i],
+                        config,
+                        state.positions[i],
+                        state.masses[i],
+                        state.thermal_energies[i],
                         smoothing_lengths[i],
                         densities[i],
-                        &*surround_pos[i],
-                        &*surround_energy,
-                        &*surround_smooth[i],
-                        &*surround_density,
+                        smoothings[i].omega,
+                        state.magnetic_fields[i],
+                        &surround_pos[i],
+                        &surround_mass[i],
+                        &surround_energy,
+                        &surround_smooth[i],
+                        &surround_density,
+                        &surround_omega,
+                        &surround_field,
                     )
                 } else {
                     Vector3::zero()
                 };
-                let neigh_vel = particle::neighborhood_velocity(
-                    self.positions[i],
-                    self.velocities[i],
+            let neigh_vel = particle::neighborhood_velocity(
+                config,
+                state.positions[i],
+                state.velocities[i],
+                smoothing_lengths[i],
+                densities[i],
+                &surround_pos[i],
+                &surround_vel[i],
+                &surround_mass[i],
+                &surround_smooth[i],
+                &surround_density,
+            );
+
+            let derivative_energy = particle::time_derivative_thermal_energy(
+                config,
+                state.positions[i],
+                state.velocities[i],
+                state.masses[i],
+                state.thermal_energies[i],
+                smoothing_lengths[i],
+                densities[i],
+                smoothings[i].omega,
+                &surround_pos[i],
+                &surround_vel[i],
+                &surround_mass[i],
+            );
+            let derivative_alpha = if config.time_dependent_viscosity {
+                particle::time_derivative_viscosity_alpha(
+                    config,
+                    state.viscosity_alphas[i],
                     smoothing_lengths[i],
+                    viscosities[i].sound_speed,
+                    divergences_curls[i].0,
+                )
+            } else {
+                0.0
+            };
+            let radiation = if config.exact_cooling {
+                0.0
+            } else {
+                cooling::rate(
+                    config,
+                    state.masses[i],
+                    state.thermal_energies[i],
                     densities[i],
-                    &*surround_pos[i],
-                    &*surround_vel,
-                    &*surround_smooth[i],
-                    &*surround_density,
-                );
-
-                let derivative_energy = particle::time_derivative_thermal_energy(
-                    self.positions[i],
-                    self.velocities[i],
-                    self.thermal_energies[i],
+                )
+            };
+            // The cleaning speed of every particle is its fast magnetosonic speed
+            let (derivative_field, derivative_cleaning) = if config.magnetic_fields {
+                let surround_cleaning: Vec<Float> = neighbor_indices[i]
+                    .iter()
+                    .map(|&idx| state.cleaning_fields[idx])
+                    .collect();
+                let surround_speed: Vec<Float> =
+                    surround_visc.iter().map(|visc| visc.sound_speed).collect();
+                let divergence = particle::magnetic_divergence(
+                    config,
+                    state.positions[i],
                     smoothing_lengths[i],
                     densities[i],
-                    &*surround_pos[i],
-                    &*surround_vel,
-                    &*surround_energy,
-                    &*surround_smooth[i],
-                    &*surround_density,
+                    smoothings[i].omega,
+                    state.magnetic_fields[i],
+                    &surround_pos[i],
+                    &surround_mass[i],
+                    &surround_field,
                 );
                 (
-                    self.velocities[i] * DELTA_T
-                        + accel * DELTA_T * DELTA_T / 2.0
-                        + VELOCITY_AVERAGING * neigh_vel,
-                    accel * DELTA_T,
-                    derivative_energy * DELTA_T,
+                    particle::time_derivative_magnetic_field(
+                        config,
+                        state.positions[i],
+                        state.velocities[i],
+                        smoothing_lengths[i],
+                        densities[i],
+                        smoothings[i].omega,
+                        state.magnetic_fields[i],
+                        state.cleaning_fields[i],
+                        viscosities[i].sound_speed,
+                        &surround_pos[i],
+                        &surround_vel[i],
+                        &surround_mass[i],
+                        &surround_smooth[i],
+                        &surround_density,
+                        &surround_omega,
+                        &surround_cleaning,
+                        &surround_speed,
+                    ),
+                    particle::time_derivative_cleaning_field(
+                        config,
+                        state.cleaning_fields[i],
+                        smoothing_lengths[i],
+                        viscosities[i].sound_speed,
+                        divergence,
+                        divergences_curls[i].0,
+                    ),
                 )
-            })
-            .collect();
-        for i in 0..COUNT {
-            self.positions[i] += deltas[i].0;
-            self.velocities[i] += deltas[i].1;
-            self.thermal_energies[i] = (self.thermal_energies[i] + deltas[i].2).max(0.0);
-        }
-        // Translate to place center of mass at the origin
-        let center_of_mass: Vector3 =
-            self.positions.iter().map(|&p| p).sum::<Vector3>() / COUNT as Float;
-        for p in self.positions.iter_mut() {
-            *p -= center_of_mass;
-        }
-        // Assert valid floats
-        assert!(self.positions.iter().all(|p| p.is_finite()));
-        assert!(self.velocities.iter().all(|p| p.is_finite()));
-        assert!(self.thermal_energies.iter().all(|p| p.is_sign_positive()));
-        // Return densities to aid computing statistics
-        densities
+            } else {
+                (Vector3::zero(), 0.0)
+            };
+            (
+                config.velocity_averaging * neigh_vel,
+                accel,
+                if config.evolves_thermal_energy() {
+                    derivative_energy + visc_energy + radiation
+                } else {
+                    0.0
+                },
+                derivative_alpha,
+                derivative_field,
+                derivative_cleaning,
+            )
+        })
+        .collect();
+    Derivatives {
+        velocity_corrections: rates.iter().map(|rate| rate.0).collect(),
+        accelerations: rates.iter().map(|rate| rate.1).collect(),
+        thermal_energies: rates.iter().map(|rate| rate.2).collect(),
+        viscosity_alphas: rates.iter().map(|rate| rate.3).collect(),
+        magnetic_fields: rates.iter().map(|rate| rate.4).collect(),
+        cleaning_fields: rates.iter().map(|rate| rate.5).collect(),
+        smoothing_lengths,
+        densities,
+        smoothing_iterations: smoothings.iter().map(|s| s.iterations).max().unwrap_or(0),
+        unconverged_smoothing: smoothings.iter().filter(|s| !s.converged).count(),
+        sound_speeds: (0..total)
+            .map(|i| viscosities.get(i).map_or(0.0, |visc| visc.sound_speed))
+            .collect(),
+        velocity_divergences: (0..total)
+            .map(|i| divergences_curls.get(i).map_or(0.0, |&(div, _)| div))
+            .collect(),
+        evaluated: (0..total)
+            .map(|i| evaluated.get(i).copied().unwrap_or(true))
+            .collect(),
     }
+}
 
-    pub fn positions(&self) -> &[Vector3] {
-        &*self.positions
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EquationOfStateType;
+    use crate::constants::{AU, SOLAR_MASS};
+
+    #[test]
+    fn barotropic_timestep_is_not_energy_limited() {
+        let config = SimulationConfig {
+            count: 500,
+            adaptive_timestep: true,
+            equation_of_state: EquationOfStateType::Barotropic,
+            ..SimulationConfig::default()
+        };
+        let mut simulation = Simulation::new(config);
+        for _ in 0..5 {
+            simulation.step();
+            let (delta_t, criterion) = simulation.last_timestep();
+            assert_ne!(criterion, Criterion::Energy, "limited to {} s", delta_t);
+        }
+        let initial = simulation.config().initial_thermal_energy();
+        assert!(simulation.thermal_energies().iter().all(|&e| e == initial));
     }
 
-    pub fn velocities(&self) -> &[Vector3] {
-        &*self.velocities
+    #[test]
+    fn active_particles_are_evaluated_as_with_all_particles() {
+        let config = SimulationConfig {
+            count: 500,
+            ..SimulationConfig::default()
+        };
+        let simulation = Simulation::new(config.clone());
+        let (state, guesses) = (&simulation.state, &simulation.smoothing_lengths);
+        let active: Vec<bool> = (0..config.count).map(|i| i % 7 == 0).collect();
+        let all = derivatives(&config, state, guesses, &vec![true; config.count]);
+        let some = derivatives(&config, state, guesses, &active);
+        for (i, &active) in active.iter().enumerate() {
+            if active {
+                assert!(some.evaluated[i]);
+                assert_eq!(some.smoothing_lengths[i], all.smoothing_lengths[i]);
+                assert_eq!(some.densities[i], all.densities[i]);
+                assert_eq!(some.accelerations[i], all.accelerations[i]);
+                assert_eq!(some.thermal_energies[i], all.thermal_energies[i]);
+            } else {
+                assert_eq!(some.accelerations[i], Vector3::zero());
+            }
+        }
+        assert!(some.evaluated.iter().any(|&evaluated| !evaluated));
     }
-    pub fn thermal_energies(&self) -> &[Float] {
-        &*self.thermal_energies
+
+    #[test]
+    fn restarts_after_accretion_below_the_neighbor_count() {
+        let config = SimulationConfig {
+            count: 50,
+            neighbors: 30,
+            enable_sinks: true,
+            sink_radius: 7_000.0 * AU,
+            ..SimulationConfig::default()
+        };
+        let mut simulation = Simulation::new(config.clone());
+        // A massive sink at the center that accretes most of the gas
+        let center = Vector3::zero();
+        simulation
+            .state
+            .push_sink(&config, center, center, 10.0 * SOLAR_MASS);
+        simulation.sinks.push(Sink {
+            formed: 0.0,
+            spin: center,
+        });
+        simulation.smoothing_lengths.push(config.sink_radius / 2.0);
+        simulation.derivatives = None;
+        let mut densities = vec![0.0; config.count + 1];
+        simulation.update_sinks(&mut densities);
+        let gas = simulation.config().count;
+        assert!(
+            0 < gas && gas <= config.neighbors,
+            "{} gas particles left",
+            gas
+        );
+
+        let path = std::env::temp_dir().join(format!("accreted_{}.snap", std::process::id()));
+        simulation.save(&path).unwrap();
+        let restarted =
+            snapshot::read_config(&path).and_then(|config| Simulation::load(&path, config));
+        std::fs::remove_file(&path).unwrap();
+        let mut restarted = restarted.unwrap();
+        assert_eq!(restarted.config().count, gas);
+        assert_eq!(restarted.positions(), simulation.positions());
+        assert_eq!(restarted.masses(), simulation.masses());
+        assert_eq!(restarted.sinks().len(), 1);
+        restarted.step();
+        assert!(restarted.positions().iter().all(|pos| pos.is_finite()));
     }
 }
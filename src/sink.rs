@@ -0,0 +1,303 @@
+use crate::config::SimulationConfig;
+use crate::constants::GRAVITATIONAL_CONSTANT;
+use crate::integrator::State;
+use crate::particle;
+use crate::vector::{Float, Vector3};
+use rayon::prelude::*;
+
+// A sink particle (Bate, Bonnell & Price 1995), standing in for a protostar. It forms from a
+// bound and collapsing clump of gas, and then accretes the gas that falls within
+// `config.sink_radius` and is bound to it. Its position, velocity and mass are kept in the
+// `State`, after all gas particles
+#[derive(Clone, Copy, Debug)]
+pub struct Sink {
+    // Simulated time of formation
+    pub formed: Float,
+    // The angular momentum of the accreted gas about the sink
+    pub spin: Vector3,
+}
+
+// The groups of gas particles, out of the first `gas` particles of `state`, that should each
+// be replaced by a new sink. A group is every particle within `sink_radius` of a particle denser
+// than `sink_density` that is at least twice `sink_radius` from every sink, and it forms a sink
+// if it is gravitationally bound, its thermal and rotational energy are small enough for it to
+// collapse, and it is contracting. The densest candidates are considered first
+pub fn collapsing_groups(
+    config: &SimulationConfig,
+    state: &State,
+    gas: usize,
+    densities: &[Float],
+    softening: &[Float],
+) -> Vec<Vec<usize>> {
+    let radius = config.sink_radius;
+    let mut candidates: Vec<usize> = (0..gas)
+        .filter(|&i| densities[i] > config.sink_density)
+        .collect();
+    candidates.sort_by(|&a, &b| densities[b].partial_cmp(&densities[a]).unwrap());
+
+    let mut sinks: Vec<Vector3> = state.positions[gas..].to_vec();
+    let mut taken = vec![false; gas];
+    let mut groups = Vec::new();
+    for i in candidates {
+        let pos = state.positions[i];
+        if taken[i] || sinks.iter().any(|&sink| (sink - pos).norm() < 2.0 * radius) {
+            continue;
+        }
+        let group: Vec<usize> = (0..gas)
+            .filter(|&j| !taken[j] && (state.positions[j] - pos).norm() < radius)
+            .collect();
+        if collapsing(config, state, &group, softening) {
+            for &j in &group {
+                taken[j] = true;
+            }
+            sinks.push(pos);
+            groups.push(group);
+        }
+    }
+    groups
+}
+
+// The ratios of thermal and rotational to gravitational energy, alpha and beta, must satisfy
+// alpha <= 1/2 and alpha + beta <= 1, and the total energy must be negative
+fn collapsing(
+    config: &SimulationConfig,
+    state: &State,
+    group: &[usize],
+    softening: &[Float],
+) -> bool {
+    let mass: Float = group.iter().map(|&j| state.masses[j]).sum();
+    let center = group
+        .iter()
+        .map(|&j| state.positions[j] * state.masses[j])
+        .sum::<Vector3>()
+        / mass;
+    let velocity = group
+        .iter()
+        .map(|&j| state.velocities[j] * state.masses[j])
+        .sum::<Vector3>()
+        / mass;
+
+    let mut gravitational = 0.0;
+    for (a, &j) in group.iter().enumerate() {
+        for &k in &group[a + 1..] {
+            gravitational += state.masses[j]
+                * particle::gravitational_potential_from(
+                    config,
+                    state.positions[j],
+                    softening[j],
+                    state.positions[k],
+                    state.masses[k],
+                    softening[k],
+                );
+        }
+    }
+    let thermal: Float = group.iter().map(|&j| state.thermal_energies[j]).sum();
+    let (mut kinetic, mut rotational, mut contraction) = (0.0, 0.0, 0.0);
+    for &j in group {
+        let (r, v, m) = (
+            state.positions[j] - center,
+            state.velocities[j] - velocity,
+            state.masses[j],
+        );
+        kinetic += 0.5 * m * v.norm_squared();
+        if r.norm_squared() > 0.0 {
+            rotational += 0.5 * m * r.cross(v).norm_squared() / r.norm_squared();
+        }
+        contraction += m * r.dot(v);
+    }
+
+    let alpha = thermal / -gravitational;
+    let beta = rotational / -gravitational;
+    gravitational < 0.0
+        && alpha <= 0.5
+        && alpha + beta <= 1.0
+        && thermal + kinetic + gravitational < 0.0
+        && contraction < 0.0
+}
+
+// The (gas particle, sink) index pairs into `state` of every gas particle within `sink_radius`
+// of a sink that it is bound to, and whose specific angular momentum is too small for a circular
+// orbit at `sink_radius`, with the sink it is most bound to
+pub fn accretions(config: &SimulationConfig, state: &State, gas: usize) -> Vec<(usize, usize)> {
+    let radius = config.sink_radius;
+    let sinks = gas..state.positions.len();
+    (0..gas)
+        .into_par_iter()
+        .filter_map(|j| {
+            sinks
+                .clone()
+                .filter_map(|s| {
+                    let r = state.positions[j] - state.positions[s];
+                    let v = state.velocities[j] - state.velocities[s];
+                    let dist = r.norm();
+                    if dist >= radius {
+                        return None;
+                    }
+                    let gm = GRAVITATIONAL_CONSTANT * state.masses[s];
+                    let energy = 0.5 * v.norm_squared() - gm / dist;
+                    let circular = (gm * radius).sqrt();
+                    if energy < 0.0 && r.cross(v).norm() < circular {
+                        Some((s, energy))
+                    } else {
+                        None
+                    }
+                })
+                .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+                .map(|(s, _)| (j, s))
+        })
+        .collect()
+}
+
+// Adds particle `j` of `state` to the sink `s`, conserving mass and linear and angular momentum.
+// Its thermal energy is lost
+pub fn absorb(state: &mut State, sink: &mut Sink, s: usize, j: usize) {
+    let (m_sink, m) = (state.masses[s], state.masses[j]);
+    let mass = m_sink + m;
+    let pos = (state.positions[s] * m_sink + state.positions[j] * m) / mass;
+    let vel = (state.velocities[s] * m_sink + state.velocities[j] * m) / mass;
+    sink.spin += m_sink * (state.positions[s] - pos).cross(state.velocities[s] - vel)
+        + m * (state.positions[j] - pos).cross(state.velocities[j] - vel);
+    state.positions[s] = pos;
+    state.velocities[s] = vel;
+    state.masses[s] = mass;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::{AU, SOLAR_MASS};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn state(positions: Vec<Vector3>, velocities: Vec<Vector3>, masses: Vec<Float>) -> State {
+        let count = positions.len();
+        State {
+            positions,
+            velocities,
+            masses,
+            thermal_energies: vec![0.0; count],
+            viscosity_alphas: vec![1.0; count],
+            radiation_energies: vec![0.0; count],
+            magnetic_fields: vec![Vector3::zero(); count],
+            cleaning_fields: vec![0.0; count],
+        }
+    }
+
+    fn vector(x: Float, y: Float, z: Float) -> Vector3 {
+        vec![x, y, z].into_iter().collect()
+    }
+
+    fn angular_momentum(state: &State, particles: &[usize]) -> Vector3 {
+        particles
+            .iter()
+            .map(|&i| state.positions[i].cross(state.velocities[i]) * state.masses[i])
+            .sum()
+    }
+
+    #[test]
+    fn absorbing_conserves_mass_and_momentum() {
+        let mut state = state(
+            vec![vector(1e13, -2e13, 3e12), vector(-4e12, 5e12, 6e13)],
+            vec![vector(3e3, 1e3, -2e3), vector(-1e3, 4e3, 5e2)],
+            vec![SOLAR_MASS, 1e-3 * SOLAR_MASS],
+        );
+        let mut sink = Sink {
+            formed: 0.0,
+            spin: vector(1e40, 2e40, -3e40),
+        };
+        let mass = state.masses[0] + state.masses[1];
+        let momentum =
+            state.velocities[0] * state.masses[0] + state.velocities[1] * state.masses[1];
+        let angular = angular_momentum(&state, &[0, 1]) + sink.spin;
+        absorb(&mut state, &mut sink, 0, 1);
+
+        let relative = |a: Vector3, b: Vector3| (a - b).norm() / b.norm();
+        assert_eq!(state.masses[0], mass);
+        assert!(relative(state.velocities[0] * state.masses[0], momentum) < 1e-12);
+        let absorbed = angular_momentum(&state, &[0]) + sink.spin;
+        assert!(relative(absorbed, angular) < 1e-12);
+    }
+
+    #[test]
+    fn only_bound_gas_within_the_radius_is_accreted() {
+        let config = SimulationConfig {
+            sink_radius: 50.0 * AU,
+            ..SimulationConfig::default()
+        };
+        let radius = config.sink_radius;
+        // The escape speed at half the radius
+        let gm = GRAVITATIONAL_CONSTANT * SOLAR_MASS;
+        let escape = (2.0 * gm / (radius / 2.0)).sqrt();
+        let (x, y) = (Vector3::unit_x(), Vector3::unit_y());
+        let gas = [
+            // Bound and falling in
+            (x * radius / 2.0, x * (-escape / 2.0)),
+            // Unbound
+            (x * radius / 2.0, x * (-2.0 * escape)),
+            // Bound but outside the radius
+            (x * 2.0 * radius, Vector3::zero()),
+            // Bound but orbiting too fast to stay within the radius
+            (x * radius * 0.9, y * (0.6 * escape)),
+            // Bound to both sinks, but more to the second
+            (y * radius * 0.9, Vector3::zero()),
+        ];
+        let count = gas.len();
+        let mut positions: Vec<Vector3> = gas.iter().map(|&(pos, _)| pos).collect();
+        let mut velocities: Vec<Vector3> = gas.iter().map(|&(_, vel)| vel).collect();
+        positions.extend(&[Vector3::zero(), y * radius * 1.5]);
+        velocities.extend(&[Vector3::zero(), Vector3::zero()]);
+        let mut masses = vec![1e-6 * SOLAR_MASS; count];
+        masses.extend(&[SOLAR_MASS, SOLAR_MASS]);
+        let state = state(positions, velocities, masses);
+
+        let mut accreted = accretions(&config, &state, count);
+        accreted.sort_unstable();
+        assert_eq!(accreted, vec![(0, count), (4, count + 1)]);
+    }
+
+    // A uniform sphere of a solar mass and a radius of 50 AU, moving with `velocity` at every
+    // offset from its center and with the given thermal energy in units of its gravitational
+    // binding energy
+    fn clump(velocity: impl Fn(Vector3) -> Vector3, thermal: Float) -> (State, Vec<usize>) {
+        let mut rng = StdRng::seed_from_u64(6);
+        let (count, radius) = (100, 50.0 * AU);
+        let mut positions = Vec::with_capacity(count);
+        while positions.len() < count {
+            let pos: Vector3 = (0..3).map(|_| rng.gen_range(-radius, radius)).collect();
+            if pos.norm() < radius {
+                positions.push(pos);
+            }
+        }
+        let velocities = positions.iter().map(|&pos| velocity(pos)).collect();
+        let mut state = state(
+            positions,
+            velocities,
+            vec![SOLAR_MASS / count as Float; count],
+        );
+        let binding = 0.6 * GRAVITATIONAL_CONSTANT * SOLAR_MASS * SOLAR_MASS / radius;
+        state.thermal_energies = vec![thermal * binding / count as Float; count];
+        (state, (0..count).collect())
+    }
+
+    #[test]
+    fn only_cold_contracting_and_bound_clumps_collapse() {
+        let config = SimulationConfig::default();
+        let softening = vec![0.0; 100];
+        let collapses =
+            |(state, group): (State, Vec<usize>)| collapsing(&config, &state, &group, &softening);
+        // The escape speed from the surface is about 6 km/s, 8e-10 / s times the radius
+        let (slow, fast) = (1e-10, 2e-9);
+        assert!(collapses(clump(|pos| pos * -slow, 0.1)));
+        // Too hot
+        assert!(!collapses(clump(|pos| pos * -slow, 0.6)));
+        // Spinning too fast, with alpha + beta above 1
+        assert!(!collapses(clump(
+            |pos| Vector3::unit_z().cross(pos) * 10.0 * slow - pos * slow,
+            0.4
+        )));
+        // Expanding
+        assert!(!collapses(clump(|pos| pos * slow, 0.1)));
+        // Falling in too fast to be bound
+        assert!(!collapses(clump(|pos| pos * -fast, 0.1)));
+    }
+}
@@ -0,0 +1,321 @@
+use crate::config::SimulationConfig;
+use crate::integrator::State;
+use crate::sink::Sink;
+use crate::vector::{Float, Vector3};
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+// Layout, all little endian:
+//   MAGIC, VERSION as u32
+//   configuration as u64 byte length followed by TOML
+//   count, sinks, steps and seed as u64, simulated time as f64
+//   positions, velocities and magnetic fields as 3 * (count + sinks) f64
+//   masses, thermal energies, viscosity alphas, radiation energies, cleaning fields and smoothing
+//   lengths as count + sinks f64
+//   formation time and spin of every sink as 4 f64
+const MAGIC: &[u8; 8] = b"SPHSNAP\0";
+const VERSION: u32 = 1;
+
+// Everything needed to continue a simulation
+pub struct Snapshot {
+    pub config: SimulationConfig,
+    pub steps: u64,
+    pub seed: u64,
+    pub time: Float,
+    // Gas particles followed by sinks
+    pub state: State,
+    pub sinks: Vec<Sink>,
+    pub smoothing_lengths: Vec<Float>,
+}
+
+// Writes to a temporary file that replaces `path` when complete, so that a crash while writing
+// never destroys the previous snapshot
+pub fn write(path: &Path, snapshot: &Snapshot) -> Result<(), String> {
+    let temporary = path.with_extension("tmp");
+    let error = |e: std::io::Error| format!("Could not write {}: {}", path.display(), e);
+    let mut out = BufWriter::new(File::create(&temporary).map_err(error)?);
+
+    let config = toml::to_string(&snapshot.config).map_err(|e| e.to_string())?;
+    let state = &snapshot.state;
+    out.write_all(MAGIC).map_err(error)?;
+    out.write_all(&VERSION.to_le_bytes()).map_err(error)?;
+    out.write_all(&(config.len() as u64).to_le_bytes())
+        .map_err(error)?;
+    out.write_all(config.as_bytes()).map_err(error)?;
+    let sinks = snapshot.sinks.len();
+    let count = state.positions.len() - sinks;
+    for &value in &[count as u64, sinks as u64, snapshot.steps, snapshot.seed] {
+        out.write_all(&value.to_le_bytes()).map_err(error)?;
+    }
+    let vectors = state
+        .positions
+        .iter()
+        .chain(state.velocities.iter())
+        .chain(state.magnetic_fields.iter());
+    let scalars = state
+        .masses
+        .iter()
+        .chain(state.thermal_energies.iter())
+        .chain(state.viscosity_alphas.iter())
+        .chain(state.radiation_energies.iter())
+        .chain(state.cleaning_fields.iter())
+        .chain(snapshot.smoothing_lengths.iter());
+    let sinks = snapshot
+        .sinks
+        .iter()
+        .flat_map(|sink| std::iter::once(&sink.formed).chain(sink.spin.iter()));
+    let floats = std::iter::once(&snapshot.time)
+        .chain(vectors.flat_map(|v| v.iter()))
+        .chain(scalars)
+        .chain(sinks);
+    for value in floats {
+        out.write_all(&value.to_le_bytes()).map_err(error)?;
+    }
+    out.flush().map_err(error)?;
+    drop(out);
+    fs::rename(&temporary, path).map_err(error)
+}
+
+pub fn read(path: &Path) -> Result<Snapshot, String> {
+    let mut input = open(path)?;
+    let config = input.config()?;
+    let count = input.u64()? as usize;
+    if count != config.count {
+        return Err(format!(
+            "Invalid snapshot {}: {} particles but count is {}",
+            path.display(),
+            count,
+            config.count
+        ));
+    }
+    let sinks = input.u64()? as usize;
+    let steps = input.u64()?;
+    let seed = input.u64()?;
+    let time = input.f64()?;
+    let total = count.saturating_add(sinks);
+    let positions = input.vectors(total)?;
+    let velocities = input.vectors(total)?;
+    let magnetic_fields = input.vectors(total)?;
+    let masses = input.floats(total)?;
+    let thermal_energies = input.floats(total)?;
+    let viscosity_alphas = input.floats(total)?;
+    let radiation_energies = input.floats(total)?;
+    let cleaning_fields = input.floats(total)?;
+    let smoothing_lengths = input.floats(total)?;
+    let sinks = input
+        .floats(sinks.saturating_mul(4))?
+        .chunks(4)
+        .map(|values| Sink {
+            formed: values[0],
+            spin: values[1..].iter().copied().collect(),
+        })
+        .collect();
+    Ok(Snapshot {
+        config,
+        steps,
+        seed,
+        time,
+        state: State {
+            positions,
+            velocities,
+            masses,
+            thermal_energies,
+            viscosity_alphas,
+            radiation_energies,
+            magnetic_fields,
+            cleaning_fields,
+        },
+        sinks,
+        smoothing_lengths,
+    })
+}
+
+// Reads only the configuration of a snapshot
+pub fn read_config(path: &Path) -> Result<SimulationConfig, String> {
+    open(path)?.config()
+}
+
+struct Input<'a> {
+    path: &'a Path,
+    reader: BufReader<File>,
+    // The number of bytes not yet read, which bounds every length read from the file
+    remaining: u64,
+}
+
+fn open(path: &Path) -> Result<Input<'_>, String> {
+    let error = |e: std::io::Error| format!("Could not read {}: {}", path.display(), e);
+    let file = File::open(path).map_err(error)?;
+    let remaining = file.metadata().map_err(error)?.len();
+    let mut input = Input {
+        path,
+        reader: BufReader::new(file),
+        remaining,
+    };
+    if input.bytes(MAGIC.len())? != MAGIC {
+        return Err(format!("{} is not a snapshot", path.display()));
+    }
+    let version = u32::from_le_bytes(input.bytes(4)?.as_slice().try_into().unwrap());
+    if version != VERSION {
+        return Err(format!(
+            "{} is a version {} snapshot, but only version {} is supported",
+            path.display(),
+            version,
+            VERSION
+        ));
+    }
+    Ok(input)
+}
+
+impl<'a> Input<'a> {
+    fn bytes(&mut self, len: usize) -> Result<Vec<u8>, String> {
+        if len as u64 > self.remaining {
+            return Err(format!(
+                "Invalid snapshot {}: truncated",
+                self.path.display()
+            ));
+        }
+        self.remaining -= len as u64;
+        let mut buffer = vec![0; len];
+        self.reader
+            .read_exact(&mut buffer)
+            .map_err(|e| format!("Could not read {}: {}", self.path.display(), e))?;
+        Ok(buffer)
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(
+            self.bytes(8)?.as_slice().try_into().unwrap(),
+        ))
+    }
+
+    fn f64(&mut self) -> Result<f64, String> {
+        Ok(f64::from_le_bytes(
+            self.bytes(8)?.as_slice().try_into().unwrap(),
+        ))
+    }
+
+    fn floats(&mut self, count: usize) -> Result<Vec<Float>, String> {
+        Ok(self
+            .bytes(count.saturating_mul(8))?
+            .chunks(8)
+            .map(|bytes| f64::from_le_bytes(bytes.try_into().unwrap()))
+            .collect())
+    }
+
+    fn vectors(&mut self, count: usize) -> Result<Vec<Vector3>, String> {
+        Ok(self
+            .floats(count.saturating_mul(3))?
+            .chunks(3)
+            .map(|xyz| xyz.iter().copied().collect())
+            .collect())
+    }
+
+    fn config(&mut self) -> Result<SimulationConfig, String> {
+        let len = self.u64()? as usize;
+        let text = String::from_utf8(self.bytes(len)?)
+            .map_err(|e| format!("Invalid snapshot {}: {}", self.path.display(), e))?;
+        let config: SimulationConfig = toml::from_str(&text)
+            .map_err(|e| format!("Invalid snapshot {}: {}", self.path.display(), e))?;
+        config.validate_restart()?;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot() -> Snapshot {
+        let config = SimulationConfig {
+            count: 3,
+            neighbors: 2,
+            ..SimulationConfig::default()
+        };
+        let values =
+            |offset: Float| -> Vec<Float> { (0..4).map(|i| offset + i as Float).collect() };
+        let vectors = |offset: Float| -> Vec<Vector3> {
+            (0..4)
+                .map(|i| {
+                    (0..3)
+                        .map(|axis| offset + (3 * i + axis) as Float)
+                        .collect()
+                })
+                .collect()
+        };
+        Snapshot {
+            config,
+            steps: 12,
+            seed: 34,
+            time: 5.6,
+            state: State {
+                positions: vectors(1.0),
+                velocities: vectors(2.0),
+                masses: values(3.0),
+                thermal_energies: values(4.0),
+                viscosity_alphas: values(5.0),
+                radiation_energies: values(6.0),
+                magnetic_fields: vectors(7.0),
+                cleaning_fields: values(8.0),
+            },
+            sinks: vec![Sink {
+                formed: 9.0,
+                spin: vectors(10.0)[0],
+            }],
+            smoothing_lengths: values(11.0),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = std::env::temp_dir().join(format!("round_trip_{}.snap", std::process::id()));
+        let written = snapshot();
+        write(&path, &written).unwrap();
+        let read = read(&path);
+        fs::remove_file(&path).unwrap();
+        let read = read.unwrap();
+
+        assert_eq!(
+            toml::to_string(&read.config).unwrap(),
+            toml::to_string(&written.config).unwrap()
+        );
+        assert_eq!(
+            (read.steps, read.seed, read.time),
+            (written.steps, written.seed, written.time)
+        );
+        let (a, b) = (&read.state, &written.state);
+        assert_eq!(a.positions, b.positions);
+        assert_eq!(a.velocities, b.velocities);
+        assert_eq!(a.masses, b.masses);
+        assert_eq!(a.thermal_energies, b.thermal_energies);
+        assert_eq!(a.viscosity_alphas, b.viscosity_alphas);
+        assert_eq!(a.radiation_energies, b.radiation_energies);
+        assert_eq!(a.magnetic_fields, b.magnetic_fields);
+        assert_eq!(a.cleaning_fields, b.cleaning_fields);
+        assert_eq!(read.sinks.len(), 1);
+        assert_eq!(
+            (read.sinks[0].formed, read.sinks[0].spin),
+            (written.sinks[0].formed, written.sinks[0].spin)
+        );
+        assert_eq!(read.smoothing_lengths, written.smoothing_lengths);
+    }
+
+    #[test]
+    fn truncated_files_are_rejected() {
+        let path = std::env::temp_dir().join(format!("truncated_{}.snap", std::process::id()));
+        write(&path, &snapshot()).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        // A configuration length far beyond the end of the file
+        let mut corrupt = bytes.clone();
+        corrupt[12..20].copy_from_slice(&u64::MAX.to_le_bytes());
+        fs::write(&path, &corrupt).unwrap();
+        let corrupt_length = read(&path);
+        fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        let truncated = read(&path);
+        fs::remove_file(&path).unwrap();
+        for result in [corrupt_length, truncated] {
+            assert!(matches!(result, Err(e) if e.ends_with("truncated")));
+        }
+    }
+}
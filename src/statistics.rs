@@ -1,49 +1,332 @@
-use crate::constants::{
-    COUNT, GAS_CONSTANT, GRAVITATIONAL_CONSTANT, MASS, MOLAR_MASS, PARTICLE_MASS,
-};
+use crate::config::SimulationConfig;
+use crate::constants::{GRAVITATIONAL_CONSTANT, TWO_PI, VACUUM_PERMEABILITY};
+use crate::cooling;
+use crate::gravity;
+use crate::particle;
 use crate::vector::{Float, Vector3};
 use rayon::prelude::*;
 
-pub fn observe_movement(velocities: &[Vector3]) -> Vector3 {
-    velocities.iter().map(|&v| v).sum()
+// The velocity of the center of mass, which should be conserved
+pub fn observe_movement(masses: &[Float], velocities: &[Vector3]) -> Vector3 {
+    velocities
+        .iter()
+        .zip(masses.iter())
+        .map(|(&v, &m)| v * m)
+        .sum::<Vector3>()
+        / masses.iter().sum::<Float>()
 }
 
 pub fn observe_thermal_energy(energies: &[Float]) -> Float {
-    energies.iter().map(|&e| e).sum()
+    energies.iter().sum()
 }
 
-pub fn observe_kinetic_energy(velocities: &[Vector3]) -> Float {
-    velocities.iter().map(|&v| v.norm_squared()).sum::<Float>() * PARTICLE_MASS / 2.0
+pub fn observe_radiation_energy(energies: &[Float]) -> Float {
+    energies.iter().sum()
 }
 
-pub fn observe_potential_energy(positions: &[Vector3]) -> Float {
-    (0..COUNT)
+pub fn observe_magnetic_energy(masses: &[Float], fields: &[Vector3], densities: &[Float]) -> Float {
+    (0..fields.len())
+        .map(|i| masses[i] * fields[i].norm_squared() / (2.0 * VACUUM_PERMEABILITY * densities[i]))
+        .sum()
+}
+
+pub fn observe_kinetic_energy(masses: &[Float], velocities: &[Vector3]) -> Float {
+    velocities
+        .iter()
+        .zip(masses.iter())
+        .map(|(&v, &m)| m * v.norm_squared())
+        .sum::<Float>()
+        / 2.0
+}
+
+pub fn observe_potential_energy(
+    config: &SimulationConfig,
+    positions: &[Vector3],
+    masses: &[Float],
+    softening: &[Float],
+) -> Float {
+    gravity::potentials(config, positions, masses, softening)
+        .iter()
+        .zip(masses.iter())
+        .map(|(&potential, &m)| m * potential)
+        .sum::<Float>()
+        * 0.5
+}
+
+// The (root mean square, maximum) relative error of the configured gravity solver compared to
+// direct summation on the same snapshot
+pub fn observe_gravity_error(
+    config: &SimulationConfig,
+    positions: &[Vector3],
+    masses: &[Float],
+    softening: &[Float],
+) -> (Float, Float) {
+    let approximate = gravity::accelerations(config, positions, masses, softening);
+    let exact = gravity::direct_accelerations(config, positions, masses, softening);
+    let errors: Vec<Float> = approximate
+        .iter()
+        .zip(exact.iter())
+        .map(|(&a, &b)| (a - b).norm() / b.norm())
+        .collect();
+    let rms = (errors.iter().map(|e| e * e).sum::<Float>() / errors.len() as Float).sqrt();
+    let max = errors.iter().copied().fold(0.0, Float::max);
+    (rms, max)
+}
+
+// Weighted by mass
+pub fn observe_average_temperature(
+    config: &SimulationConfig,
+    masses: &[Float],
+    energies: &[Float],
+    densities: &[Float],
+) -> Float {
+    (0..energies.len())
+        .into_par_iter()
+        .map(|i| masses[i] * particle::temperature(config, masses[i], energies[i], densities[i]))
+        .sum::<Float>()
+        / masses.iter().sum::<Float>()
+}
+
+pub fn observe_average_pressure(
+    config: &SimulationConfig,
+    masses: &[Float],
+    energies: &[Float],
+    densities: &[Float],
+) -> Float {
+    (0..energies.len())
+        .into_par_iter()
+        .map(|i| particle::pressure(config, masses[i], energies[i], densities[i]))
+        .sum::<Float>()
+        / energies.len() as Float
+}
+
+// The net power radiated by the gas, after heating
+pub fn observe_luminosity(
+    config: &SimulationConfig,
+    masses: &[Float],
+    energies: &[Float],
+    densities: &[Float],
+) -> Float {
+    (0..energies.len())
+        .into_par_iter()
+        .map(|i| -cooling::rate(config, masses[i], energies[i], densities[i]))
+        .sum()
+}
+
+// A bound pair of sinks, with its primary and secondary as indices into the bodies of a
+// simulation, and the elements of its relative orbit
+pub struct Binary {
+    pub primary: usize,
+    pub secondary: usize,
+    pub semi_major_axis: Float,
+    pub eccentricity: Float,
+    pub period: Float,
+    // The secondary mass over the primary mass, at most 1
+    pub mass_ratio: Float,
+}
+
+// Every pair of sinks, the bodies from index `sinks` on, that are bound and each other's most
+// bound companion, treating them as point masses
+pub fn observe_binaries(
+    positions: &[Vector3],
+    velocities: &[Vector3],
+    masses: &[Float],
+    sinks: usize,
+) -> Vec<Binary> {
+    let orbital_energy = |a: usize, b: usize| {
+        let reduced = masses[a] * masses[b] / (masses[a] + masses[b]);
+        0.5 * reduced * (velocities[a] - velocities[b]).norm_squared()
+            - GRAVITATIONAL_CONSTANT * masses[a] * masses[b] / (positions[a] - positions[b]).norm()
+    };
+    let companions: Vec<Option<usize>> = (sinks..positions.len())
+        .map(|a| {
+            (sinks..positions.len())
+                .filter(|&b| b != a)
+                .map(|b| (b, orbital_energy(a, b)))
+                .filter(|&(_, energy)| energy < 0.0)
+                .min_by(|x, y| x.1.partial_cmp(&y.1).unwrap())
+                .map(|(b, _)| b)
+        })
+        .collect();
+
+    let mut binaries = Vec::new();
+    for a in sinks..positions.len() {
+        let b = match companions[a - sinks] {
+            Some(b) if b > a && companions[b - sinks] == Some(a) => b,
+            _ => continue,
+        };
+        let (primary, secondary) = if masses[a] >= masses[b] {
+            (a, b)
+        } else {
+            (b, a)
+        };
+        let mu = GRAVITATIONAL_CONSTANT * (masses[a] + masses[b]);
+        let r = positions[secondary] - positions[primary];
+        let v = velocities[secondary] - velocities[primary];
+        let specific_energy = 0.5 * v.norm_squared() - mu / r.norm();
+        let semi_major_axis = -mu / (2.0 * specific_energy);
+        let h = r.cross(v).norm_squared();
+        binaries.push(Binary {
+            primary,
+            secondary,
+            semi_major_axis,
+            eccentricity: (1.0 + 2.0 * specific_energy * h / (mu * mu))
+                .max(0.0)
+                .sqrt(),
+            period: TWO_PI * (semi_major_axis.powi(3) / mu).sqrt(),
+            mass_ratio: masses[secondary] / masses[primary],
+        });
+    }
+    binaries
+}
+
+// The mass of the gas, the first `gas` bodies, in the circumstellar disc of every sink and in the
+// circumbinary disc of every binary. Gas that is bound to a binary as a whole and farther from
+// its center of mass than the separation is circumbinary, and other gas belongs to the sink it
+// is most bound to, counting its thermal energy
+pub fn observe_disc_masses(
+    positions: &[Vector3],
+    velocities: &[Vector3],
+    masses: &[Float],
+    energies: &[Float],
+    gas: usize,
+    binaries: &[Binary],
+) -> (Vec<Float>, Vec<Float>) {
+    // Of gas particle `i` relative to a point mass
+    let specific_energy = |i: usize, pos: Vector3, vel: Vector3, mass: Float| {
+        0.5 * (velocities[i] - vel).norm_squared() + energies[i] / masses[i]
+            - GRAVITATIONAL_CONSTANT * mass / (positions[i] - pos).norm()
+    };
+    let centers: Vec<(Vector3, Vector3, Float, Float)> = binaries
+        .iter()
+        .map(|binary| {
+            let (a, b) = (binary.primary, binary.secondary);
+            let mass = masses[a] + masses[b];
+            (
+                (positions[a] * masses[a] + positions[b] * masses[b]) / mass,
+                (velocities[a] * masses[a] + velocities[b] * masses[b]) / mass,
+                mass,
+                (positions[a] - positions[b]).norm(),
+            )
+        })
+        .collect();
+
+    let sinks = positions.len() - gas;
+    let (mut circumstellar, mut circumbinary) = (vec![0.0; sinks], vec![0.0; binaries.len()]);
+    let discs: Vec<Option<Disc>> = (0..gas)
         .into_par_iter()
         .map(|i| {
-            let mut energy = 0.0;
-            for j in 0..COUNT {
-                if i != j {
-                    energy += (positions[i] - positions[j]).norm().powi(-1);
-                }
+            let binary = centers.iter().position(|&(pos, vel, mass, separation)| {
+                (positions[i] - pos).norm() > separation && specific_energy(i, pos, vel, mass) < 0.0
+            });
+            if let Some(binary) = binary {
+                return Some(Disc::Circumbinary(binary));
             }
-            energy
+            (gas..positions.len())
+                .map(|s| {
+                    (
+                        s,
+                        specific_energy(i, positions[s], velocities[s], masses[s]),
+                    )
+                })
+                .filter(|&(_, energy)| energy < 0.0)
+                .min_by(|x, y| x.1.partial_cmp(&y.1).unwrap())
+                .map(|(s, _)| Disc::Circumstellar(s - gas))
         })
-        .sum::<Float>()
-        * -0.5
-        * GRAVITATIONAL_CONSTANT
-        * PARTICLE_MASS.powi(2)
+        .collect();
+    for (i, disc) in discs.into_iter().enumerate() {
+        match disc {
+            Some(Disc::Circumstellar(sink)) => circumstellar[sink] += masses[i],
+            Some(Disc::Circumbinary(binary)) => circumbinary[binary] += masses[i],
+            None => {}
+        }
+    }
+    (circumstellar, circumbinary)
 }
 
-pub fn observe_average_temperature(energies: &[Float]) -> Float {
-    let energy = observe_thermal_energy(energies);
-    energy * MOLAR_MASS / 1.5 / GAS_CONSTANT / MASS
+// The disc of a sink or a binary, by its index
+enum Disc {
+    Circumstellar(usize),
+    Circumbinary(usize),
 }
 
-pub fn observe_average_pressure(energies: &[Float], densities: &[Float]) -> Float {
-    (0..COUNT)
-        .into_par_iter()
-        .map(|i| energies[i] * densities[i])
-        .sum::<Float>()
-        / MASS
-        / 1.5
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::{AU, SOLAR_MASS};
+
+    const GAS: usize = 3;
+
+    fn vector(x: Float, y: Float, z: Float) -> Vector3 {
+        vec![x, y, z].into_iter().collect()
+    }
+
+    // Three gas particles, then a sink of half a solar mass at apocenter of an orbit with a
+    // semi-major axis of 10 AU and an eccentricity of 1/2 about a sink of a solar mass, with the
+    // relative speed scaled by `speed`, and a lone sink far away moving too fast to be bound
+    fn bodies(speed: Float) -> (Vec<Vector3>, Vec<Vector3>, Vec<Float>) {
+        let (primary, secondary) = (SOLAR_MASS, 0.5 * SOLAR_MASS);
+        let mass = primary + secondary;
+        let (a, e) = (10.0 * AU, 0.5);
+        let mu = GRAVITATIONAL_CONSTANT * mass;
+        let r = a * (1.0 + e);
+        let v = speed * (mu * (1.0 - e) / r).sqrt();
+        let bulk = vector(1e3, -2e3, 5e2);
+        let (x, y) = (Vector3::unit_x(), Vector3::unit_y());
+
+        let around_primary = x * (-r * secondary / mass);
+        let mut positions = vec![
+            // Circling the primary at 1 AU
+            around_primary - x * AU,
+            // Bound to the binary as a whole, far outside its orbit
+            x * 100.0 * AU,
+            // Too fast to be bound to anything
+            y * 100.0 * AU,
+        ];
+        let primary_velocity = bulk - y * (v * secondary / mass);
+        let mut velocities = vec![
+            primary_velocity - y * (GRAVITATIONAL_CONSTANT * primary / AU).sqrt(),
+            bulk,
+            bulk + x * 1e5,
+        ];
+        positions.extend(&[x * (r * primary / mass), around_primary, x * 1e4 * AU]);
+        velocities.extend(&[bulk + y * (v * primary / mass), primary_velocity, y * 1e5]);
+        let mut masses = vec![1e-6 * SOLAR_MASS, 2e-6 * SOLAR_MASS, 4e-6 * SOLAR_MASS];
+        masses.extend(&[secondary, primary, SOLAR_MASS]);
+        (positions, velocities, masses)
+    }
+
+    #[test]
+    fn bound_pairs_are_reported_with_their_orbit() {
+        let (positions, velocities, masses) = bodies(1.0);
+        let binaries = observe_binaries(&positions, &velocities, &masses, GAS);
+        assert_eq!(binaries.len(), 1);
+        let binary = &binaries[0];
+        assert_eq!((binary.primary, binary.secondary), (GAS + 1, GAS));
+        let (a, mu) = (10.0 * AU, GRAVITATIONAL_CONSTANT * 1.5 * SOLAR_MASS);
+        assert!((binary.semi_major_axis / a - 1.0).abs() < 1e-12);
+        assert!((binary.eccentricity - 0.5).abs() < 1e-12);
+        let period = TWO_PI * (a.powi(3) / mu).sqrt();
+        assert!((binary.period / period - 1.0).abs() < 1e-12);
+        assert_eq!(binary.mass_ratio, 0.5);
+    }
+
+    #[test]
+    fn unbound_pairs_are_not_reported() {
+        // Just above the escape speed at apocenter, which is sqrt(2 / (1 - e)) = 2 times the
+        // orbital speed there
+        let (positions, velocities, masses) = bodies(2.01);
+        assert!(observe_binaries(&positions, &velocities, &masses, GAS).is_empty());
+    }
+
+    #[test]
+    fn gas_is_assigned_to_the_disc_it_is_bound_to() {
+        let (positions, velocities, masses) = bodies(1.0);
+        let binaries = observe_binaries(&positions, &velocities, &masses, GAS);
+        let energies: Vec<Float> = masses[..GAS].iter().map(|&m| 1e3 * m).collect();
+        let (circumstellar, circumbinary) =
+            observe_disc_masses(&positions, &velocities, &masses, &energies, GAS, &binaries);
+        assert_eq!(circumstellar, vec![0.0, masses[0], 0.0]);
+        assert_eq!(circumbinary, vec![masses[1]]);
+    }
 }
@@ -0,0 +1,93 @@
+use crate::config::SimulationConfig;
+use crate::integrator::{Derivatives, State};
+use crate::vector::Float;
+use rayon::prelude::*;
+use std::fmt;
+
+// The condition that limits a timestep
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Criterion {
+    // `config.delta_t`
+    Maximum,
+    // Sound and compression crossing the smoothing length
+    Courant,
+    // sqrt(h / |a|)
+    Acceleration,
+    // Relative change of thermal energy
+    Energy,
+}
+
+impl fmt::Display for Criterion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Criterion::Maximum => "max",
+            Criterion::Courant => "cfl",
+            Criterion::Acceleration => "accel",
+            Criterion::Energy => "energy",
+        };
+        f.pad(name)
+    }
+}
+
+// The largest stable timestep of every particle, and the criterion that limits it
+pub fn particle_timesteps(
+    config: &SimulationConfig,
+    state: &State,
+    derivatives: &Derivatives,
+) -> Vec<(Float, Criterion)> {
+    (0..state.positions.len())
+        .into_par_iter()
+        .map(|i| {
+            let smooth = derivatives.smoothing_lengths[i];
+            let signal_speed =
+                derivatives.sound_speeds[i] + smooth * derivatives.velocity_divergences[i].abs();
+            let accel = derivatives.accelerations[i].norm();
+            let energy_rate = derivatives.thermal_energies[i].abs();
+            [
+                (config.delta_t, Criterion::Maximum),
+                (
+                    config.courant_factor * smooth / signal_speed,
+                    Criterion::Courant,
+                ),
+                (
+                    config.acceleration_factor * (smooth / accel).sqrt(),
+                    Criterion::Acceleration,
+                ),
+                (
+                    config.energy_change_factor * state.thermal_energies[i] / energy_rate,
+                    Criterion::Energy,
+                ),
+            ]
+            .iter()
+            // Criteria without a limit, e.g. with zero acceleration or energy, are infinite or NaN
+            .filter(|(dt, _)| *dt > 0.0 && dt.is_finite())
+            .fold((config.delta_t, Criterion::Maximum), |a, &b| {
+                if b.0 < a.0 {
+                    b
+                } else {
+                    a
+                }
+            })
+        })
+        .collect()
+}
+
+// The global timestep of the next step, which is `config.delta_t` unless `adaptive_timestep` is on
+pub fn timestep(
+    config: &SimulationConfig,
+    state: &State,
+    derivatives: &Derivatives,
+) -> (Float, Criterion) {
+    if !config.adaptive_timestep {
+        return (config.delta_t, Criterion::Maximum);
+    }
+    particle_timesteps(config, state, derivatives)
+        .into_iter()
+        .fold((config.delta_t, Criterion::Maximum), |a, b| {
+            if b.0 < a.0 {
+                b
+            } else {
+                a
+            }
+        })
+}
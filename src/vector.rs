@@ -89,7 +89,7 @@ impl FromIterator<Float> for Vector3 {
                 Some(val) => result.0[i] = val,
             }
         }
-        if iterator.next() != None {
+        if iterator.next().is_some() {
             panic!("Cannot convert iterator of length >3 to Vector3")
         }
         result